@@ -24,6 +24,7 @@ pub fn help_panel(screen: usize) -> impl View {
                 .child(help_item("n", "Create a new realm."))
                 .child(help_item("r", "Restart currently selected realm."))
                 .child(help_item("u", "Open shell to update RealmFS image of selected realm."))
+                .child(help_item("a", "Attach or detach a removable device to running realm."))
                 .child(help_item(".", "Toggle display of system realms."))
                 .child(DummyView)
         } else {
@@ -33,6 +34,8 @@ pub fn help_panel(screen: usize) -> impl View {
                 .child(help_item("n", "Create new RealmFS as fork of selected image."))
                 .child(help_item("s", "Seal selected RealmFS image."))
                 .child(help_item("u", "Open shell to update selected RealmFS image."))
+                .child(help_item("p", "Save a snapshot of selected RealmFS image."))
+                .child(help_item("R", "Restore selected RealmFS image from a snapshot."))
                 .child(help_item(".", "Toggle display of system RealmFS images."))
                 .child(DummyView)
         }
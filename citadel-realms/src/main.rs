@@ -15,6 +15,7 @@ mod tree;
 mod notes;
 mod terminal;
 mod item_list;
+mod progress;
 
 fn main() {
 
@@ -0,0 +1,79 @@
+use std::thread;
+
+use cursive::Cursive;
+use cursive::traits::Boxable;
+use cursive::utils::Counter;
+use cursive::views::{Dialog, DummyView, LinearLayout, PaddedView, ProgressBar, TextContent, TextView};
+use libcitadel::{Progress, Result};
+
+///
+/// A dialog which displays a progress bar while a long running operation
+/// such as forking a RealmFS image runs on a background thread.
+///
+pub struct ProgressDialog;
+
+impl ProgressDialog {
+
+    /// Open a progress dialog with `title` and run `task` on a new thread.
+    ///
+    /// The task is passed a `Progress` instance which updates the dialog. When the task
+    /// completes the dialog is closed and `done` is called with the result of the task.
+    ///
+    pub fn run<T,D>(s: &mut Cursive, title: &str, task: T, done: D)
+        where T: FnOnce(&mut dyn Progress) -> Result<()> + Send + 'static,
+              D: FnOnce(&mut Cursive, Result<()>) + Send + 'static,
+    {
+        let counter = Counter::new(0);
+        let message = TextContent::new("");
+
+        let content = LinearLayout::vertical()
+            .child(TextView::new_with_content(message.clone()))
+            .child(DummyView)
+            .child(ProgressBar::new().range(0, 100).with_value(counter.clone()));
+
+        let dialog = Dialog::around(PaddedView::new((2,2,1,1), content))
+            .title(title)
+            .min_width(60);
+
+        s.add_layer(dialog);
+        s.set_autorefresh(true);
+
+        let sink = s.cb_sink().clone();
+        thread::spawn(move || {
+            let mut progress = DialogProgress { counter, message, total: 0 };
+            let result = task(&mut progress);
+            let finish = move |s: &mut Cursive| {
+                s.set_autorefresh(false);
+                s.pop_layer();
+                done(s, result);
+            };
+            if let Err(e) = sink.send(Box::new(finish)) {
+                warn!("error sending progress dialog completion: {}", e);
+            }
+        });
+    }
+}
+
+struct DialogProgress {
+    counter: Counter,
+    message: TextContent,
+    total: u64,
+}
+
+impl Progress for DialogProgress {
+    fn begin(&mut self, message: &str, total: u64) {
+        self.message.set_content(message);
+        self.total = total;
+        self.counter.set(0);
+    }
+
+    fn update(&mut self, done: u64) {
+        if let Some(percent) = (done.min(self.total) * 100).checked_div(self.total) {
+            self.counter.set(percent as usize);
+        }
+    }
+
+    fn end(&mut self) {
+        self.counter.set(100);
+    }
+}
@@ -8,6 +8,7 @@ use crate::realm::config_realm::ConfigDialog;
 use crate::ui::{DeferredAction, GlobalState};
 use crate::realm::delete_realm::DeleteRealmDialog;
 use crate::realm::new_realm::NewRealmDialog;
+use crate::realm::device_dialog::DeviceDialog;
 use crate::dialogs::confirm_dialog;
 use crate::item_list::ItemList;
 use crate::notes::NotesDialog;
@@ -119,6 +120,13 @@ impl RealmAction {
         })
     }
 
+    pub fn attach_device() -> EventResult {
+        EventResult::with_cb(move |s| {
+            let realm = RealmAction::current_realm(s);
+            DeviceDialog::open(s, realm);
+        })
+    }
+
     pub fn new_realm(manager: Arc<RealmManager>) -> EventResult {
         EventResult::with_cb(move |s| NewRealmDialog::open(s, manager.clone()))
     }
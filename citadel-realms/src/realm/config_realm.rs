@@ -13,10 +13,10 @@ use cursive::{
     },
     utils::markup::StyledString,
     view::ViewWrapper,
-    views::{ViewBox, LinearLayout, TextView, DummyView, PaddedView, Dialog, Button, SelectView},
+    views::{ViewBox, LinearLayout, TextView, DummyView, PaddedView, Dialog, Button, SelectView, EditView},
 };
 
-use libcitadel::{RealmConfig, RealmFS, Realm, OverlayType, terminal::Base16Scheme, RealmManager};
+use libcitadel::{RealmConfig, RealmFS, Realm, OverlayType, ResourceLimit, terminal::Base16Scheme, RealmManager};
 
 use crate::theme::ThemeChooser;
 use crate::dialogs::DialogButtonAdapter;
@@ -30,6 +30,7 @@ pub struct ConfigDialog {
     scheme: Option<String>,
     realmfs: Option<String>,
     overlay: OverlayType,
+    limits: Vec<(ResourceLimit, String)>,
     realmfs_list: Vec<String>,
     inner: ViewBox,
 }
@@ -99,11 +100,17 @@ impl ConfigDialog {
             .child(ConfigDialog::realmfs_widget(realmfs_list))
             .child(ConfigDialog::overlay_widget(&config))
             .child(ConfigDialog::colorscheme_widget(&config))
+            .child(DummyView)
+            .child(ConfigDialog::limits_widget())
             .scrollable();
 
         let dialog = Dialog::around(PaddedView::new((2,2,1,0), content))
             .title("Realm Config")
             .button("Apply", |s| {
+                if let Err(e) = ConfigDialog::call_dialog(s, |d| d.validate_limits()) {
+                    s.add_layer(Dialog::info(e.to_string()).title("Invalid Resource Limit"));
+                    return;
+                }
                 s.call_on_id("config-dialog", |d: &mut ConfigDialog| d.apply_changes());
                 ItemList::<Realm>::call_update_info("realms", s);
                 s.pop_layer();
@@ -114,7 +121,7 @@ impl ConfigDialog {
             .dismiss_button("Cancel")
             .with_id("config-dialog-inner");
 
-        ConfigDialog { manager, realm, scheme: None, realmfs:None, overlay: OverlayType::None, realmfs_list: realmfs_names, inner: ViewBox::boxed(dialog) }
+        ConfigDialog { manager, realm, scheme: None, realmfs:None, overlay: OverlayType::None, limits: Vec::new(), realmfs_list: realmfs_names, inner: ViewBox::boxed(dialog) }
     }
 
     fn has_changes(&mut self) -> bool {
@@ -123,6 +130,9 @@ impl ConfigDialog {
             return true;
         }
         drop(config);
+        if !self.changed_limits().is_empty() {
+            return true;
+        }
         self.call_on_options(|v| v.has_changes())
     }
 
@@ -150,6 +160,36 @@ impl ConfigDialog {
         self.call_id("realmfs-select", f)
     }
 
+    fn call_on_limit_edit<R,F: FnOnce(&mut EditView) -> R>(&mut self, limit: ResourceLimit, f: F) -> R {
+        self.call_id(&Self::limit_id(limit), f)
+    }
+
+    fn limit_id(limit: ResourceLimit) -> String {
+        format!("limit-{}", limit.config_key())
+    }
+
+    // The resource limits which have been edited and the new value for each
+    fn changed_limits(&mut self) -> Vec<(ResourceLimit, String)> {
+        let limits = self.limits.clone();
+        let mut changed = Vec::new();
+        for (limit, original) in limits {
+            let value = self.call_on_limit_edit(limit, |v| v.get_content().trim().to_string());
+            if value != original {
+                changed.push((limit, value));
+            }
+        }
+        changed
+    }
+
+    fn validate_limits(&mut self) -> libcitadel::Result<()> {
+        for (limit, value) in self.changed_limits() {
+            if !value.is_empty() {
+                limit.validate(&value)?;
+            }
+        }
+        Ok(())
+    }
+
     fn call_id<V: View, F: FnOnce(&mut V) -> R, R>(&mut self, id: &str, callback: F) -> R
     {
         self.call_on_id(id, callback)
@@ -165,10 +205,16 @@ impl ConfigDialog {
         self.overlay = config.overlay();
 
         let realmfs_name = config.realmfs().to_string();
+        self.limits = ResourceLimit::all().iter()
+            .map(|&limit| (limit, config.resource_limit(limit).unwrap_or_default()))
+            .collect();
         drop(config);
 
         self.set_realmfs_selection(&realmfs_name);
         self.set_overlay_selection(self.overlay);
+        for (limit, value) in self.limits.clone() {
+            self.call_on_limit_edit(limit, |v| v.set_content(value));
+        }
 
         let scheme_name = self.realm.config().terminal_scheme().unwrap_or("default-dark").to_string();
         self.call_on_scheme_button(|b| b.set_label(scheme_name.as_str()));
@@ -197,11 +243,19 @@ impl ConfigDialog {
         let realm = self.realm.clone();
 
         let scheme_changed = realm.config().terminal_scheme != self.scheme;
+        let changed_limits = self.changed_limits();
         realm.with_mut_config(|c| {
             c.terminal_scheme = self.scheme.clone();
             c.realmfs = self.realmfs.clone();
             c.set_overlay(self.overlay);
 
+            for (limit, value) in &changed_limits {
+                let value = Some(value.as_str()).filter(|v| !v.is_empty());
+                if let Err(e) = c.set_resource_limit(*limit, value) {
+                    warn!("{}", e);
+                }
+            }
+
             self.call_on_options(|v| v.save_config(c));
         });
 
@@ -286,6 +340,28 @@ impl ConfigDialog {
             .child(DummyView)
     }
 
+    fn limits_widget() -> impl View {
+        let mut layout = LinearLayout::vertical()
+            .child(ConfigDialog::header("Resource Limits"))
+            .child(TextView::new("Leave empty to use the default from the global realm config. Changes take effect when the realm is next started."))
+            .child(DummyView);
+
+        for &limit in ResourceLimit::all() {
+            let edit = EditView::new()
+                .style(ColorStyle::tertiary())
+                .filler(" ")
+                .on_edit(|s, _, _| ConfigDialog::call_dialog(s, |d| d.update_buttons()))
+                .with_id(Self::limit_id(limit))
+                .fixed_width(12);
+
+            layout.add_child(LinearLayout::horizontal()
+                .child(TextView::new(limit.description()).fixed_width(22))
+                .child(edit)
+                .child(DummyView));
+        }
+        layout
+    }
+
     pub fn set_realmfs(&mut self, name: &str) {
         self.realmfs = Some(name.to_string());
         self.update_buttons();
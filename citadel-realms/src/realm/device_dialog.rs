@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+
+use cursive::Cursive;
+use cursive::traits::Boxable;
+use cursive::views::{Dialog, LinearLayout, PaddedView, SelectView, TextView, DummyView};
+use libcitadel::{Realm, DeviceSpec};
+
+use crate::item_list::ItemList;
+
+///
+/// Dialog for attaching removable devices such as USB storage devices to a
+/// running realm, or detaching devices which are already attached.
+///
+pub struct DeviceDialog;
+
+impl DeviceDialog {
+
+    pub fn open(s: &mut Cursive, realm: Realm) {
+        if !realm.is_active() {
+            s.add_layer(Dialog::info(format!("Realm '{}' must be running to attach a device.", realm.name())).title("Attach Device"));
+            return;
+        }
+
+        let devices = match Self::device_list(&realm) {
+            Ok(devices) => devices,
+            Err(e) => {
+                s.add_layer(Dialog::info(format!("Error listing devices: {}", e)).title("Attach Device"));
+                return;
+            }
+        };
+        if devices.is_empty() {
+            s.add_layer(Dialog::info("No removable devices found.").title("Attach Device"));
+            return;
+        }
+
+        let mut select = SelectView::new();
+        for (label, device, attached) in devices {
+            select.add_item(label, (device, attached));
+        }
+        select.set_on_submit(move |s, (device, attached): &(PathBuf, bool)| {
+            s.pop_layer();
+            let manager = realm.manager();
+            let result = if *attached {
+                manager.detach_device(&realm, device)
+            } else {
+                manager.attach_device(&realm, device)
+            };
+            if let Err(e) = result {
+                let action = if *attached { "Detach" } else { "Attach" };
+                s.add_layer(Dialog::info(format!("{} failed: {}", action, e)).title("Attach Device"));
+            }
+            ItemList::<Realm>::call_update_info("realms", s);
+        });
+
+        let content = LinearLayout::vertical()
+            .child(TextView::new("Select a device to attach it to the realm, or an attached device to detach it."))
+            .child(DummyView)
+            .child(select);
+
+        let dialog = Dialog::around(PaddedView::new((2,2,1,0), content))
+            .title("Attach Device")
+            .dismiss_button("Cancel")
+            .fixed_width(60);
+
+        s.add_layer(dialog);
+    }
+
+    // Each removable device with a label describing which realm it is attached to, and
+    // whether it is attached to `realm`. Devices attached to other realms are not listed.
+    fn device_list(realm: &Realm) -> libcitadel::Result<Vec<(String, PathBuf, bool)>> {
+        let attached = realm.attached_devices();
+        let others = realm.manager().active_realms(false).into_iter()
+            .filter(|r| r.name() != realm.name())
+            .flat_map(|r| r.attached_devices())
+            .collect::<Vec<_>>();
+
+        let mut devices = DeviceSpec::removable_block_devices()?;
+        devices.extend(attached.iter().filter(|d| !devices.contains(d)).cloned().collect::<Vec<_>>());
+
+        Ok(devices.into_iter()
+            .filter(|d| !others.contains(d))
+            .map(|d| {
+                let is_attached = attached.contains(&d);
+                let label = if is_attached {
+                    format!("{}  [attached]", d.display())
+                } else {
+                    d.display().to_string()
+                };
+                (label, d, is_attached)
+            })
+            .collect())
+    }
+}
@@ -20,6 +20,7 @@ mod actions;
 mod new_realm;
 mod delete_realm;
 mod config_realm;
+mod device_dialog;
 
 pub struct RealmListContent {
     show_system_realms: bool,
@@ -119,6 +120,7 @@ impl ItemListContent<Realm> for RealmListContent {
             Event::Char('$') => RealmAction::open_shell(false),
             Event::Char('#') => RealmAction::open_shell(true),
             Event::Char('u') => RealmAction::update_realmfs(),
+            Event::Char('a') => RealmAction::attach_device(),
             Event::Char('.') => {
                 self.show_system_realms = !self.show_system_realms;
                 EventResult::with_cb(|s| ItemList::<Realm>::call_reload("realms", s))
@@ -208,6 +210,13 @@ impl <'a> RealmInfoRender <'a> {
             self.newline();
         }
 
+        let devices = self.realm.attached_devices();
+        if !devices.is_empty() {
+            let devices = devices.iter().map(|d| d.display().to_string()).collect::<Vec<_>>();
+            self.print("   Attached: ").dim_style().println(devices.join(" ")).pop();
+            self.newline();
+        }
+
     }
 
     fn detached(&self, realmfs: &RealmFS) -> bool {
@@ -1,7 +1,7 @@
 use cursive::views::{ViewBox, SelectView, EditView, TextView, ViewRef, Dialog, TextContent};
 use cursive::traits::{View,Identifiable,Finder};
 use cursive::view::ViewWrapper;
-use libcitadel::{RealmFS, GLOBAL_CONFIG, Realm, RealmManager};
+use libcitadel::{RealmFS, GLOBAL_CONFIG, Realm, RealmManager, Progress};
 use cursive::Cursive;
 use crate::dialogs::{Validatable, DialogButtonAdapter, FieldDialogBuilder, ValidatorResult};
 use cursive::theme::ColorStyle;
@@ -11,6 +11,7 @@ use libcitadel::terminal::Base16Scheme;
 use std::sync::Arc;
 use crate::item_list::ItemList;
 use std::rc::Rc;
+use crate::progress::ProgressDialog;
 
 pub struct NewRealmDialog {
     manager: Arc<RealmManager>,
@@ -370,23 +371,39 @@ impl NewRealmFSDialog {
             return;
         }
 
-        let name = dialog.name_edit_content();
+        let name = dialog.name_edit_content().to_string();
 
         if !RealmFS::is_valid_name(&name) {
             s.add_layer(Dialog::info("RealmFS name is invalid.").title("Invalid Name"));
+            return;
         }
 
-        if let Some(realmfs) = dialog.call_on_realmfs_select(|v| v.selection()) {
-            if let Err(e) = realmfs.fork(&name) {
+        let selection = dialog.call_on_realmfs_select(|v| v.selection());
+        drop(dialog);
+        s.pop_layer();
+
+        let realmfs = match selection {
+            Some(realmfs) => (*realmfs).clone(),
+            None => {
+                NewRealmDialog::call_dialog(s, |v| v.reload_realmfs(&name));
+                return;
+            }
+        };
+
+        let title = format!("Fork {}-realmfs.img", realmfs.name());
+        let task = {
+            let realmfs = realmfs.clone();
+            let name = name.clone();
+            move |progress: &mut dyn Progress| realmfs.fork(&name, progress).map(|_| ())
+        };
+        ProgressDialog::run(s, &title, task, move |s, result| {
+            if let Err(e) = result {
                 let msg = format!("Failed to fork RealmFS '{}' to '{}': {}", realmfs.name(), name, e);
-                s.pop_layer();
                 s.add_layer(Dialog::info(msg.as_str()).title("Fork Failed"));
                 return;
             }
-        }
-        NewRealmDialog::call_dialog(s, |v| v.reload_realmfs(&name));
-        s.pop_layer();
-
+            NewRealmDialog::call_dialog(s, |v| v.reload_realmfs(&name));
+        });
     }
 }
 
@@ -9,6 +9,7 @@ use crate::ui::{DeferredAction, GlobalState};
 use cursive::views::Dialog;
 use crate::item_list::ItemList;
 use crate::realmfs::fork_dialog::ForkDialog;
+use crate::realmfs::snapshot_dialog::{SnapshotDialog, RestoreDialog};
 use crate::notes::NotesDialog;
 
 type ActionCallback = Fn(&RealmFS)+Send+Sync;
@@ -139,6 +140,20 @@ impl RealmFSAction {
         })
     }
 
+    pub fn snapshot_realmfs() -> EventResult {
+        EventResult::with_cb(move |s| {
+            let realmfs = RealmFSAction::current_realmfs(s);
+            SnapshotDialog::open(s, realmfs);
+        })
+    }
+
+    pub fn restore_realmfs() -> EventResult {
+        EventResult::with_cb(move |s| {
+            let realmfs = RealmFSAction::current_realmfs(s);
+            RestoreDialog::open(s, realmfs);
+        })
+    }
+
     pub fn update_realmfs() -> EventResult {
         EventResult::with_cb(move |s| {
             let realmfs = Self::current_realmfs(s);
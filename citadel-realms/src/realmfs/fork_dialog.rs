@@ -1,4 +1,4 @@
-use libcitadel::{RealmManager, RealmFS};
+use libcitadel::{RealmManager, RealmFS, Progress};
 use cursive::views::{TextContent, ViewBox, TextView, EditView, Dialog};
 use cursive::traits::{Identifiable, View,Finder};
 use std::sync::Arc;
@@ -10,6 +10,7 @@ use cursive::event::{EventResult, Event};
 use cursive::view::ViewWrapper;
 use std::rc::Rc;
 use crate::item_list::ItemList;
+use crate::progress::ProgressDialog;
 
 pub struct ForkDialog {
     realmfs: RealmFS,
@@ -66,21 +67,29 @@ impl ForkDialog {
         if !is_enabled {
             return;
         }
-        let name = Self::call_dialog(s, |v| v.name_edit_content());
+        let name = Self::call_dialog(s, |v| v.name_edit_content()).to_string();
         if !RealmFS::is_valid_name(&name) {
             s.add_layer(Dialog::info("RealmFS name is invalid.").title("Invalid Name"));
-        }
-        let realmfs = Self::call_dialog(s, |v| v.realmfs.clone());
-        if let Err(e) = realmfs.fork(&name) {
-           let msg = format!("Failed to fork RealmFS '{}' to '{}': {}", realmfs.name(), name, e);
-            warn!(msg.as_str());
-            s.pop_layer();
-            s.add_layer(Dialog::info(msg.as_str()));
             return;
         }
-
+        let realmfs = Self::call_dialog(s, |v| v.realmfs.clone());
         s.pop_layer();
-        ItemList::<RealmFS>::call_reload("realmfs", s);
+
+        let title = format!("Fork {}-realmfs.img", realmfs.name());
+        let task = {
+            let realmfs = realmfs.clone();
+            let name = name.clone();
+            move |progress: &mut dyn Progress| realmfs.fork(&name, progress).map(|_| ())
+        };
+        ProgressDialog::run(s, &title, task, move |s, result| {
+            if let Err(e) = result {
+                let msg = format!("Failed to fork RealmFS '{}' to '{}': {}", realmfs.name(), name, e);
+                warn!(msg.as_str());
+                s.add_layer(Dialog::info(msg.as_str()));
+                return;
+            }
+            ItemList::<RealmFS>::call_reload("realmfs", s);
+        });
     }
 
     fn name_updated(&mut self) {
@@ -8,6 +8,7 @@ use cursive::theme::{PaletteColor, ColorStyle, Style, Effect};
 
 mod actions;
 mod fork_dialog;
+mod snapshot_dialog;
 pub use self::actions::RealmFSAction;
 
 pub struct RealmFSListContent {
@@ -99,6 +100,8 @@ impl ItemListContent<RealmFS> for RealmFSListContent {
             Event::Char('s') => RealmFSAction::seal_realmfs(sealed),
             Event::Char('S') => RealmFSAction::unseal_realmfs(sealed),
             Event::Char('e') => RealmFSAction::edit_notes(),
+            Event::Char('p') => RealmFSAction::snapshot_realmfs(),
+            Event::Char('R') => RealmFSAction::restore_realmfs(),
             Event::Char('.') => {
                 self.show_system = !self.show_system;
                 EventResult::with_cb(|s| ItemList::<RealmFS>::call_reload("realmfs", s))
@@ -0,0 +1,139 @@
+use libcitadel::{RealmFS, Snapshot, Progress, util};
+use cursive::views::{Dialog, EditView, SelectView, TextView, LinearLayout, DummyView, PaddedView};
+use cursive::traits::{Boxable, Scrollable};
+use cursive::Cursive;
+use std::rc::Rc;
+use crate::dialogs::{FieldDialogBuilder, confirm_dialog};
+use crate::item_list::ItemList;
+use crate::progress::ProgressDialog;
+
+pub struct SnapshotDialog;
+
+impl SnapshotDialog {
+
+    pub fn open(s: &mut Cursive, realmfs: RealmFS) {
+        if !realmfs.is_sealed() && realmfs.is_activated() {
+            s.add_layer(Dialog::info("Cannot snapshot unsealed RealmFS because it is currently activated. Deactivate first").title("Cannot Snapshot"));
+            return;
+        }
+        let text = format!("Save a snapshot of {}-realmfs.img which can later be restored. Provide a name for the snapshot and an optional note.", realmfs.name());
+        let dialog = FieldDialogBuilder::new(&["Name", "Note"], &text)
+            .title("Snapshot RealmFS")
+            .id("snapshot-realmfs-dialog")
+            .edit_view("snapshot-name", 24)
+            .edit_view("snapshot-note", 40)
+            .build(move |s| Self::handle_ok(s, &realmfs));
+        s.add_layer(dialog);
+    }
+
+    fn edit_content(s: &mut Cursive, id: &str) -> Rc<String> {
+        s.call_on_id(id, |v: &mut EditView| v.get_content())
+            .unwrap_or_else(|| panic!("call_on_id({})", id))
+    }
+
+    fn handle_ok(s: &mut Cursive, realmfs: &RealmFS) {
+        let name = Self::edit_content(s, "snapshot-name").to_string();
+        let note = Self::edit_content(s, "snapshot-note").to_string();
+        if !Snapshot::is_valid_name(&name) {
+            s.add_layer(Dialog::info("Snapshot name is invalid.").title("Invalid Name"));
+            return;
+        }
+        s.pop_layer();
+
+        let title = format!("Snapshot {}-realmfs.img", realmfs.name());
+        let task = {
+            let realmfs = realmfs.clone();
+            let name = name.clone();
+            move |progress: &mut dyn Progress| realmfs.snapshot(&name, Some(&note), progress).map(|_| ())
+        };
+        let realmfs = realmfs.clone();
+        ProgressDialog::run(s, &title, task, move |s, result| {
+            if let Err(e) = result {
+                let msg = format!("Failed to create snapshot '{}' of RealmFS '{}': {}", name, realmfs.name(), e);
+                warn!(msg.as_str());
+                s.add_layer(Dialog::info(msg.as_str()));
+            }
+        });
+    }
+}
+
+pub struct RestoreDialog;
+
+impl RestoreDialog {
+
+    pub fn open(s: &mut Cursive, realmfs: RealmFS) {
+        let snapshots = match realmfs.snapshots() {
+            Ok(snapshots) => snapshots,
+            Err(e) => {
+                s.add_layer(Dialog::info(format!("Error loading snapshots: {}", e)).title("Restore Snapshot"));
+                return;
+            }
+        };
+        if snapshots.is_empty() {
+            s.add_layer(Dialog::info(format!("No snapshots exist for {}-realmfs.img", realmfs.name())).title("Restore Snapshot"));
+            return;
+        }
+
+        let mut select = SelectView::new();
+        for snapshot in snapshots.into_iter().rev() {
+            select.add_item(Self::snapshot_label(&snapshot), snapshot);
+        }
+        select.set_on_submit(move |s, snapshot: &Snapshot| Self::confirm_restore(s, &realmfs, snapshot));
+
+        let content = LinearLayout::vertical()
+            .child(TextView::new("Select a snapshot to restore. The current image will be kept as a backup copy."))
+            .child(DummyView)
+            .child(select.scrollable().max_height(10));
+
+        let dialog = Dialog::around(PaddedView::new((2,2,1,1), content))
+            .title("Restore Snapshot")
+            .dismiss_button("Cancel")
+            .min_width(60);
+        s.add_layer(dialog);
+    }
+
+    fn snapshot_label(snapshot: &Snapshot) -> String {
+        let mut label = format!("{:<24} {}", snapshot.name(), util::format_timestamp(snapshot.timestamp()));
+        if let Some(note) = snapshot.note() {
+            label.push_str("  ");
+            label.push_str(note);
+        }
+        label
+    }
+
+    fn confirm_restore(s: &mut Cursive, realmfs: &RealmFS, snapshot: &Snapshot) {
+        if realmfs.is_activated() {
+            s.add_layer(Dialog::info("Cannot restore snapshot because RealmFS is currently activated. Deactivate first").title("Cannot Restore"));
+            return;
+        }
+        let title = "Restore Snapshot?";
+        let msg = format!("Replace {}-realmfs.img with snapshot '{}'?", realmfs.name(), snapshot.name());
+        let realmfs = realmfs.clone();
+        let name = snapshot.name().to_string();
+        let dialog = confirm_dialog(title, &msg, move |s| {
+            s.pop_layer();
+            Self::restore(s, &realmfs, &name);
+        });
+        s.add_layer(dialog);
+    }
+
+    fn restore(s: &mut Cursive, realmfs: &RealmFS, name: &str) {
+        let title = format!("Restore {}-realmfs.img", realmfs.name());
+        let task = {
+            let realmfs = realmfs.clone();
+            let name = name.to_string();
+            move |progress: &mut dyn Progress| realmfs.restore_snapshot(&name, progress)
+        };
+        let realmfs = realmfs.clone();
+        let name = name.to_string();
+        ProgressDialog::run(s, &title, task, move |s, result| {
+            if let Err(e) = result {
+                let msg = format!("Failed to restore RealmFS '{}' from snapshot '{}': {}", realmfs.name(), name, e);
+                warn!(msg.as_str());
+                s.add_layer(Dialog::info(msg.as_str()));
+                return;
+            }
+            ItemList::<RealmFS>::call_reload("realmfs", s);
+        });
+    }
+}
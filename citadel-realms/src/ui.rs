@@ -1,7 +1,7 @@
 
 use cursive::{Cursive, event::{Event, Key, EventResult}, traits::View, views::LinearLayout, CbSink, ScreenId};
 
-use libcitadel::{Result, RealmFS, Logger, LogLevel, Realm, RealmManager,RealmEvent};
+use libcitadel::{Result, RealmFS, Logger, LogLevel, Realm, RealmManager,RealmEvent,NoProgress,ActivationReconciler};
 
 use crate::backend::Backend;
 use crate::logview::LogView;
@@ -82,6 +82,9 @@ impl RealmUI {
         log_output.set_as_log_output();
 
         let manager = RealmManager::load()?;
+        if let Err(e) = ActivationReconciler::new(&manager).run() {
+            warn!("error reconciling RealmFS activations: {}", e);
+        }
         let inner = Arc::new(RwLock::new(Inner::new()));
 
         Ok(RealmUI{ manager, inner, log_output })
@@ -336,7 +339,7 @@ impl RealmUI {
 
         if let Some(size) = update.auto_resize_size() {
             println!("Resizing image to {} gb", size.size_in_gb());
-            update.apply_resize(size)?;
+            update.apply_resize(size, &mut NoProgress)?;
         }
 
         println!();
@@ -6,7 +6,7 @@ use std::thread::{self,JoinHandle};
 use std::time::{self,Instant};
 
 use libcitadel::Result;
-use libcitadel::ResourceImage;
+use libcitadel::{ResourceImage,NoProgress};
 use crate::boot::disks;
 use crate::boot::rootfs::setup_rootfs_resource;
 use crate::install::installer::Installer;
@@ -146,7 +146,7 @@ fn decompress_one_image(image: ResourceImage) -> JoinHandle<Result<()>> {
     thread::spawn(move || {
         let start = Instant::now();
         info!("Decompressing {}", image.path().display());
-        image.decompress()?;
+        image.decompress(&mut NoProgress)?;
         cmd!("/usr/bin/du", "-h {}", image.path().display())?;
         info!("Decompress {:?} finished in {} seconds",
               image.path().file_name().unwrap(),
@@ -1,8 +1,10 @@
 use std::fs;
 use std::process::exit;
+use std::thread;
+use std::time::{Duration,Instant};
 
-use libcitadel::{Result,ResourceImage,CommandLine,format_error,KeyRing,LogLevel,Logger};
-use libcitadel::RealmManager;
+use libcitadel::{Result,ResourceImage,CommandLine,OsRelease,Partition,format_error,KeyRing,LogLevel,Logger};
+use libcitadel::{RealmManager,ActivationReconciler};
 
 mod live;
 mod disks;
@@ -20,6 +22,7 @@ pub fn main(args: Vec<String>) {
         Some(s) if s == "rootfs" => do_rootfs(),
         Some(s) if s == "setup" => do_setup(),
         Some(s) if s == "start-realms" => do_start_realms(),
+        Some(s) if s == "auto-bless" => do_auto_bless(),
         _ => Err(format_err!("Bad or missing argument")),
     };
 
@@ -86,5 +89,63 @@ fn mount_overlay() -> Result<()> {
 
 fn do_start_realms() -> Result<()> {
     let manager = RealmManager::load()?;
+    // Nothing else can be using RealmFS images before realms are started, so
+    // anything left behind by a crash can safely be removed.
+    if let Err(e) = ActivationReconciler::new(&manager).exclusive(true).run() {
+        warn!("Failed to reconcile RealmFS activations: {}", format_error(&e));
+    }
     manager.start_boot_realms()
 }
+
+/// Units which must be active before the booted rootfs partition is marked as good
+const DEFAULT_BLESS_TARGETS: &str = "graphical.target";
+
+/// Maximum time to wait for bless targets to become active
+const BLESS_TIMEOUT: Duration = Duration::from_secs(600);
+
+fn bless_targets() -> Vec<&'static str> {
+    CommandLine::bless_targets()
+        .or_else(OsRelease::citadel_bless_targets)
+        .unwrap_or(DEFAULT_BLESS_TARGETS)
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn targets_active(targets: &[&str]) -> Result<bool> {
+    for target in targets {
+        if !cmd_ok!("/usr/bin/systemctl", "is-active --quiet {}", target)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+//
+// Wait until every unit in the list of bless targets is active and then mark the
+// booted rootfs partition as successfully booted. The list of units is read from
+// the kernel command line variable citadel.bless-targets or from CITADEL_BLESS_TARGETS
+// in /etc/os-release.
+//
+fn do_auto_bless() -> Result<()> {
+    let targets = bless_targets();
+    info!("Waiting for {} before marking rootfs partition as good", targets.join(", "));
+
+    let start = Instant::now();
+    while !targets_active(&targets)? {
+        if start.elapsed() > BLESS_TIMEOUT {
+            bail!("Timed out waiting for {} to become active, not marking rootfs partition as good", targets.join(", "));
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+
+    for mut p in Partition::rootfs_partitions()? {
+        if p.is_initialized() && p.is_mounted() {
+            info!("Marking rootfs partition {} as good", p.path().display());
+            return p.bless();
+        }
+    }
+    warn!("No mounted partition found to bless");
+    Ok(())
+}
@@ -1,6 +1,6 @@
 use std::process::Command;
 
-use libcitadel::{BlockDev, ResourceImage, CommandLine, ImageHeader, Partition, Result, LoopDevice};
+use libcitadel::{BlockDev, ResourceImage, CommandLine, ImageHeader, Partition, Result, LoopDevice, VersionFloor, NoProgress};
 use std::path::Path;
 use std::process::Stdio;
 use libcitadel::verity::Verity;
@@ -24,7 +24,7 @@ pub fn setup_rootfs_resource(rootfs: &ResourceImage) -> Result<()> {
 
 fn setup_resource_unverified(img: &ResourceImage) -> Result<()> {
     if img.is_compressed() {
-        img.decompress()?;
+        img.decompress(&mut NoProgress)?;
     }
     let loopdev = LoopDevice::create(img.path(), Some(4096), true)?;
     info!("Loop device created: {}", loopdev);
@@ -49,9 +49,11 @@ fn setup_partition_verified(p: &mut Partition) -> Result<()> {
         }
         if !p.is_signature_valid() {
             p.write_status(ImageHeader::STATUS_BAD_SIG)?;
-            bail!("Signature verification failed on partition");
+            bail!("Signature verification failed on partition ({} of {} required signatures valid)",
+                  p.valid_signature_count(), p.signature_threshold());
         }
-        info!("Image signature is valid for channel {}", p.metainfo().channel());
+        info!("Image signature is valid for channel {} ({} of {} required signatures)",
+              p.metainfo().channel(), p.valid_signature_count(), p.signature_threshold());
     }
     Verity::setup_partition(p)?;
     Ok(())
@@ -85,15 +87,34 @@ fn choose_boot_partiton(scan: bool) -> Result<Partition> {
         }
     }
 
+    let floor = boot_version_floor(&partitions);
+
     let mut best = None;
     for p in partitions {
-        best = compare_boot_partitions(best, p);
+        best = compare_boot_partitions(best, p, &floor);
+    }
+    let mut best = best.ok_or_else(|| format_err!("No partition found to boot from"))?;
+    if scan {
+        best.begin_boot_attempt()?;
+    }
+    Ok(best)
+}
+
+// The stored version floor is usually not available yet when choosing a boot partition
+// so also raise the floor from the metainfo of any partition which has booted successfully.
+// Unless signatures are disabled only a partition signed by the channel keys may raise it.
+fn boot_version_floor(partitions: &[Partition]) -> VersionFloor {
+    let mut floor = VersionFloor::load_or_default();
+    for p in partitions {
+        if p.is_initialized() && p.is_good() && (!signatures_enabled() || p.is_signature_valid()) {
+            floor.raise_from_metainfo(&p.metainfo());
+        }
     }
-    best.ok_or_else(|| format_err!("No partition found to boot from"))
+    floor
 }
 
-fn compare_boot_partitions(a: Option<Partition>, b: Partition) -> Option<Partition> {
-    if !is_bootable(&b) {
+fn compare_boot_partitions(a: Option<Partition>, b: Partition, floor: &VersionFloor) -> Option<Partition> {
+    if !is_bootable(&b, floor) {
         return a;
     }
 
@@ -125,20 +146,26 @@ fn compare_boot_partitions(a: Option<Partition>, b: Partition) -> Option<Partiti
         }
     }
 
-    // choose NEW over GOOD if versions are the same or
+    // choose NEW or TRY_BOOT over GOOD if versions are the same or
     // if versions cannot be compared because channels differ
-    if b.is_new() && a.is_good() {
+    if (b.is_new() || b.is_try_boot()) && a.is_good() {
         return Some(b);
     }
 
     Some(a)
 }
 
-fn is_bootable(p: &Partition) -> bool {
+fn is_bootable(p: &Partition, floor: &VersionFloor) -> bool {
     if !p.is_initialized() {
         return false;
     }
 
+    if !floor.permits(&p.metainfo()) {
+        warn!("Partition {} has version {} which is below the minimum version for channel {}",
+              p.path().display(), p.metainfo().version(), p.metainfo().channel());
+        return false;
+    }
+
     // signatures enabled so not bootable without pubkey
     if signatures_enabled() && !p.has_public_key() {
         return false;
@@ -148,6 +175,11 @@ fn is_bootable(p: &Partition) -> bool {
         return true;
     }
 
+    // boot_scan() has already marked TRY_BOOT partitions with no tries remaining as FAILED
+    if p.is_try_boot() && p.boot_tries() > 0 {
+        return true;
+    }
+
     // If signatures are disabled then don't disqualify an
     // image which failed a prior signature verification
     if !signatures_enabled() && p.is_sig_failed() {
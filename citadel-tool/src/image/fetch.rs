@@ -0,0 +1,186 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use libcitadel::{Result,ResourceImage,ChannelManifest,ManifestImage,util};
+
+/// Directory into which fetched images are staged
+const RESOURCES_BASEDIR: &str = "/storage/resources";
+
+///
+/// Downloads the signed manifest for a channel from an update server and then
+/// downloads and verifies the images it lists.
+///
+/// The update server is any HTTP server (or a local directory) with one
+/// directory for each channel containing the manifest and the image files:
+///
+///     $base/$channel/manifest
+///     $base/$channel/manifest.sig
+///     $base/$channel/citadel-rootfs-$channel-012.img
+///
+pub struct Fetcher {
+    base: String,
+    channel: String,
+    target_dir: PathBuf,
+}
+
+impl Fetcher {
+    pub fn new(base: &str, channel: &str, target_dir: Option<&str>) -> Fetcher {
+        let target_dir = match target_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => Path::new(RESOURCES_BASEDIR).join(channel),
+        };
+        Fetcher {
+            base: base.trim_end_matches('/').to_string(),
+            channel: channel.to_string(),
+            target_dir,
+        }
+    }
+
+    fn is_http(&self) -> bool {
+        self.base.starts_with("http://") || self.base.starts_with("https://")
+    }
+
+    fn fetch_file(&self, filename: &str, target: &Path) -> Result<()> {
+        if self.is_http() {
+            let url = format!("{}/{}/{}", self.base, self.channel, filename);
+            info!("Downloading {}", url);
+            cmd!("/usr/bin/curl", "--fail --silent --show-error --location --output {} {}", target.display(), url)
+        } else {
+            let source = Path::new(self.base.trim_start_matches("file://")).join(&self.channel).join(filename);
+            info!("Copying {}", source.display());
+            fs::copy(&source, target)
+                .map_err(|e| format_err!("failed to copy {}: {}", source.display(), e))?;
+            Ok(())
+        }
+    }
+
+    /// Download the manifest and signature file for the channel and return the
+    /// manifest if the signature is valid. The downloaded files only replace the
+    /// stored manifest once verified, and a manifest older than the stored one
+    /// is rejected.
+    pub fn fetch_manifest(&self) -> Result<ChannelManifest> {
+        fs::create_dir_all(&self.target_dir)?;
+        let path = self.target_dir.join(ChannelManifest::MANIFEST_FILENAME);
+        let sigpath = self.target_dir.join(ChannelManifest::SIGNATURE_FILENAME);
+        let staging = self.target_dir.join(format!(".{}.fetch", ChannelManifest::MANIFEST_FILENAME));
+        let sigstaging = self.target_dir.join(format!(".{}.fetch", ChannelManifest::SIGNATURE_FILENAME));
+
+        let result = self.fetch_file(ChannelManifest::MANIFEST_FILENAME, &staging)
+            .and_then(|_| self.fetch_file(ChannelManifest::SIGNATURE_FILENAME, &sigstaging))
+            .and_then(|_| self.verify_manifest(&staging, &sigstaging, &path));
+
+        let manifest = match result {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                let _ = fs::remove_file(&staging);
+                let _ = fs::remove_file(&sigstaging);
+                return Err(err);
+            }
+        };
+
+        fs::rename(&sigstaging, &sigpath)?;
+        fs::rename(&staging, &path)?;
+        info!("Manifest signature is valid for channel {}", self.channel);
+        Ok(manifest)
+    }
+
+    fn verify_manifest(&self, path: &Path, sigpath: &Path, current: &Path) -> Result<ChannelManifest> {
+        let manifest = ChannelManifest::parse_verified(&fs::read(path)?, &fs::read(sigpath)?)?;
+        if manifest.channel() != self.channel {
+            bail!("Manifest is for channel '{}' but expected channel '{}'", manifest.channel(), self.channel);
+        }
+        if current.exists() {
+            let stored = ChannelManifest::parse_bytes(&fs::read(current)?)?;
+            if manifest.is_older_than(&stored) {
+                bail!("Manifest timestamp {} is older than stored manifest timestamp {}", manifest.timestamp(), stored.timestamp());
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Download every image listed in `manifest` which has one of the types in `image_types`
+    /// (or all images if `image_types` is empty) and which is not already present.
+    pub fn fetch_images(&self, manifest: &ChannelManifest, image_types: &[&str]) -> Result<()> {
+        for image in manifest.images() {
+            if image_types.is_empty() || image_types.contains(&image.image_type()) {
+                self.fetch_image(image)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fetch_image(&self, image: &ManifestImage) -> Result<()> {
+        let target = self.target_dir.join(image.filename());
+        if target.exists() && Self::matches_manifest(&target, image)? {
+            info!("Image {} is already up to date", image.filename());
+            return Ok(());
+        }
+
+        let staging = self.target_dir.join(format!(".{}.fetch", image.filename()));
+        let result = self.fetch_file(image.filename(), &staging)
+            .and_then(|_| self.verify_staged(&staging, image));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&staging);
+            return Err(err);
+        }
+
+        fs::rename(&staging, &target)?;
+        info!("Image {} staged to {}", image.filename(), target.display());
+        Ok(())
+    }
+
+    fn matches_manifest(path: &Path, image: &ManifestImage) -> Result<bool> {
+        if path.metadata()?.len() != image.size() {
+            return Ok(false);
+        }
+        Ok(util::sha256(path)? == image.shasum())
+    }
+
+    fn verify_staged(&self, path: &Path, image: &ManifestImage) -> Result<()> {
+        if !Self::matches_manifest(path, image)? {
+            bail!("Downloaded image {} does not match size and sha256 in manifest", image.filename());
+        }
+
+        // ResourceImage requires the .img extension so check header through a link
+        let link = path.with_extension("fetch.img");
+        let _ = fs::remove_file(&link);
+        fs::hard_link(path, &link)?;
+        let result = ResourceImage::from_path(&link);
+        fs::remove_file(&link)?;
+
+        let metainfo = result?.metainfo();
+        if metainfo.image_type() != image.image_type() || metainfo.version() != image.version() || metainfo.channel() != self.channel {
+            bail!("Header of downloaded image {} does not match manifest", image.filename());
+        }
+        Ok(())
+    }
+}
+
+/// Build a manifest for the channel `channel` from the image files found in `dir`.
+pub fn create_manifest(dir: &Path, channel: &str, timestamp: &str) -> Result<ChannelManifest> {
+    let mut manifest = ChannelManifest::new(channel, timestamp);
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("img")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    for path in paths {
+        let img = ResourceImage::from_path(&path)?;
+        let metainfo = img.metainfo();
+        if metainfo.channel() != channel {
+            warn!("Skipping {} because it is for channel '{}'", path.display(), metainfo.channel());
+            continue;
+        }
+        let filename = path.file_name().unwrap().to_string_lossy();
+        info!("Adding {} to manifest", filename);
+        manifest.add_image(ManifestImage::new(
+            metainfo.image_type(), metainfo.version(), &filename,
+            &util::sha256(&path)?, path.metadata()?.len(), metainfo.kernel_version()));
+    }
+    Ok(manifest)
+}
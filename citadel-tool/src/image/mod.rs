@@ -3,16 +3,30 @@ use std::process::exit;
 
 use clap::{App,Arg,SubCommand,ArgMatches};
 use clap::AppSettings::*;
-use libcitadel::{Result,ResourceImage,Logger,LogLevel,format_error,Partition,KeyPair,ImageHeader};
+use libcitadel::{Result,ResourceImage,Logger,LogLevel,format_error,Partition,KeyPair,ImageHeader,KeyRing,PublicKey,ChannelManifest,OsRelease,VersionFloor,MetaInfo,CommandLine};
 use std::fs;
+use std::time::{SystemTime,UNIX_EPOCH};
 use hex;
 
+use crate::progress::ProgressBar;
+
+mod fetch;
+mod status;
+
+pub use self::status::StderrLogOutput;
+
 pub fn main(args: Vec<String>) {
 
     let app = App::new("citadel-image")
         .about("Citadel update image builder")
         .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
 
+        .subcommand(SubCommand::with_name("status")
+            .about("Display status of rootfs partitions, resource images and RealmFS images")
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Print status as JSON")))
+
         .subcommand(SubCommand::with_name("metainfo")
             .about("Display metainfo variables for an image file")
             .arg(Arg::with_name("path")
@@ -48,10 +62,81 @@ pub fn main(args: Vec<String>) {
             .arg(Arg::with_name("no-prefer")
                 .long("no-prefer")
                 .help("Don't set PREFER_BOOT flag"))
+            .arg(Arg::with_name("allow-rollback")
+                .long("allow-rollback")
+                .help("Install image even if version is below the minimum version (recovery mode only)"))
             .arg(Arg::with_name("path")
                 .required_unless("choose")
                 .help("Path to image file")))
 
+        .subcommand(SubCommand::with_name("sign-image")
+            .about("Sign an image file or a detached metainfo file")
+            .arg(Arg::with_name("keyfile")
+                .long("keyfile")
+                .takes_value(true)
+                .conflicts_with_all(&["keyring", "signature"])
+                .help("Sign with keypair read from file (as generated by genkeys)"))
+            .arg(Arg::with_name("keyring")
+                .long("keyring")
+                .takes_value(true)
+                .conflicts_with("signature")
+                .help("Sign with keypair stored in kernel keyring under this name"))
+            .arg(Arg::with_name("metainfo")
+                .long("metainfo")
+                .takes_value(true)
+                .conflicts_with_all(&["path", "signature"])
+                .help("Sign a detached metainfo file and write signature to <metainfo>.sig"))
+            .arg(Arg::with_name("signature")
+                .long("signature")
+                .takes_value(true)
+                .help("Add signature from a file created with --metainfo to image"))
+            .arg(Arg::with_name("path")
+                .required_unless("metainfo")
+                .help("Path to image file")))
+
+        .subcommand(SubCommand::with_name("fetch")
+            .about("Download and verify images listed in the manifest for a channel")
+            .arg(Arg::with_name("channel")
+                .long("channel")
+                .takes_value(true)
+                .help("Channel to fetch (default: channel from /etc/os-release)"))
+            .arg(Arg::with_name("image-type")
+                .long("type")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only fetch images of this type"))
+            .arg(Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .help("Directory to stage images into (default: /storage/resources/<channel>)"))
+            .arg(Arg::with_name("url")
+                .required(true)
+                .help("Base URL or directory of update server")))
+
+        .subcommand(SubCommand::with_name("create-manifest")
+            .about("Create a signed channel manifest for the image files in a directory")
+            .arg(Arg::with_name("channel")
+                .long("channel")
+                .takes_value(true)
+                .required(true)
+                .help("Channel name of manifest"))
+            .arg(Arg::with_name("keyfile")
+                .long("keyfile")
+                .takes_value(true)
+                .conflicts_with("keyring")
+                .help("Sign with keypair read from file (as generated by genkeys)"))
+            .arg(Arg::with_name("keyring")
+                .long("keyring")
+                .takes_value(true)
+                .help("Sign with keypair stored in kernel keyring under this name"))
+            .arg(Arg::with_name("sign-only")
+                .long("sign-only")
+                .help("Add a signature to an existing manifest instead of creating a new one"))
+            .arg(Arg::with_name("dir")
+                .required(true)
+                .help("Directory containing image files")))
+
         .subcommand(SubCommand::with_name("genkeys")
             .about("Generate a pair of keys"))
 
@@ -74,11 +159,14 @@ pub fn main(args: Vec<String>) {
 
     let matches = app.get_matches_from(args);
     let result = match matches.subcommand() {
+        ("status", Some(m)) => status(m),
         ("metainfo", Some(m)) => metainfo(m),
         ("info", Some(m)) => info(m),
         ("generate-verity", Some(m)) => generate_verity(m),
         ("verify", Some(m)) => verify(m),
         ("sign-image", Some(m)) => sign_image(m),
+        ("fetch", Some(m)) => fetch(m),
+        ("create-manifest", Some(m)) => create_manifest(m),
         ("genkeys", Some(_)) => genkeys(),
         ("decompress", Some(m)) => decompress(m),
         ("verify-shasum", Some(m)) => verify_shasum(m),
@@ -107,18 +195,34 @@ fn info_signature(img: &ResourceImage) -> Result<()> {
     } else {
         println!("Signature: No Signature");
     }
-    match img.header().public_key()? {
-        Some(pubkey) => {
-            if img.header().verify_signature(pubkey) {
-                println!("Signature is valid");
+    for (pubkey, _) in img.header().signature_blocks() {
+        println!("Signed by: {}", hex::encode(pubkey.to_bytes()));
+    }
+    match img.header().public_keys()? {
+        Some(keys) => {
+            let count = img.header().valid_signature_count(&keys);
+            if count >= keys.threshold() {
+                println!("Signature is valid ({} of {} required signatures)", count, keys.threshold());
             } else {
-                println!("Signature verify FAILED");
+                println!("Signature verify FAILED ({} of {} required signatures)", count, keys.threshold());
             }
         },
         None => { println!("No public key found for channel '{}'", img.metainfo().channel()) },
     }
    Ok(())
 }
+
+fn status(arg_matches: &ArgMatches) -> Result<()> {
+    Logger::set_log_level(LogLevel::Warn);
+    if arg_matches.is_present("json") {
+        Logger::set_log_output(Box::new(status::StderrLogOutput));
+        status::StatusReport::generate()?.print_json()
+    } else {
+        status::StatusReport::generate()?.print_table();
+        Ok(())
+    }
+}
+
 fn metainfo(arg_matches: &ArgMatches) -> Result<()> {
     let img = load_image(arg_matches)?;
     print!("{}",String::from_utf8(img.header().metainfo_bytes())?);
@@ -130,14 +234,14 @@ fn generate_verity(arg_matches: &ArgMatches) -> Result<()> {
     if img.has_verity_hashtree() {
         info!("Image already has dm-verity hashtree appended, doing nothing.");
     } else {
-        img.generate_verity_hashtree()?;
+        img.generate_verity_hashtree(&mut ProgressBar::new())?;
     }
     Ok(())
 }
 
 fn verify(arg_matches: &ArgMatches) -> Result<()> {
     let img = load_image(arg_matches)?;
-    let ok = img.verify_verity()?;
+    let ok = img.verify_verity(&mut ProgressBar::new())?;
     if ok {
         info!("Image verification succeeded");
     } else {
@@ -179,7 +283,11 @@ fn install_rootfs(arg_matches: &ArgMatches) -> Result<()> {
 
     let img = load_image(arg_matches)?;
 
-    if !arg_matches.is_present("skip-sha") {
+    check_version_floor(&img.metainfo(), arg_matches.is_present("allow-rollback"))?;
+
+    // The sha256 value of a delta image describes the image after the delta
+    // is applied and is checked by comparing the verity root hash instead.
+    if !arg_matches.is_present("skip-sha") && !img.is_delta() {
         info!("Verifying sha256 hash of image");
         let shasum = img.generate_shasum()?;
         if shasum != img.metainfo().shasum() {
@@ -193,10 +301,40 @@ fn install_rootfs(arg_matches: &ArgMatches) -> Result<()> {
         clear_prefer_boot()?;
         img.header().set_flag(ImageHeader::FLAG_PREFER_BOOT);
     }
-    img.write_to_partition(&partition)?;
+    if img.is_delta() {
+        let base = booted_partition()?;
+        img.write_delta_to_partition(&base, &partition, &mut ProgressBar::new())?;
+    } else {
+        img.write_to_partition(&partition, &mut ProgressBar::new())?;
+    }
     Ok(())
 }
 
+fn check_version_floor(metainfo: &MetaInfo, allow_rollback: bool) -> Result<()> {
+    let floor = VersionFloor::load()?;
+    if floor.allows(metainfo) {
+        return Ok(());
+    }
+    let min = floor.min_version(metainfo.channel(), metainfo.image_type());
+    if allow_rollback && CommandLine::recovery_mode() {
+        warn!("Installing image version {} below minimum version {}", metainfo.version(), min);
+        return Ok(());
+    } else if allow_rollback {
+        bail!("--allow-rollback can only be used when booted in recovery mode");
+    }
+    bail!("Image version {} is below the minimum version {} for {} images on channel {}",
+          metainfo.version(), min, metainfo.image_type(), metainfo.channel());
+}
+
+fn booted_partition() -> Result<Partition> {
+    for p in Partition::rootfs_partitions()? {
+        if p.is_initialized() && p.is_mounted() {
+            return Ok(p);
+        }
+    }
+    Err(format_err!("No mounted rootfs partition found to apply delta image against"))
+}
+
 fn clear_prefer_boot() -> Result<()> {
     for mut p in Partition::rootfs_partitions()? {
         if p.is_initialized() && p.header().has_flag(ImageHeader::FLAG_PREFER_BOOT) {
@@ -206,30 +344,137 @@ fn clear_prefer_boot() -> Result<()> {
     Ok(())
 }
 
+#[derive(Serialize,Deserialize)]
+struct DetachedSignature {
+    #[serde(rename="public-key")]
+    public_key: String,
+    signature: String,
+}
+
 fn sign_image(arg_matches: &ArgMatches) -> Result<()> {
-    let _img = load_image(arg_matches)?;
-    info!("Not implemented yet");
+    if let Some(metainfo) = arg_matches.value_of("metainfo") {
+        return sign_metainfo(arg_matches, metainfo);
+    }
+
+    let img = load_image(arg_matches)?;
+    let header = img.header();
+
+    if let Some(sigfile) = arg_matches.value_of("signature") {
+        let content = fs::read_to_string(sigfile)?;
+        let detached: DetachedSignature = toml::from_str(&content)?;
+        let pubkey = PublicKey::from_hex(&detached.public_key)?;
+        let signature = hex::decode(&detached.signature)?;
+        header.insert_signature(&pubkey, &signature)?;
+    } else {
+        let keypair = load_signing_keys(arg_matches)?;
+        header.add_signature(&keypair)?;
+    }
+
+    header.write_header_to(img.path())?;
+    info!("Signature added to {}", img.path().display());
+    info_signature(&img)
+}
+
+fn sign_metainfo(arg_matches: &ArgMatches, path: &str) -> Result<()> {
+    let keypair = load_signing_keys(arg_matches)?;
+    let metainfo = fs::read(path)?;
+    if ImageHeader::new().set_metainfo_bytes(&metainfo).is_err() {
+        bail!("File {} is not a valid metainfo document", path);
+    }
+    let detached = DetachedSignature {
+        public_key: keypair.public_key().to_hex(),
+        signature: hex::encode(keypair.sign(&metainfo).to_bytes()),
+    };
+    let sigfile = format!("{}.sig", path);
+    fs::write(&sigfile, toml::to_string(&detached)?)?;
+    info!("Signature written to {}", sigfile);
     Ok(())
 }
 
+fn fetch(arg_matches: &ArgMatches) -> Result<()> {
+    let channel = match arg_matches.value_of("channel") {
+        Some(channel) => channel,
+        None => match OsRelease::citadel_channel() {
+            Some(channel) => channel,
+            None => bail!("No channel specified and no channel found in /etc/os-release"),
+        },
+    };
+    let url = arg_matches.value_of("url").expect("url argument missing");
+    let image_types = arg_matches.values_of("image-type")
+        .map(|v| v.collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let fetcher = fetch::Fetcher::new(url, channel, arg_matches.value_of("target"));
+    let manifest = fetcher.fetch_manifest()?;
+    fetcher.fetch_images(&manifest, &image_types)
+}
+
+fn create_manifest(arg_matches: &ArgMatches) -> Result<()> {
+    let dir = Path::new(arg_matches.value_of("dir").expect("dir argument missing"));
+    let channel = arg_matches.value_of("channel").expect("channel argument missing");
+
+    if !arg_matches.is_present("sign-only") {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().to_string();
+        let manifest = fetch::create_manifest(dir, channel, &timestamp)?;
+        fs::write(dir.join(ChannelManifest::MANIFEST_FILENAME), manifest.to_bytes()?)?;
+        let _ = fs::remove_file(dir.join(ChannelManifest::SIGNATURE_FILENAME));
+        info!("Manifest written to {}", dir.join(ChannelManifest::MANIFEST_FILENAME).display());
+    }
+
+    if arg_matches.is_present("keyfile") || arg_matches.is_present("keyring") {
+        let keypair = load_signing_keys(arg_matches)?;
+        ChannelManifest::sign_file(dir, &keypair)?;
+        info!("Manifest signature written to {}", dir.join(ChannelManifest::SIGNATURE_FILENAME).display());
+    } else if arg_matches.is_present("sign-only") {
+        bail!("No signing keys specified, use --keyfile or --keyring");
+    }
+    Ok(())
+}
+
+fn load_signing_keys(arg_matches: &ArgMatches) -> Result<KeyPair> {
+    if let Some(name) = arg_matches.value_of("keyring") {
+        return KeyRing::get_kernel_keypair(name);
+    }
+    let path = match arg_matches.value_of("keyfile") {
+        Some(path) => path,
+        None => bail!("No signing keys specified, use --keyfile or --keyring"),
+    };
+    let content = fs::read_to_string(path)?;
+    // Accept either the output of 'citadel-image genkeys' or a bare hex string
+    let hex = match content.parse::<toml::Value>() {
+        Ok(value) => match value.get("keypair").and_then(|v| v.as_str()) {
+            Some(s) => s.to_string(),
+            None => bail!("No 'keypair' field found in key file {}", path),
+        },
+        Err(_) => content.trim().to_string(),
+    };
+    KeyPair::from_hex(&hex)
+}
+
 fn install_image(arg_matches: &ArgMatches) -> Result<()> {
     let source = arg_matches.value_of("path").expect("path argument missing");
     let img = load_image(arg_matches)?;
-    let _hdr = img.header();
+    let hdr = img.header();
     let metainfo = img.metainfo();
 
-    // XXX verify signature?
+    // XXX refuse to install unsigned images?
+    let verified = match hdr.public_keys() {
+        Ok(Some(ref keys)) => hdr.verify_signatures(keys),
+        _ => false,
+    };
 
     if !(metainfo.image_type() == "kernel" || metainfo.image_type() == "extra") {
         bail!("Cannot install image type {}", metainfo.image_type());
     }
 
+    check_version_floor(&metainfo, false)?;
+
     let shasum = img.generate_shasum()?;
     if shasum != img.metainfo().shasum() {
         bail!("Image shasum does not match metainfo");
     }
 
-    img.generate_verity_hashtree()?;
+    img.generate_verity_hashtree(&mut ProgressBar::new())?;
 
     let filename = if metainfo.image_type() == "kernel" {
         let kernel_version = match metainfo.kernel_version() {
@@ -253,6 +498,13 @@ fn install_image(arg_matches: &ArgMatches) -> Result<()> {
         rotate(&image_dest)?;
     }
     fs::rename(source,image_dest)?;
+
+    // An unverified header could set an arbitrarily high floor and lock out every legitimate image
+    if verified {
+        VersionFloor::update_from_metainfo(&metainfo)?;
+    } else {
+        warn!("Not raising version floor because image signature could not be verified");
+    }
     Ok(())
 }
 
@@ -280,7 +532,7 @@ fn decompress(arg_matches: &ArgMatches) -> Result<()> {
     if !img.is_compressed() {
         info!("Image is not compressed, not decompressing.");
     } else {
-        img.decompress()?;
+        img.decompress(&mut ProgressBar::new())?;
     }
     Ok(())
 }
@@ -0,0 +1,247 @@
+use std::io::{self,Write};
+use std::path::Path;
+
+use libcitadel::{Result,Partition,ResourceImage,ImageHeader,ChannelKeys,RealmManager,RealmFS,Activation,Logger,LogLevel,LogOutput};
+use libcitadel::verity::Verity;
+
+/// Resource image types which are reported in addition to rootfs partitions and RealmFS images
+const RESOURCE_TYPES: &[&str] = &["kernel", "extra"];
+
+///
+/// A summary of the state of every image on the system: the rootfs partitions,
+/// the kernel and extra resource images and all RealmFS images.
+///
+#[derive(Serialize,Default)]
+pub struct StatusReport {
+    partitions: Vec<ImageStatus>,
+    resources: Vec<ImageStatus>,
+    realmfs: Vec<ImageStatus>,
+}
+
+#[derive(Serialize,Default)]
+struct ImageStatus {
+    name: String,
+    path: String,
+    #[serde(rename = "image-type")]
+    image_type: Option<String>,
+    channel: Option<String>,
+    version: Option<u32>,
+    status: String,
+    flags: Vec<&'static str>,
+    #[serde(rename = "boot-tries", skip_serializing_if = "Option::is_none")]
+    boot_tries: Option<u8>,
+    signature: &'static str,
+    verity: &'static str,
+    mounted: bool,
+}
+
+impl ImageStatus {
+    fn from_header(name: &str, path: &Path, header: &ImageHeader) -> ImageStatus {
+        let metainfo = header.metainfo();
+        let mut flags = Vec::new();
+        if header.has_flag(ImageHeader::FLAG_PREFER_BOOT) {
+            flags.push("prefer-boot");
+        }
+        if header.has_flag(ImageHeader::FLAG_HASH_TREE) {
+            flags.push("hash-tree");
+        }
+        if header.has_flag(ImageHeader::FLAG_DATA_COMPRESSED) {
+            flags.push("compressed");
+        }
+        ImageStatus {
+            name: name.to_string(),
+            path: path.display().to_string(),
+            image_type: Some(metainfo.image_type().to_string()),
+            channel: Some(metainfo.channel().to_string()),
+            version: Some(metainfo.version()),
+            status: header.status_code_label(),
+            flags,
+            verity: if header.has_flag(ImageHeader::FLAG_HASH_TREE) { "hashtree" } else { "none" },
+            ..Default::default()
+        }
+    }
+
+    fn missing(name: &str, path: &Path, status: &str) -> ImageStatus {
+        ImageStatus {
+            name: name.to_string(),
+            path: path.display().to_string(),
+            status: status.to_string(),
+            signature: "-",
+            verity: "-",
+            ..Default::default()
+        }
+    }
+}
+
+impl StatusReport {
+    pub fn generate() -> Result<StatusReport> {
+        let mut report = StatusReport::default();
+        for partition in Partition::rootfs_partitions()? {
+            report.partitions.push(Self::partition_status(&partition));
+        }
+        for image_type in RESOURCE_TYPES {
+            report.resources.push(Self::resource_status(image_type));
+        }
+        match RealmManager::load() {
+            Ok(manager) => {
+                for realmfs in manager.realmfs_list() {
+                    report.realmfs.push(Self::realmfs_status(&realmfs));
+                }
+            },
+            Err(err) => warn!("Unable to load RealmFS images: {}", err),
+        }
+        Ok(report)
+    }
+
+    fn partition_status(partition: &Partition) -> ImageStatus {
+        let name = partition.path().file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if !partition.is_initialized() {
+            let mut status = ImageStatus::missing(&name, partition.path(), "Empty");
+            status.mounted = partition.is_mounted();
+            return status;
+        }
+
+        let header = partition.header();
+        let mut status = ImageStatus::from_header(&name, partition.path(), header);
+        status.boot_tries = Some(partition.boot_tries());
+        status.signature = signature_state(header, header.public_keys());
+        status.mounted = partition.is_mounted();
+        if partition.is_mounted() && device_mapper_exists(&Verity::device_name(&header.metainfo())) {
+            status.verity = "active";
+        }
+        status
+    }
+
+    fn resource_status(image_type: &str) -> ImageStatus {
+        let image = match ResourceImage::find(image_type) {
+            Ok(image) => image,
+            Err(_) => return ImageStatus::missing(image_type, Path::new("-"), "Not found"),
+        };
+        let header = image.header();
+        let mut status = ImageStatus::from_header(image_type, image.path(), header);
+        status.signature = signature_state(header, header.public_keys());
+        status.mounted = image.is_mounted();
+        if device_mapper_exists(&Verity::device_name(&header.metainfo())) {
+            status.verity = "active";
+        }
+        status
+    }
+
+    fn realmfs_status(realmfs: &RealmFS) -> ImageStatus {
+        let header = realmfs.header();
+        let mut status = ImageStatus::from_header(realmfs.name(), realmfs.path(), header);
+        status.signature = if !realmfs.is_sealed() {
+            "unsealed"
+        } else if header.metainfo().channel() == RealmFS::USER_KEYNAME {
+            let keys = realmfs.sealing_keys()
+                .map(|keys| Some(ChannelKeys::single(keys.public_key())))
+                .or_else(|_| Ok(None));
+            signature_state(header, keys)
+        } else {
+            signature_state(header, header.public_keys())
+        };
+        match realmfs.activation() {
+            Some(ref activation) => {
+                status.mounted = true;
+                status.verity = match **activation {
+                    Activation::Verity { .. } => "active",
+                    Activation::Loop { .. } => "loop",
+                };
+            },
+            None => status.mounted = false,
+        }
+        status
+    }
+
+    pub fn print_json(&self) -> Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        serde_json::to_writer_pretty(&mut out, self)?;
+        writeln!(out)?;
+        Ok(())
+    }
+
+    pub fn print_table(&self) {
+        let mut rows = vec![
+            ["", "NAME", "CHANNEL", "VERSION", "STATUS", "FLAGS", "SIGNATURE", "VERITY", "MOUNTED"].iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+        ];
+        let sections = [("rootfs", &self.partitions), ("resource", &self.resources), ("realmfs", &self.realmfs)];
+        for (label, images) in sections.iter() {
+            for image in images.iter() {
+                rows.push(Self::table_row(label, image));
+            }
+        }
+
+        let mut widths = vec![0; rows[0].len()];
+        for row in &rows {
+            for (idx, col) in row.iter().enumerate() {
+                widths[idx] = widths[idx].max(col.len());
+            }
+        }
+        for row in &rows {
+            let line = row.iter().zip(widths.iter())
+                .map(|(col, width)| format!("{:<width$}", col, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            println!("{}", line.trim_end());
+        }
+    }
+
+    fn table_row(label: &str, image: &ImageStatus) -> Vec<String> {
+        let mut flags = image.flags.join(",");
+        if let Some(tries) = image.boot_tries {
+            if tries > 0 {
+                if !flags.is_empty() {
+                    flags.push(',');
+                }
+                flags.push_str(&format!("tries={}", tries));
+            }
+        }
+        vec![
+            label.to_string(),
+            image.name.clone(),
+            image.channel.clone().unwrap_or_else(|| "-".to_string()),
+            image.version.map(|v| format!("{:03}", v)).unwrap_or_else(|| "-".to_string()),
+            image.status.clone(),
+            if flags.is_empty() { "-".to_string() } else { flags },
+            image.signature.to_string(),
+            image.verity.to_string(),
+            if image.mounted { "yes".to_string() } else { "no".to_string() },
+        ]
+    }
+}
+
+fn signature_state(header: &ImageHeader, keys: Result<Option<ChannelKeys>>) -> &'static str {
+    if !header.has_signature() && header.signature_block_count() == 0 {
+        return "unsigned";
+    }
+    match keys {
+        Ok(Some(ref keys)) if header.verify_signatures(keys) => "valid",
+        Ok(Some(_)) => "invalid",
+        Ok(None) => "no-key",
+        Err(err) => {
+            warn!("Error loading public keys for channel {}: {}", header.metainfo().channel(), err);
+            "no-key"
+        },
+    }
+}
+
+fn device_mapper_exists(name: &str) -> bool {
+    Path::new("/dev/mapper").join(name).exists()
+}
+
+/// Sends log output to stderr so that it does not mix with JSON written to stdout
+pub struct StderrLogOutput;
+
+impl LogOutput for StderrLogOutput {
+    fn log_output(&mut self, level: LogLevel, line: &str) -> Result<()> {
+        let line = Logger::format_logline(level, line);
+        io::stderr().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
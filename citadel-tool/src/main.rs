@@ -13,8 +13,10 @@ mod boot;
 mod image;
 mod install;
 mod mkimage;
+mod realm;
 mod realmfs;
 mod sync;
+mod progress;
 
 fn main() {
     let exe = match env::current_exe() {
@@ -32,6 +34,8 @@ fn main() {
         install::main(args);
     } else if exe == Path::new("/usr/bin/citadel-image") {
         image::main(args);
+    } else if exe == Path::new("/usr/bin/citadel-realm") {
+        realm::main(args);
     } else if exe == Path::new("/usr/bin/citadel-realmfs") {
         realmfs::main(args);
     } else if exe == Path::new("/usr/libexec/citadel-desktop-sync") {
@@ -53,6 +57,7 @@ fn dispatch_command(args: Vec<String>) {
             "boot" => boot::main(rebuild_args("citadel-boot", args)),
             "install" => install::main(rebuild_args("citadel-install", args)),
             "image" => image::main(rebuild_args("citadel-image", args)),
+            "realm" => realm::main(rebuild_args("citadel-realm", args)),
             "realmfs" => realmfs::main(rebuild_args("citadel-realmfs", args)),
             "mkimage" => mkimage::main(rebuild_args("citadel-mkimage", args)),
             "sync" => sync::main(rebuild_args("citadel-desktop-sync", args)),
@@ -4,12 +4,14 @@ use std::fs::{self,File};
 use std::io::{self,Write};
 
 use failure::ResultExt;
-use libcitadel::{Result,ImageHeader,devkeys};
+use libcitadel::{Result,ImageHeader,ResourceImage,BlockDelta,devkeys};
 
 use super::config::BuildConfig;
 use std::path::Path;
 use libcitadel::verity::Verity;
 
+use crate::progress::ProgressBar;
+
 pub struct UpdateBuilder {
     config: BuildConfig,
     image_data: PathBuf,
@@ -18,6 +20,8 @@ pub struct UpdateBuilder {
     shasum: Option<String>,
     verity_salt: Option<String>,
     verity_root: Option<String>,
+
+    delta_base: Option<ResourceImage>,
 }
 
 
@@ -34,7 +38,7 @@ impl UpdateBuilder {
         UpdateBuilder {
             config, image_data,
             nblocks: None, shasum: None, verity_salt: None,
-            verity_root: None,
+            verity_root: None, delta_base: None,
         }
     }
 
@@ -42,6 +46,10 @@ impl UpdateBuilder {
         format!("citadel-{}-{}-{:03}.img", self.config.img_name(), self.config.channel(), self.config.version())
     }
 
+    fn delta_filename(&self, base_version: u32) -> String {
+        format!("citadel-{}-{}-{:03}-delta-{:03}.img", self.config.img_name(), self.config.channel(), self.config.version(), base_version)
+    }
+
     fn build_filename(config: &BuildConfig) -> String {
         format!("citadel-{}-{}-{:03}", config.image_type(), config.channel(), config.version())
     }
@@ -54,6 +62,9 @@ impl UpdateBuilder {
         info!("Copying source file to {}", self.image_data.display());
         fs::copy(self.config.source(), &self.image_data)?;
 
+        self.load_delta_base()
+            .context("failed to load delta base image")?;
+
         self.pad_image()
             .context("failed writing padding to image")?;
         
@@ -62,9 +73,12 @@ impl UpdateBuilder {
 
         self.calculate_shasum()?;
 
-        self.prepend_empty_block()?;
+        self.generate_delta()
+            .context("failed generating delta image")?;
 
-        self.compress_image()?;
+        self.prepend_empty_block(&self.image_data)?;
+
+        self.compress_image(&self.image_data)?;
 
         self.write_final_image()
             .context("failed to write final image file")?;
@@ -72,6 +86,26 @@ impl UpdateBuilder {
         Ok(())
     }
 
+    // Copy the base image for a delta into the work directory and generate the
+    // verity hash tree for it so that it can be compared with the new image.
+    fn load_delta_base(&mut self) -> Result<()> {
+        let base = match self.config.delta_base() {
+            Some(base) => base,
+            None => return Ok(()),
+        };
+        let path = self.config.workdir_path("delta-base.img");
+        info!("Copying delta base image {} to {}", base.display(), path.display());
+        fs::copy(base, &path)?;
+
+        let image = ResourceImage::from_path(&path)?;
+        if image.metainfo().image_type() != self.config.image_type() {
+            bail!("Delta base image has wrong image type '{}'", image.metainfo().image_type());
+        }
+        image.generate_verity_hashtree(&mut ProgressBar::new())?;
+        self.delta_base = Some(image);
+        Ok(())
+    }
+
     fn image(&self) -> &Path {
         &self.image_data
     }
@@ -110,10 +144,10 @@ impl UpdateBuilder {
         Ok(())
     }
 
-    fn prepend_empty_block(&mut self) -> Result<()> {
-        let tmpfile = self.image().with_extension("tmp");
-        cmd!("/bin/dd", "if={} of={} bs=4096 seek=1 conv=sparse", self.image().display(), tmpfile.display())?;
-        fs::rename(tmpfile, self.image())?;
+    fn prepend_empty_block(&self, path: &Path) -> Result<()> {
+        let tmpfile = path.with_extension("tmp");
+        cmd!("/bin/dd", "if={} of={} bs=4096 seek=1 conv=sparse", path.display(), tmpfile.display())?;
+        fs::rename(tmpfile, path)?;
         Ok(())
     }
 
@@ -121,7 +155,11 @@ impl UpdateBuilder {
         let hashfile = self.config.workdir_path(self.verity_filename());
         let outfile = self.config.workdir_path("verity-format.out");
 
-        let output = Verity::new(self.image()).generate_initial_hashtree(&hashfile)?;
+        // A delta is built by comparing verity hashes, so use the same salt as the base image
+        let output = match self.delta_base {
+            Some(ref base) => Verity::new(self.image()).generate_initial_hashtree_with_salt(&hashfile, base.metainfo().verity_salt(), &mut ProgressBar::new())?,
+            None => Verity::new(self.image()).generate_initial_hashtree(&hashfile, &mut ProgressBar::new())?,
+        };
 
         fs::write(outfile, output.output())
             .context("failed to write veritysetup command output to a file")?;
@@ -144,51 +182,91 @@ impl UpdateBuilder {
         Ok(())
     }
 
-    fn compress_image(&self) -> Result<()> {
+    fn generate_delta(&self) -> Result<()> {
+        let base = match self.delta_base {
+            Some(ref base) => base,
+            None => return Ok(()),
+        };
+        let base_hashes = Verity::read_leaf_hashes(base.path(), base.metainfo().nblocks())?;
+        let hashfile = self.config.workdir_path(self.verity_filename());
+        let new_hashes = Verity::read_leaf_hashes(&hashfile, self.nblocks.unwrap())?;
+
+        let delta = BlockDelta::from_hashes(&base_hashes, &new_hashes);
+        info!("Delta from version {} contains {} of {} blocks",
+              base.metainfo().version(), delta.changed_blocks(), delta.nblocks());
+
+        let delta_data = self.config.workdir_path("delta-data");
+        delta.write(self.image(), &delta_data)?;
+        self.prepend_empty_block(&delta_data)?;
+        self.compress_image(&delta_data)?;
+
+        let metainfo = self.generate_delta_metainfo(base)?;
+        let target = self.config.workdir_path(self.delta_filename(base.metainfo().version()));
+        self.write_image_file(&delta_data, &target, &metainfo)
+    }
+
+    fn compress_image(&self, path: &Path) -> Result<()> {
         if self.config.compress() {
-            info!("Compressing image data");
-            cmd!("xz", "-T0 {}", self.image().display())
-                .context(format!("failed to compress {}", self.image().display()))?;
-            // Rename back to original image_data filename
-            fs::rename(self.image().with_extension("xz"), self.image())?;
+            let compression = self.config.compression();
+            info!("Compressing image data with {}", compression);
+            compression.compress_file(path)
+                .context(format!("failed to compress {}", path.display()))?;
         }
         Ok(())
     }
 
     fn write_final_image(&self) -> Result<()> {
-        let header = self.generate_header()?;
+        let metainfo = self.generate_metainfo();
+        fs::write(self.config.workdir_path("metainfo"), &metainfo)?;
         let target = self.config.workdir_path(self.target_filename());
+        self.write_image_file(self.image(), &target, &metainfo)
+    }
 
-        let mut out = File::create(&target)
+    fn write_image_file(&self, data: &Path, target: &Path, metainfo: &[u8]) -> Result<()> {
+        let header = self.generate_header(target, metainfo)?;
+
+        let mut out = File::create(target)
             .context(format!("could not open output file {}", target.display()))?;
 
         header.write_header(&out)?;
 
-        let mut data = File::open(&self.image())
-            .context(format!("could not open image data file {}", self.image().display()))?;
+        let mut data = File::open(data)
+            .context(format!("could not open image data file {}", data.display()))?;
         io::copy(&mut data, &mut out)
             .context("error copying image data to output file")?;
         Ok(())
     }
 
-    fn generate_header(&self) -> Result<ImageHeader> {
+    fn generate_header(&self, target: &Path, metainfo: &[u8]) -> Result<ImageHeader> {
         let hdr = ImageHeader::new();
 
         if self.config.compress() {
             hdr.set_flag(ImageHeader::FLAG_DATA_COMPRESSED);
         }
 
-        let metainfo = self.generate_metainfo();
-        fs::write(self.config.workdir_path("metainfo"), &metainfo)?;
-        hdr.set_metainfo_bytes(&metainfo)?;
+        hdr.set_metainfo_bytes(metainfo)?;
 
         if self.config.channel() == "dev" {
-            let sig = devkeys().sign(&metainfo);
+            let sig = devkeys().sign(metainfo);
             hdr.set_signature(sig.to_bytes())?;
+        } else {
+            self.write_unsigned_metainfo(target, metainfo)?;
         }
         Ok(hdr)
     }
 
+    // Images for channels other than 'dev' are left unsigned and the metainfo
+    // is written to a separate file so that it can be signed on another machine
+    // with 'citadel-image sign-image --metainfo'.
+    fn write_unsigned_metainfo(&self, target: &Path, metainfo: &[u8]) -> Result<()> {
+        let path = target.with_extension("img.metainfo");
+        fs::write(&path, metainfo)
+            .context(format!("failed to write metainfo to {}", path.display()))?;
+        info!("Image for channel '{}' is not signed", self.config.channel());
+        info!("Metainfo to be signed has been written to {}", path.display());
+        Ok(())
+    }
+
     fn generate_metainfo(&self) -> Vec<u8> {
         // writes to Vec can't fail, unwrap once to avoid clutter
         self._generate_metainfo().unwrap()
@@ -211,11 +289,24 @@ impl UpdateBuilder {
         }
         writeln!(v, "channel = \"{}\"", self.config.channel())?;
         writeln!(v, "version = {}", self.config.version())?;
+        if let Some(min) = self.config.min_version() {
+            writeln!(v, "min-version = {}", min)?;
+        }
         writeln!(v, "timestamp = \"{}\"", self.config.timestamp())?;
         writeln!(v, "nblocks = {}", self.nblocks.unwrap())?;
         writeln!(v, "shasum = \"{}\"", self.shasum.as_ref().unwrap())?;
         writeln!(v, "verity-salt = \"{}\"", self.verity_salt.as_ref().unwrap())?;
         writeln!(v, "verity-root = \"{}\"", self.verity_root.as_ref().unwrap())?;
+        if self.config.compress() {
+            writeln!(v, "compression = \"{}\"", self.config.compression())?;
+        }
+        Ok(v)
+    }
+
+    fn generate_delta_metainfo(&self, base: &ResourceImage) -> Result<Vec<u8>> {
+        let mut v = self._generate_metainfo()?;
+        writeln!(v, "delta-base-version = {}", base.metainfo().version())?;
+        writeln!(v, "delta-base-root = \"{}\"", base.metainfo().verity_root())?;
         Ok(v)
     }
 }
@@ -4,7 +4,7 @@ use std::path::{Path, PathBuf};
 
 use toml;
 
-use libcitadel::Result;
+use libcitadel::{Result,Compression};
 
 #[derive(Deserialize)]
 pub struct BuildConfig {
@@ -16,6 +16,7 @@ pub struct BuildConfig {
     source: String,
     #[serde(default)]
     compress: bool,
+    compression: Option<String>,
     #[serde(rename = "kernel-version")]
     kernel_version: Option<String>,
     #[serde(rename = "kernel-id")]
@@ -24,6 +25,12 @@ pub struct BuildConfig {
     #[serde(rename = "realmfs-name")]
     realmfs_name: Option<String>,
 
+    #[serde(rename = "min-version")]
+    min_version: Option<usize>,
+
+    #[serde(rename = "delta-base")]
+    delta_base: Option<String>,
+
     #[serde(skip)]
     basedir: PathBuf,
     #[serde(skip)]
@@ -78,6 +85,22 @@ impl BuildConfig {
         if self.image_type == "kernel" && self.kernel_version.is_none() {
             bail!("Cannot build 'kernel' image without kernel-version field");
         }
+        if let Some(ref name) = self.compression {
+            Compression::from_name(name)?;
+        }
+        if let Some(min) = self.min_version {
+            if min > self.version {
+                bail!("min-version ({}) cannot be greater than version ({})", min, self.version);
+            }
+        }
+        if let Some(ref base) = self.delta_base {
+            if self.image_type != "rootfs" {
+                bail!("Delta images can only be built for 'rootfs' image type");
+            }
+            if !Path::new(base).is_file() {
+                bail!("Delta base image '{}' does not exist or is not a regular file", base);
+            }
+        }
 
         Ok(())
     }
@@ -108,6 +131,14 @@ impl BuildConfig {
         self.realmfs_name.as_ref().map(|s| s.as_str())
     }
 
+    pub fn delta_base(&self) -> Option<&Path> {
+        self.delta_base.as_ref().map(Path::new)
+    }
+
+    pub fn min_version(&self) -> Option<usize> {
+        self.min_version
+    }
+
     pub fn version(&self) -> usize {
         self.version
     }
@@ -120,7 +151,17 @@ impl BuildConfig {
         &self.image_type
     }
 
+    /// Return `true` if the image data should be compressed. Setting the
+    /// `compression` field selects an algorithm and also enables compression.
     pub fn compress(&self) -> bool {
-        self.compress
+        self.compress || self.compression.is_some()
+    }
+
+    /// Algorithm used to compress the image data, xz unless configured otherwise.
+    pub fn compression(&self) -> Compression {
+        match self.compression {
+            Some(ref name) => Compression::from_name(name).expect("compression validated when config loaded"),
+            None => Compression::Xz,
+        }
     }
 }
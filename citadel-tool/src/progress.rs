@@ -0,0 +1,65 @@
+use std::io::{self,Write};
+
+use libcitadel::Progress;
+
+const BAR_WIDTH: usize = 30;
+
+///
+/// Displays a progress bar on stderr for long running image operations.
+///
+/// Nothing is displayed if stderr is not a terminal.
+///
+pub struct ProgressBar {
+    message: String,
+    total: u64,
+    percent: Option<u64>,
+    enabled: bool,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        let enabled = unsafe { libc::isatty(libc::STDERR_FILENO) == 1 };
+        ProgressBar { message: String::new(), total: 0, percent: None, enabled }
+    }
+
+    fn draw(&self, percent: u64) {
+        let filled = (percent as usize * BAR_WIDTH) / 100;
+        let bar = format!("{}{}", "#".repeat(filled), " ".repeat(BAR_WIDTH - filled));
+        let stderr = io::stderr();
+        let mut lock = stderr.lock();
+        let _ = write!(lock, "\r{} [{}] {:3}%", self.message, bar, percent);
+        let _ = lock.flush();
+    }
+}
+
+impl Progress for ProgressBar {
+    fn begin(&mut self, message: &str, total: u64) {
+        self.end();
+        self.message = message.to_string();
+        self.total = total;
+        self.update(0);
+    }
+
+    fn update(&mut self, done: u64) {
+        let percent = (done.min(self.total) * 100)
+            .checked_div(self.total)
+            .unwrap_or(100);
+        if self.enabled && self.percent != Some(percent) {
+            self.draw(percent);
+        }
+        self.percent = Some(percent);
+    }
+
+    fn end(&mut self) {
+        if self.enabled && self.percent.is_some() {
+            eprintln!();
+        }
+        self.percent = None;
+    }
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
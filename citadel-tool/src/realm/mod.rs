@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::process::exit;
+use std::sync::Arc;
+
+use clap::{App,Arg,ArgMatches,SubCommand};
+use clap::AppSettings::*;
+
+use libcitadel::{Result,Realm,RealmManager,DeviceSpec,Logger,LogLevel};
+use libcitadel::format_error;
+
+pub fn main(args: Vec<String>) {
+
+    Logger::set_log_level(LogLevel::Info);
+
+    let app = App::new("citadel-realm")
+        .about("Citadel realm tool")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+
+        .subcommand(SubCommand::with_name("attach")
+            .about("Attach a device such as a USB storage device to a running realm")
+            .arg(Arg::with_name("realm")
+                .help("Name of realm")
+                .required(true))
+            .arg(Arg::with_name("device")
+                .help("Path to device node")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("detach")
+            .about("Detach a device which was attached to a running realm")
+            .arg(Arg::with_name("realm")
+                .help("Name of realm")
+                .required(true))
+            .arg(Arg::with_name("device")
+                .help("Path to device node")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("devices")
+            .about("List devices attached to running realms and removable devices which can be attached"));
+
+    let matches = app.get_matches_from(args);
+    let result = match matches.subcommand() {
+        ("attach", Some(m)) => attach(m),
+        ("detach", Some(m)) => detach(m),
+        ("devices", Some(_)) => devices(),
+        _ => Ok(()),
+    };
+
+    if let Err(ref e) = result {
+        eprintln!("Error: {}", format_error(e));
+        exit(1);
+    }
+}
+
+fn load_realm(arg_matches: &ArgMatches) -> Result<(Arc<RealmManager>, Realm)> {
+    let manager = RealmManager::load()?;
+    let name = arg_matches.value_of("realm").unwrap();
+    let realm = match manager.realm_by_name(name) {
+        Some(realm) => realm,
+        None => bail!("No realm named '{}' exists", name),
+    };
+    Ok((manager, realm))
+}
+
+fn attach(arg_matches: &ArgMatches) -> Result<()> {
+    let (manager, realm) = load_realm(arg_matches)?;
+    manager.attach_device(&realm, Path::new(arg_matches.value_of("device").unwrap()))
+}
+
+fn detach(arg_matches: &ArgMatches) -> Result<()> {
+    let (manager, realm) = load_realm(arg_matches)?;
+    manager.detach_device(&realm, Path::new(arg_matches.value_of("device").unwrap()))
+}
+
+fn devices() -> Result<()> {
+    let manager = RealmManager::load()?;
+    let realms = manager.active_realms(false);
+    let removable = DeviceSpec::removable_block_devices()?;
+    for device in &removable {
+        match realms.iter().find(|r| r.attached_devices().contains(device)) {
+            Some(realm) => println!("  {:<16} attached to realm-{}", device.display(), realm.name()),
+            None => println!("  {}", device.display()),
+        }
+    }
+    for realm in &realms {
+        for device in realm.attached_devices().iter().filter(|d| !removable.contains(d)) {
+            println!("  {:<16} attached to realm-{}", device.display(), realm.name());
+        }
+    }
+    Ok(())
+}
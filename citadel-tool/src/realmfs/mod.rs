@@ -1,12 +1,17 @@
 use clap::App;
 use clap::ArgMatches;
 
-use libcitadel::{Result,RealmFS,Logger,LogLevel};
+use libcitadel::{Result,RealmFS,RealmFSRecipe,RealmFSDiff,RealmFSBundle,RealmFSGarbage,RealmManager,Logger,LogLevel,util};
 use clap::SubCommand;
 use clap::AppSettings::*;
 use clap::Arg;
 use libcitadel::ResizeSize;
 use libcitadel::format_error;
+
+use crate::image::StderrLogOutput;
+use crate::progress::ProgressBar;
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
 use std::process::exit;
 
 pub fn main(args: Vec<String>) {
@@ -17,6 +22,15 @@ pub fn main(args: Vec<String>) {
         .about("Citadel realmfs image tool")
         .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder,SubcommandsNegateReqs])
 
+        .subcommand(SubCommand::with_name("info")
+            .about("Display metainfo and lineage of a RealmFS image")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("packages")
+                .long("packages")
+                .help("Also list packages installed when the image was last sealed")))
+
         .subcommand(SubCommand::with_name("resize")
             .about("Resize an existing RealmFS image. If the image is currently sealed, it will also be unsealed.")
             .arg(Arg::with_name("image")
@@ -35,6 +49,18 @@ is the final absolute size of the image.")
                 .required(true)))
 
 
+        .subcommand(SubCommand::with_name("shrink")
+            .about("Reduce the size of an unsealed RealmFS image and release unused space from the image file")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image to shrink")
+                .required(true))
+            .arg(Arg::with_name("size")
+                .help("Size to reduce RealmFS image to (default: minimum size with 1gb free space)"))
+            .arg(Arg::with_name("compact-only")
+                .long("compact-only")
+                .conflicts_with("size")
+                .help("Only release unused space from the image file without changing the size of the image")))
+
         .subcommand(SubCommand::with_name("fork")
             .about("Create a new RealmFS image as an unsealed copy of an existing image")
             .arg(Arg::with_name("image")
@@ -59,10 +85,98 @@ is the final absolute size of the image.")
 
         .subcommand(SubCommand::with_name("update")
             .about("Open an update shell on the image")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("script")
+                .long("script")
+                .takes_value(true)
+                .help("Run a script non-interactively instead of opening a shell. The update is only applied if the script succeeds."))
+            .arg(Arg::with_name("log")
+                .long("log")
+                .takes_value(true)
+                .requires("script")
+                .help("File to write script output to (default: <image>.update.log)")))
+
+        .subcommand(SubCommand::with_name("build")
+            .about("Build a new sealed RealmFS image from a recipe file")
+            .arg(Arg::with_name("recipe")
+                .help("Path to recipe file")
+                .required(true))
+            .arg(Arg::with_name("check")
+                .long("check")
+                .help("Only check whether the image built from the recipe is up to date")))
+
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("Save a copy of a RealmFS image as a named snapshot which can later be restored")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("snapshot")
+                .help("Name of snapshot to create")
+                .required(true))
+            .arg(Arg::with_name("note")
+                .long("note")
+                .takes_value(true)
+                .help("Note to record with snapshot")))
+
+        .subcommand(SubCommand::with_name("snapshots")
+            .about("List snapshots of a RealmFS image")
             .arg(Arg::with_name("image")
                 .help("Path or name of RealmFS image")
                 .required(true)))
 
+        .subcommand(SubCommand::with_name("restore")
+            .about("Replace a RealmFS image with a previously created snapshot")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("snapshot")
+                .help("Name of snapshot to restore")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("diff")
+            .about("Show files and packages which differ between two RealmFS images")
+            .arg(Arg::with_name("image")
+                .help("Path or name of first RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("other")
+                .help("Path or name of second RealmFS image")
+                .required(true))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Display differences as JSON")))
+
+        .subcommand(SubCommand::with_name("export")
+            .about("Export a RealmFS image to a bundle file which can be imported on another machine")
+            .arg(Arg::with_name("image")
+                .help("Path or name of RealmFS image to export")
+                .required(true))
+            .arg(Arg::with_name("bundle")
+                .help("Path of bundle file to create")
+                .required(true)))
+
+        .subcommand(SubCommand::with_name("import")
+            .about("Import a RealmFS image from a bundle file and seal it with the local sealing keys")
+            .arg(Arg::with_name("bundle")
+                .help("Path to bundle file")
+                .required(true))
+            .arg(Arg::with_name("name")
+                .long("name")
+                .takes_value(true)
+                .help("Name for imported image (default: name of exported image)"))
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Import image without asking for confirmation")))
+
+        .subcommand(SubCommand::with_name("gc")
+            .about("Remove backup copies, files left by interrupted updates and RealmFS images not used by any realm")
+            .arg(Arg::with_name("yes")
+                .long("yes")
+                .short("y")
+                .help("Remove files without asking for confirmation")))
+
         .subcommand(SubCommand::with_name("activate")
             .about("Activate a RealmFS by creating a block device for the image and mounting it.")
             .arg(Arg::with_name("image")
@@ -82,11 +196,21 @@ is the final absolute size of the image.")
 
     let matches = app.get_matches_from(args);
     let result = match matches.subcommand() {
+        ("info", Some(m)) => image_info(m),
         ("resize", Some(m)) => resize(m),
         ("autoresize", Some(m)) => autoresize(m),
+        ("shrink", Some(m)) => shrink(m),
         ("fork", Some(m)) => fork(m),
         ("seal", Some(m)) => seal(m),
         ("update", Some(m)) => update(m),
+        ("build", Some(m)) => build(m),
+        ("snapshot", Some(m)) => snapshot(m),
+        ("snapshots", Some(m)) => snapshots(m),
+        ("restore", Some(m)) => restore(m),
+        ("diff", Some(m)) => diff(m),
+        ("export", Some(m)) => export(m),
+        ("import", Some(m)) => import(m),
+        ("gc", Some(m)) => gc(m),
         ("activate", Some(m)) => activate(m),
         ("deactivate", Some(m)) => deactivate(m),
         _ => image_info(&matches),
@@ -104,7 +228,10 @@ fn realmfs_image(arg_matches: &ArgMatches) -> Result<RealmFS> {
         Some(s) => s,
         None => bail!("Image argument required."),
     };
+    load_realmfs(image)
+}
 
+fn load_realmfs(image: &str) -> Result<RealmFS> {
     let realmfs = if RealmFS::is_valid_name(image) {
         RealmFS::load_by_name(image)?
     } else if RealmFS::is_valid_realmfs_image(image) {
@@ -118,26 +245,25 @@ fn realmfs_image(arg_matches: &ArgMatches) -> Result<RealmFS> {
 fn image_info(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     print!("{}", String::from_utf8(img.header().metainfo_bytes())?);
-    Ok(())
-}
 
-fn parse_resize_size(s: &str) -> Result<ResizeSize> {
-    let unit = s.chars().last().filter(|c| c.is_alphabetic());
-
-    let skip = if s.starts_with('+') { 1 } else { 0 };
-    let size = s.chars()
-        .skip(skip)
-        .take_while(|c| c.is_numeric())
-        .collect::<String>()
-        .parse::<usize>()
-        .map_err(|_| format_err!("Unable to parse size value '{}'",s))?;
-
-    match unit {
-        Some('g') | Some('G') => Ok(ResizeSize::gigs(size)),
-        Some('m') | Some('M') => Ok(ResizeSize::megs(size)),
-        Some(c) => Err(format_err!("Unknown size unit '{}'", c)),
-        None => Ok(ResizeSize::blocks(size)),
+    let provenance = match img.provenance()? {
+        Some(provenance) => provenance,
+        None => return Ok(()),
+    };
+    println!();
+    println!("Lineage: {}", provenance.lineage());
+    if img.is_sealed() && !provenance.is_verified() {
+        println!("         (not covered by image signature)");
     }
+    if arg_matches.is_present("packages") {
+        println!("Packages:");
+        for (name, version) in provenance.packages() {
+            println!("  {} {}", name, version);
+        }
+    } else if !provenance.packages().is_empty() {
+        println!("Packages: {} installed", provenance.packages().len());
+    }
+    Ok(())
 }
 
 fn resize(arg_matches: &ArgMatches) -> Result<()> {
@@ -150,12 +276,12 @@ fn resize(arg_matches: &ArgMatches) -> Result<()> {
     };
     info!("Size is {}", size_arg);
     let mode_add = size_arg.starts_with('+');
-    let size = parse_resize_size(size_arg)?;
+    let size = ResizeSize::parse(size_arg.trim_start_matches('+'))?;
 
     if mode_add {
-        img.resize_grow_by(size)
+        img.resize_grow_by(size, &mut ProgressBar::new())
     } else {
-        img.resize_grow_to(size)
+        img.resize_grow_to(size, &mut ProgressBar::new())
     }
 }
 
@@ -163,13 +289,45 @@ fn autoresize(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
 
     if let Some(size) = img.auto_resize_size() {
-        img.resize_grow_to(size)
+        img.resize_grow_to(size, &mut ProgressBar::new())
     } else {
         info!("RealmFS image {} has sufficient free space, doing nothing", img.path().display());
         Ok(())
     }
 }
 
+fn shrink(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let size_before = img.metainfo_nblocks();
+    let allocated_before = img.allocated_size_blocks()?;
+
+    if arg_matches.is_present("compact-only") {
+        img.compact()?;
+    } else {
+        let size = match arg_matches.value_of("size") {
+            Some(size) => Some(ResizeSize::parse(size)?),
+            None => None,
+        };
+        img.shrink(size, &mut ProgressBar::new())?;
+    }
+
+    let size_after = img.metainfo_nblocks();
+    let allocated_after = img.allocated_size_blocks()?;
+    info!("Image size: {} -> {}", format_blocks(size_before), format_blocks(size_after));
+    info!("Disk usage: {} -> {} ({} reclaimed)", format_blocks(allocated_before), format_blocks(allocated_after),
+          format_blocks(allocated_before.saturating_sub(allocated_after)));
+    Ok(())
+}
+
+fn format_blocks(nblocks: usize) -> String {
+    let megs = nblocks as f64 / 256.0;
+    if megs < 1024.0 {
+        format!("{:.1} MiB", megs)
+    } else {
+        format!("{:.2} GiB", megs / 1024.0)
+    }
+}
+
 fn fork(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     let forkname = match arg_matches.value_of("forkname") {
@@ -182,7 +340,7 @@ fn fork(arg_matches: &ArgMatches) -> Result<()> {
     if RealmFS::named_image_exists(forkname) {
         bail!("A RealmFS image named '{}' already exists", forkname);
     }
-    img.fork(forkname)?;
+    img.fork(forkname, &mut ProgressBar::new())?;
     Ok(())
 }
 
@@ -204,6 +362,14 @@ fn seal(arg_matches: &ArgMatches) -> Result<()> {
 fn update(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     let mut update = img.update();
+    if let Some(script) = arg_matches.value_of("script") {
+        let log = arg_matches.value_of("log")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| update.default_log_path());
+        update.run_script(Path::new(script), &log, &mut ProgressBar::new())?;
+        info!("Update of RealmFS image {} completed", img.name());
+        return Ok(());
+    }
     update.setup()?;
     update.open_update_shell()?;
     update.apply_update()?;
@@ -211,6 +377,181 @@ fn update(arg_matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+fn build(arg_matches: &ArgMatches) -> Result<()> {
+    let recipe = RealmFSRecipe::load(arg_matches.value_of("recipe").unwrap())?;
+    let manager = RealmManager::load()?;
+    let existing = manager.realmfs_by_name(recipe.name());
+
+    if arg_matches.is_present("check") {
+        return match existing {
+            Some(ref img) if recipe.is_current(img) => {
+                info!("RealmFS image {} is up to date with recipe", recipe.name());
+                Ok(())
+            },
+            Some(_) => bail!("RealmFS image {} is out of date with recipe", recipe.name()),
+            None => bail!("RealmFS image {} has not been built", recipe.name()),
+        };
+    }
+
+    match existing {
+        Some(ref img) if recipe.is_current(img) => {
+            info!("RealmFS image {} is already up to date with recipe", recipe.name());
+            Ok(())
+        },
+        Some(_) => bail!("RealmFS image {} already exists and is out of date with recipe. Remove it to rebuild.", recipe.name()),
+        None => {
+            recipe.build(&manager, &mut ProgressBar::new())?;
+            info!("Built RealmFS image {} from recipe (hash: {})", recipe.name(), recipe.hash());
+            Ok(())
+        },
+    }
+}
+
+fn snapshot(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let name = arg_matches.value_of("snapshot").unwrap();
+    img.snapshot(name, arg_matches.value_of("note"), &mut ProgressBar::new())?;
+    info!("Created snapshot '{}' of RealmFS image {}", name, img.name());
+    Ok(())
+}
+
+fn snapshots(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let snapshots = img.snapshots()?;
+    if snapshots.is_empty() {
+        println!("RealmFS image {} has no snapshots", img.name());
+        return Ok(());
+    }
+    let width = snapshots.iter().map(|s| s.name().len()).max().unwrap_or(0).max(4);
+    println!("{:<width$}  {:<19}  VERSION  SEALED  NOTE", "NAME", "CREATED (UTC)", width = width);
+    for s in snapshots {
+        let line = format!("{:<width$}  {}  {:<7}  {:<6}  {}", s.name(), util::format_timestamp(s.timestamp()),
+                           s.version(), if s.is_sealed() { "yes" } else { "no" }, s.note().unwrap_or(""), width = width);
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+fn restore(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let name = arg_matches.value_of("snapshot").unwrap();
+    img.restore_snapshot(name, &mut ProgressBar::new())?;
+    info!("Restored RealmFS image {} from snapshot '{}'", img.name(), name);
+    Ok(())
+}
+
+fn diff(arg_matches: &ArgMatches) -> Result<()> {
+    let json = arg_matches.is_present("json");
+    if json {
+        Logger::set_log_output(Box::new(StderrLogOutput));
+    }
+    let manager = RealmManager::load()?;
+    let mut a = load_realmfs(arg_matches.value_of("image").unwrap())?;
+    let mut b = load_realmfs(arg_matches.value_of("other").unwrap())?;
+    a.set_manager(manager.clone());
+    b.set_manager(manager);
+
+    let diff = RealmFSDiff::compare(&a, &b)?;
+    if json {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        serde_json::to_writer_pretty(&mut out, &diff)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    if diff.is_empty() {
+        println!("No differences found");
+        return Ok(());
+    }
+    if !diff.packages().is_empty() {
+        println!("Packages:");
+        for p in diff.packages() {
+            match (p.old_version(), p.new_version()) {
+                (Some(old), Some(new)) => println!("  ~ {} {} -> {}", p.name(), old, new),
+                (Some(old), None) => println!("  - {} {}", p.name(), old),
+                (None, Some(new)) => println!("  + {} {}", p.name(), new),
+                (None, None) => {},
+            }
+        }
+        println!();
+    }
+    println!("Files:");
+    let mut files = diff.added().iter().map(|f| (f, 'A'))
+        .chain(diff.removed().iter().map(|f| (f, 'D')))
+        .chain(diff.modified().iter().map(|f| (f, 'M')))
+        .collect::<Vec<_>>();
+    files.sort();
+    for (file, status) in files {
+        println!("  {} {}", status, file);
+    }
+    println!();
+    println!("{} added, {} removed, {} modified files, {} package changes",
+             diff.added().len(), diff.removed().len(), diff.modified().len(), diff.packages().len());
+    Ok(())
+}
+
+fn export(arg_matches: &ArgMatches) -> Result<()> {
+    let img = realmfs_image(arg_matches)?;
+    let bundle = Path::new(arg_matches.value_of("bundle").unwrap());
+    RealmFSBundle::export(&img, bundle, &mut ProgressBar::new())?;
+    info!("Exported RealmFS image {} to {}", img.name(), bundle.display());
+    Ok(())
+}
+
+fn import(arg_matches: &ArgMatches) -> Result<()> {
+    let bundle = Path::new(arg_matches.value_of("bundle").unwrap());
+    let info = RealmFSBundle::read_info(bundle)?;
+    println!("RealmFS image '{}' version {} ({})", info.name(), info.version(), if info.is_sealed() { "sealed" } else { "unsealed" });
+    println!("  exported from: {} at {}", info.exported_from(), util::format_timestamp(info.exported_at()));
+    println!("  verity root:   {}", info.verity_root().unwrap_or("none"));
+    println!();
+    println!("The bundle does not prove where this image came from. Importing it will seal it with the local sealing keys.");
+
+    if !arg_matches.is_present("yes") {
+        print!("Import this image? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Nothing imported");
+            return Ok(());
+        }
+    }
+    let manager = RealmManager::load()?;
+    let img = RealmFSBundle::import(bundle, arg_matches.value_of("name"), &manager, &mut ProgressBar::new())?;
+    info!("Imported RealmFS image {} from {}", img.name(), bundle.display());
+    Ok(())
+}
+
+fn gc(arg_matches: &ArgMatches) -> Result<()> {
+    let manager = RealmManager::load()?;
+    let garbage = RealmFSGarbage::scan(&manager)?;
+    if garbage.is_empty() {
+        println!("Nothing to remove");
+        return Ok(());
+    }
+    for item in garbage.items() {
+        println!("  {:>10}  {:<18}  {}", format_blocks((item.size() / 4096) as usize), item.kind(), item.path().display());
+    }
+    println!();
+    println!("{} files, {} reclaimable", garbage.items().len(), format_blocks((garbage.reclaimable_size() / 4096) as usize));
+
+    if !arg_matches.is_present("yes") {
+        print!("Remove these files? [y/N] ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Nothing removed");
+            return Ok(());
+        }
+    }
+    let reclaimed = garbage.remove_all(&manager);
+    info!("Reclaimed {}", format_blocks((reclaimed / 4096) as usize));
+    Ok(())
+}
+
 fn activate(arg_matches: &ArgMatches) -> Result<()> {
     let img = realmfs_image(arg_matches)?;
     let img_arg = arg_matches.value_of("image").unwrap();
@@ -60,6 +60,12 @@ impl CommandLine {
         Self::var_exists("citadel.recovery")
     }
 
+    /// Return `true` if variable citadel.allow-rollback is present on kernel command line.
+    /// This is only honored in recovery mode. See `VersionFloor`.
+    pub fn allow_rollback() -> bool {
+        Self::var_exists("citadel.allow-rollback")
+    }
+
     pub fn overlay() -> bool { Self::var_exists("citadel.overlay") }
 
     /// Return `true` if sealed realmfs images are enabled on kernel command line
@@ -95,6 +101,19 @@ impl CommandLine {
         None
     }
 
+    /// Return the number of trusted channel keys which must have signed an image,
+    /// if variable citadel.sigthreshold is present on kernel command line.
+    pub fn signature_threshold() -> Option<usize> {
+        Self::get_value("citadel.sigthreshold")
+            .and_then(|s| s.parse::<usize>().ok())
+    }
+
+    /// Return comma separated list of systemd units from variable citadel.bless-targets
+    /// if present on kernel command line.
+    pub fn bless_targets() -> Option<&'static str> {
+        Self::get_value("citadel.bless-targets")
+    }
+
     pub fn verbose() -> bool {
         Self::var_exists("citadel.verbose")
     }
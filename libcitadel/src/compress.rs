@@ -0,0 +1,172 @@
+use std::fmt;
+use std::fs::{self,File};
+use std::io::{self,Read,Write,BufReader,BufWriter};
+use std::path::Path;
+
+use xz2::read::XzDecoder;
+
+use crate::{Result,util};
+
+/// zstd compression level used when building images
+const ZSTD_LEVEL: i32 = 19;
+
+/// compression levels used by `compress_to()` which are faster for large inputs
+const ZSTD_STREAM_LEVEL: i32 = 6;
+const XZ_STREAM_LEVEL: u32 = 6;
+
+///
+/// The algorithm used to compress the data of a resource image.
+///
+/// The algorithm is recorded in the `compression` field of the image
+/// metainfo. Images which have `FLAG_DATA_COMPRESSED` set but which do not
+/// have this field were built before it was introduced and are always
+/// compressed with xz.
+///
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Compression {
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+
+    /// Parse the name of a compression algorithm as stored in metainfo.
+    pub fn from_name(name: &str) -> Result<Compression> {
+        match name {
+            "xz" => Ok(Compression::Xz),
+            "zstd" => Ok(Compression::Zstd),
+            _ => bail!("Unknown compression algorithm '{}'", name),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Xz => "xz",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Wrap `reader` in a streaming decoder for this algorithm.
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        let decoder: Box<dyn Read + 'a> = match self {
+            Compression::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        };
+        Ok(decoder)
+    }
+
+    /// Decompress all data read from `reader` and write it to the file `target`.
+    pub fn decompress_to<R: Read, P: AsRef<Path>>(self, reader: R, target: P) -> Result<u64> {
+        let target = target.as_ref();
+        let mut decoder = self.decoder(BufReader::new(reader))?;
+        let mut out = BufWriter::new(File::create(target)?);
+        let n = io::copy(&mut decoder, &mut out)
+            .map_err(|e| format_err!("failed to decompress {} data to {}: {}", self, target.display(), e))?;
+        out.flush()?;
+        Ok(n)
+    }
+
+    /// Compress all data read from `reader` and write it to the file `target`.
+    ///
+    /// A faster compression level is used than when compressing images with
+    /// `compress_file()` since this is used for very large inputs such as RealmFS images.
+    pub fn compress_to<R: Read, P: AsRef<Path>>(self, mut reader: R, target: P) -> Result<u64> {
+        let target = target.as_ref();
+        let out = BufWriter::new(File::create(target)?);
+        let n = match self {
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(out, XZ_STREAM_LEVEL);
+                let n = io::copy(&mut reader, &mut encoder)
+                    .and_then(|n| encoder.finish()?.flush().map(|_| n));
+                n.map_err(|e| format_err!("failed to compress {} data to {}: {}", self, target.display(), e))?
+            },
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(out, ZSTD_STREAM_LEVEL)?;
+                let n = io::copy(&mut reader, &mut encoder)
+                    .and_then(|n| encoder.finish()?.flush().map(|_| n));
+                n.map_err(|e| format_err!("failed to compress {} data to {}: {}", self, target.display(), e))?
+            },
+        };
+        Ok(n)
+    }
+
+    /// Compress the file at `path` in place.
+    pub fn compress_file<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        match self {
+            Compression::Xz => {
+                util::xz_compress(path)?;
+                fs::rename(path.with_extension("xz"), path)?;
+            },
+            Compression::Zstd => {
+                let tmp = path.with_extension("zst");
+                let input = File::open(path)?;
+                let output = BufWriter::new(File::create(&tmp)?);
+                zstd::stream::copy_encode(input, output, ZSTD_LEVEL)
+                    .map_err(|e| format_err!("failed to compress {}: {}", path.display(), e))?;
+                fs::rename(&tmp, path)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use crate::util::TestDir;
+
+    fn roundtrip(compression: Compression) {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let compressed = match compression {
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(&data).unwrap();
+                encoder.finish().unwrap()
+            },
+            Compression::Zstd => zstd::stream::encode_all(Cursor::new(&data), 3).unwrap(),
+        };
+        let mut output = Vec::new();
+        compression.decoder(Cursor::new(compressed)).unwrap()
+            .read_to_end(&mut output).unwrap();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn decode_xz() {
+        roundtrip(Compression::Xz);
+    }
+
+    #[test]
+    fn decode_zstd() {
+        roundtrip(Compression::Zstd);
+    }
+
+    #[test]
+    fn compress_to_file() {
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let dir = TestDir::new("compress-test");
+        for &compression in &[Compression::Xz, Compression::Zstd] {
+            let path = dir.path().join(format!("data.{}", compression));
+            assert_eq!(compression.compress_to(Cursor::new(&data), &path).unwrap(), data.len() as u64);
+            let mut output = Vec::new();
+            compression.decoder(File::open(&path).unwrap()).unwrap()
+                .read_to_end(&mut output).unwrap();
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn parse_name() {
+        assert_eq!(Compression::from_name("zstd").unwrap(), Compression::Zstd);
+        assert_eq!(Compression::from_name(Compression::Xz.name()).unwrap(), Compression::Xz);
+        assert!(Compression::from_name("gzip").is_err());
+    }
+}
@@ -84,6 +84,14 @@ impl OsRelease {
         OsRelease::get_value("CITADEL_IMAGE_PUBKEY")
     }
 
+    pub fn citadel_image_signature_threshold() -> Option<usize> {
+        OsRelease::get_int_value("CITADEL_IMAGE_SIGNATURE_THRESHOLD")
+    }
+
+    pub fn citadel_bless_targets() -> Option<&'static str> {
+        OsRelease::get_value("CITADEL_BLESS_TARGETS")
+    }
+
     pub fn citadel_rootfs_version() -> Option<usize> {
         OsRelease::get_int_value("CITADEL_ROOTFS_VERSION")
     }
@@ -0,0 +1,162 @@
+use std::fs::{File,OpenOptions};
+use std::io::{self,Read,Write,Seek,SeekFrom,BufReader,BufWriter};
+use std::path::Path;
+
+use byteorder::{BigEndian,ReadBytesExt,WriteBytesExt};
+
+use crate::{Result,BLOCK_SIZE};
+
+/// Expected magic value at the start of delta data
+const DELTA_MAGIC: &[u8] = b"SGDELTA1";
+
+/// Size of a sha256 verity hash
+const HASH_SIZE: usize = 32;
+
+///
+/// A block level delta between two versions of an image.
+///
+/// The delta stores only the 4096 byte blocks of the new image which differ
+/// from the block at the same position in the base image. Changed blocks are
+/// found by comparing the lowest level of the dm-verity hash trees of the two
+/// images, which requires that both hash trees were generated with the same
+/// salt.
+///
+/// The layout of the delta data is the following:
+///
+///    magic     8 bytes     'SGDELTA1'
+///    nblocks   8 bytes     Number of blocks in the new image
+///
+/// followed by a list of records:
+///
+///    start     8 bytes     Index of first block in this run
+///    count     4 bytes     Number of blocks in this run
+///    data      count * 4096 bytes
+///
+/// The list is terminated by a record with a count of 0. All integer values
+/// are stored in Big Endian byte order.
+///
+pub struct BlockDelta {
+    nblocks: usize,
+    runs: Vec<(usize,usize)>,
+}
+
+impl BlockDelta {
+
+    /// Compare the verity hashes of the data blocks of two images and
+    /// record every run of blocks in the new image which has changed.
+    /// Blocks in the new image past the end of the base image are always
+    /// included.
+    pub fn from_hashes(base_hashes: &[u8], new_hashes: &[u8]) -> BlockDelta {
+        let nblocks = new_hashes.len() / HASH_SIZE;
+        let base_nblocks = base_hashes.len() / HASH_SIZE;
+
+        let changed = |idx: usize| {
+            if idx >= base_nblocks {
+                return true;
+            }
+            let range = idx * HASH_SIZE..(idx + 1) * HASH_SIZE;
+            base_hashes[range.clone()] != new_hashes[range]
+        };
+
+        let mut runs = Vec::new();
+        let mut current: Option<(usize,usize)> = None;
+        for idx in 0..nblocks {
+            if changed(idx) {
+                current = match current {
+                    Some((start, count)) => Some((start, count + 1)),
+                    None => Some((idx, 1)),
+                };
+            } else if let Some(run) = current.take() {
+                runs.push(run);
+            }
+        }
+        runs.extend(current);
+        BlockDelta { nblocks, runs }
+    }
+
+    /// Number of blocks in the new image
+    pub fn nblocks(&self) -> usize {
+        self.nblocks
+    }
+
+    /// Number of blocks stored in the delta
+    pub fn changed_blocks(&self) -> usize {
+        self.runs.iter().map(|(_,count)| count).sum()
+    }
+
+    /// Write the delta to `output` reading the changed blocks from the
+    /// new image data in the file `image`.
+    pub fn write<P: AsRef<Path>, Q: AsRef<Path>>(&self, image: P, output: Q) -> Result<()> {
+        let mut image = File::open(image.as_ref())?;
+        let mut out = BufWriter::new(File::create(output.as_ref())?);
+        out.write_all(DELTA_MAGIC)?;
+        out.write_u64::<BigEndian>(self.nblocks as u64)?;
+
+        let mut buffer = vec![0u8; BLOCK_SIZE];
+        for &(start, count) in &self.runs {
+            out.write_u64::<BigEndian>(start as u64)?;
+            out.write_u32::<BigEndian>(count as u32)?;
+            image.seek(SeekFrom::Start((start * BLOCK_SIZE) as u64))?;
+            for _ in 0..count {
+                image.read_exact(&mut buffer)?;
+                out.write_all(&buffer)?;
+            }
+        }
+        out.write_u64::<BigEndian>(0)?;
+        out.write_u32::<BigEndian>(0)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Read delta data from `reader` and write every stored block to the
+    /// file or block device `target`. Blocks which are not stored in the
+    /// delta are expected to already be present in `target`. Returns the
+    /// number of blocks in the new image.
+    pub fn apply<R: Read, P: AsRef<Path>>(reader: R, target: P) -> Result<usize> {
+        let mut reader = BufReader::new(reader);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != DELTA_MAGIC {
+            bail!("Delta data does not have expected magic value");
+        }
+        let nblocks = reader.read_u64::<BigEndian>()? as usize;
+
+        let mut out = OpenOptions::new().write(true).open(target.as_ref())?;
+        loop {
+            let start = reader.read_u64::<BigEndian>()? as usize;
+            let count = reader.read_u32::<BigEndian>()? as usize;
+            if count == 0 {
+                break;
+            }
+            if start + count > nblocks {
+                bail!("Delta record for blocks {}-{} is past end of image ({} blocks)", start, start + count, nblocks);
+            }
+            out.seek(SeekFrom::Start((start * BLOCK_SIZE) as u64))?;
+            let copied = io::copy(&mut (&mut reader).take((count * BLOCK_SIZE) as u64), &mut out)?;
+            if copied as usize != count * BLOCK_SIZE {
+                bail!("Delta data truncated in record for block {}", start);
+            }
+        }
+        out.sync_all()?;
+        Ok(nblocks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hashes(blocks: &[u8]) -> Vec<u8> {
+        blocks.iter().flat_map(|b| vec![*b; HASH_SIZE]).collect()
+    }
+
+    #[test]
+    fn changed_runs() {
+        let base = hashes(&[1, 2, 3, 4, 5]);
+        let new = hashes(&[1, 9, 9, 4, 5, 6, 7]);
+        let delta = BlockDelta::from_hashes(&base, &new);
+        assert_eq!(delta.nblocks(), 7);
+        assert_eq!(delta.runs, vec![(1, 2), (5, 2)]);
+        assert_eq!(delta.changed_blocks(), 4);
+    }
+}
@@ -2,7 +2,9 @@ use std::env;
 use std::fs::File;
 use std::io::{self,Seek,Read,BufReader,BufRead,SeekFrom};
 use std::path::{Path,PathBuf};
-use std::process::{Command,ExitStatus,Stdio};
+use std::process::{Command,ExitStatus,Stdio,Child};
+use std::thread;
+use std::time::Duration;
 
 use crate::Result;
 
@@ -77,6 +79,78 @@ impl Exec {
         Ok(String::from_utf8(result.stdout).unwrap().trim().to_owned())
     }
 
+    ///
+    /// Execute a command and call `poll` every `interval` until the command exits. This allows
+    /// the caller to report progress of a long running command, for example by checking the
+    /// size of an output file.
+    ///
+    pub fn run_polling<F: FnMut()>(&mut self, args: impl AsRef<str>, interval: Duration, mut poll: F) -> Result<()> {
+        self.ensure_command_exists()?;
+        verbose!("cmd {} {}", self.cmd_name, args.as_ref());
+        self.add_args(args);
+        let mut child = self.cmd
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stderr = Self::read_stderr(&mut child);
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            poll();
+            thread::sleep(interval);
+        };
+        self.finish_child(stderr, status)
+    }
+
+    ///
+    /// Execute a command and pass everything the command writes to stdout to the
+    /// closure `f` as it is produced.
+    ///
+    pub fn run_with_stdout<F: FnMut(&[u8])>(&mut self, args: impl AsRef<str>, mut f: F) -> Result<()> {
+        self.ensure_command_exists()?;
+        verbose!("cmd {} {}", self.cmd_name, args.as_ref());
+        self.add_args(args);
+        let mut child = self.cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stderr = Self::read_stderr(&mut child);
+
+        if let Some(mut stdout) = child.stdout.take() {
+            let mut buffer = [0u8; 1024];
+            loop {
+                let n = stdout.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                f(&buffer[..n]);
+            }
+        }
+        let status = child.wait()?;
+        self.finish_child(stderr, status)
+    }
+
+    // Collect stderr of `child` on a separate thread so that a child which writes a
+    // lot of output to stderr does not block while stdout is being read.
+    fn read_stderr(child: &mut Child) -> Option<thread::JoinHandle<Vec<String>>> {
+        child.stderr.take().map(|stderr| thread::spawn(move || {
+            BufReader::new(stderr).lines()
+                .map_while(|line| line.ok())
+                .collect()
+        }))
+    }
+
+    fn finish_child(&self, stderr: Option<thread::JoinHandle<Vec<String>>>, status: ExitStatus) -> Result<()> {
+        if let Some(handle) = stderr {
+            for line in handle.join().unwrap_or_default() {
+                verbose!("  {}", line);
+            }
+        }
+        self.check_cmd_status(status)
+    }
+
     ///
     /// Execute a command, pipe the contents of a file to stdin, return the output as a `String`
     ///
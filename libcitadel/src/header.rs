@@ -5,7 +5,7 @@ use std::path::Path;
 use toml;
 
 use crate::blockdev::AlignedBuffer;
-use crate::{BlockDev,Result,public_key_for_channel,PublicKey};
+use crate::{BlockDev,Result,public_keys_for_channel,PublicKey,KeyPair,ChannelKeys};
 use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::sync::atomic::{Ordering,AtomicIsize};
 use std::os::unix::fs::MetadataExt;
@@ -19,6 +19,12 @@ const METAINFO_OFFSET: usize = 8;
 /// Signature is 64 bytes long
 const SIGNATURE_LENGTH: usize = 64;
 
+/// Public key is 32 bytes long
+const PUBLIC_KEY_LENGTH: usize = 32;
+
+/// A signature block is a public key followed by a signature
+const SIGNATURE_BLOCK_LENGTH: usize = PUBLIC_KEY_LENGTH + SIGNATURE_LENGTH;
+
 /// Maximum amount of space in block for metainfo document
 const MAX_METAINFO_LEN: usize = (ImageHeader::HEADER_SIZE - (METAINFO_OFFSET + SIGNATURE_LENGTH));
 
@@ -45,11 +51,17 @@ fn is_valid_status_code(code: u8) -> bool {
 ///
 ///    signature    64              8 + length
 ///
+///    nsigs        1               72 + length
+///
+///    sigblocks  96 * nsigs        73 + length
+///
 /// magic     : Must match ascii bytes 'SGOS' for the header to be considered valid
 ///
 /// status    : One of the `STATUS` constants defined below
 ///
-/// flags     : May contain 'FLAG' values defined below.
+/// flags     : May contain 'FLAG' values defined below. The upper 4 bits
+///             hold the number of boot attempts remaining for a rootfs
+///             partition (see `boot_tries()`).
 ///
 /// length    : The size of the metainfo field in bytes as a 16-bit Big Endian value
 ///
@@ -57,6 +69,14 @@ fn is_valid_status_code(code: u8) -> bool {
 ///
 /// signature : ed25519 signature over the bytes of the metainfo field
 ///
+/// nsigs     : The number of additional signature blocks which follow
+///
+/// sigblocks : A list of additional signatures over the metainfo field. Each
+///             block is a 32 byte ed25519 public key followed by a 64 byte
+///             signature produced by that key. Images which must be signed by
+///             several keys, or by both the old and new key while the key for a
+///             channel is being changed, store the extra signatures here.
+///
 
 pub struct ImageHeader {
     buffer: RwLock<HeaderBytes>,
@@ -141,11 +161,16 @@ impl ImageHeader {
     pub const FLAG_HASH_TREE: u8 = 0x02; // dm-verity hash tree data is appended to the image
     pub const FLAG_DATA_COMPRESSED: u8 = 0x04; // The image data is compressed and needs to be uncompressed before use.
 
+    const BOOT_TRIES_MASK: u8 = 0xF0; // Upper 4 bits of flags byte store boot attempts remaining
+    const BOOT_TRIES_SHIFT: u8 = 4;
+    pub const MAX_BOOT_TRIES: u8 = 15;
+    pub const DEFAULT_BOOT_TRIES: u8 = 3;
+
     pub const STATUS_INVALID: u8 = 0; // Set on partition before writing a new rootfs disk image
     pub const STATUS_NEW: u8 = 1; // Set on partition after write of new rootfs disk image completes successfully
     pub const STATUS_TRY_BOOT: u8 = 2; // Set on boot selected partition if in `STATUS_NEW` state.
     pub const STATUS_GOOD: u8 = 3; // Set on boot when a `STATUS_TRY_BOOT` partition successfully launches desktop
-    pub const STATUS_FAILED: u8 = 4; // Set on boot for any partition in state `STATUS_TRY_BOOT`
+    pub const STATUS_FAILED: u8 = 4; // Set on boot for any partition in state `STATUS_TRY_BOOT` with no boot tries remaining
     pub const STATUS_BAD_SIG: u8 = 5; // Set on boot selected partition when signature fails to verify
     pub const STATUS_BAD_META: u8 = 6; // Set on partition when metainfo cannot be parsed
 
@@ -329,6 +354,18 @@ impl ImageHeader {
         old == new
     }
 
+    /// Return the number of boot attempts remaining before a partition in
+    /// `STATUS_TRY_BOOT` state is marked as `STATUS_FAILED`.
+    pub fn boot_tries(&self) -> u8 {
+        (self.flags() & Self::BOOT_TRIES_MASK) >> Self::BOOT_TRIES_SHIFT
+    }
+
+    pub fn set_boot_tries(&self, tries: u8) {
+        let tries = tries.min(Self::MAX_BOOT_TRIES);
+        let flags = self.flags() & !Self::BOOT_TRIES_MASK;
+        self.write_u8(5, flags | (tries << Self::BOOT_TRIES_SHIFT));
+    }
+
     pub fn metainfo_len(&self) -> usize {
         self.read_u16(6) as usize
     }
@@ -377,14 +414,124 @@ impl ImageHeader {
         self.set_signature(&zeros)
     }
 
-    pub fn public_key(&self) -> Result<Option<PublicKey>> {
-        public_key_for_channel(self.metainfo().channel())
+    fn signature_blocks_offset(&self) -> usize {
+        METAINFO_OFFSET + self.metainfo_len() + SIGNATURE_LENGTH
+    }
+
+    fn max_signature_blocks(&self) -> usize {
+        Self::HEADER_SIZE.saturating_sub(self.signature_blocks_offset() + 1) / SIGNATURE_BLOCK_LENGTH
+    }
+
+    /// Return the number of additional signature blocks stored in the header.
+    pub fn signature_block_count(&self) -> usize {
+        if self.max_signature_blocks() == 0 {
+            return 0;
+        }
+        let count = self.read_u8(self.signature_blocks_offset()) as usize;
+        count.min(self.max_signature_blocks())
+    }
+
+    /// Return the list of additional signature blocks as pairs of public key and signature.
+    pub fn signature_blocks(&self) -> Vec<(PublicKey, Vec<u8>)> {
+        let base = self.signature_blocks_offset() + 1;
+        (0..self.signature_block_count())
+            .flat_map(|idx| {
+                let offset = base + (idx * SIGNATURE_BLOCK_LENGTH);
+                let pubkey = PublicKey::from_bytes(&self.read_bytes(offset, PUBLIC_KEY_LENGTH)).ok()?;
+                let signature = self.read_bytes(offset + PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH);
+                Some((pubkey, signature))
+            })
+            .collect()
+    }
+
+    /// Append a signature block containing `pubkey` and `signature`. If a block for
+    /// `pubkey` already exists, the signature in that block is replaced.
+    pub fn add_signature_block(&self, pubkey: &PublicKey, signature: &[u8]) -> Result<()> {
+        if signature.len() != SIGNATURE_LENGTH {
+            bail!("Signature has invalid length: {}", signature.len());
+        }
+        let count = self.signature_block_count();
+        let idx = self.signature_blocks()
+            .iter()
+            .position(|(k,_)| k == pubkey)
+            .unwrap_or(count);
+
+        if idx >= self.max_signature_blocks() {
+            bail!("No space remaining in header for another signature block");
+        }
+
+        let count_offset = self.signature_blocks_offset();
+        let offset = count_offset + 1 + (idx * SIGNATURE_BLOCK_LENGTH);
+        self.write_bytes(offset, pubkey.to_bytes());
+        self.write_bytes(offset + PUBLIC_KEY_LENGTH, signature);
+        if idx == count {
+            self.write_u8(count_offset, (count + 1) as u8);
+        }
+        Ok(())
+    }
+
+    /// Sign the metainfo with `keys` and add the result to the header.
+    pub fn add_signature(&self, keys: &KeyPair) -> Result<()> {
+        let sig = keys.sign(&self.metainfo_bytes());
+        self.insert_signature(&keys.public_key(), sig.to_bytes())
+    }
+
+    /// Add a signature over the metainfo produced by `pubkey` to the header. If the
+    /// header is not signed yet the signature is stored as the primary signature,
+    /// otherwise it is stored in a signature block.
+    pub fn insert_signature(&self, pubkey: &PublicKey, signature: &[u8]) -> Result<()> {
+        if signature.len() != SIGNATURE_LENGTH {
+            bail!("Signature has invalid length: {}", signature.len());
+        }
+        if !pubkey.verify(&self.metainfo_bytes(), signature) {
+            bail!("Signature is not a valid signature over the metainfo of this header");
+        }
+        if !self.has_signature() {
+            self.set_signature(signature)
+        } else if pubkey.verify(&self.metainfo_bytes(), &self.signature()) {
+            // Primary signature was already produced by this key
+            Ok(())
+        } else {
+            self.add_signature_block(pubkey, signature)
+        }
+    }
+
+    pub fn clear_signature_blocks(&self) {
+        let offset = self.signature_blocks_offset();
+        if offset < Self::HEADER_SIZE {
+            let zeros = vec![0u8; Self::HEADER_SIZE - offset];
+            self.write_bytes(offset, &zeros);
+        }
+    }
+
+    /// Return the set of keys trusted to sign images for the channel named in the metainfo.
+    pub fn public_keys(&self) -> Result<Option<ChannelKeys>> {
+        public_keys_for_channel(self.metainfo().channel())
     }
 
     pub fn verify_signature(&self, pubkey: PublicKey) -> bool {
         pubkey.verify(&self.metainfo_bytes(), &self.signature())
     }
 
+    /// Return the number of distinct keys in `keys` which have produced a valid signature
+    /// over the metainfo, either as the primary signature or in a signature block.
+    pub fn valid_signature_count(&self, keys: &ChannelKeys) -> usize {
+        let mut signatures = Vec::new();
+        if self.has_signature() {
+            signatures.push((None, self.signature()));
+        }
+        for (pubkey, sig) in self.signature_blocks() {
+            signatures.push((Some(pubkey), sig));
+        }
+        keys.count_valid(&self.metainfo_bytes(), &signatures)
+    }
+
+    /// Return `true` if at least `keys.threshold()` distinct trusted keys have
+    /// signed the metainfo.
+    pub fn verify_signatures(&self, keys: &ChannelKeys) -> bool {
+        self.valid_signature_count(keys) >= keys.threshold()
+    }
+
     pub fn write_header<W: Write>(&self, mut writer: W) -> Result<()> {
         self.with_bytes(|bs| writer.write_all(&bs.0))?;
         Ok(())
@@ -461,6 +608,23 @@ pub struct MetaInfo {
 
     #[serde(default, rename = "verity-root")]
     verity_root: String,
+
+    #[serde(rename = "min-version")]
+    min_version: Option<u32>,
+
+    #[serde(rename = "delta-base-version")]
+    delta_base_version: Option<u32>,
+
+    #[serde(rename = "delta-base-root")]
+    delta_base_root: Option<String>,
+
+    compression: Option<String>,
+
+    #[serde(rename = "recipe-hash")]
+    recipe_hash: Option<String>,
+
+    #[serde(rename = "provenance-sha256")]
+    provenance_sha256: Option<String>,
 }
 
 impl MetaInfo {
@@ -527,5 +691,118 @@ impl MetaInfo {
     pub fn verity_tag(&self) -> String {
         self.verity_root().chars().take(8).collect()
     }
+
+    /// The lowest version of this image type and channel which may be used after this image
+    /// has been installed. See `VersionFloor`.
+    pub fn min_version(&self) -> Option<u32> {
+        self.min_version
+    }
+
+    /// If this is a delta image, the version of the image the delta must be applied to.
+    pub fn delta_base_version(&self) -> Option<u32> {
+        self.delta_base_version
+    }
+
+    /// If this is a delta image, the verity root hash of the image the delta must be applied to.
+    pub fn delta_base_root(&self) -> Option<&str> {
+        Self::str_ref(&self.delta_base_root)
+    }
+
+    /// The algorithm used to compress the image data if `FLAG_DATA_COMPRESSED` is set.
+    /// Images built before this field was added are compressed with xz.
+    pub fn compression(&self) -> Option<&str> {
+        Self::str_ref(&self.compression)
+    }
+
+    /// For a RealmFS image built from a recipe, the hash of the recipe. See `RealmFSRecipe`.
+    pub fn recipe_hash(&self) -> Option<&str> {
+        Self::str_ref(&self.recipe_hash)
+    }
+
+    /// For a sealed RealmFS image, the sha256 hash of the provenance file stored next
+    /// to the image. See `Provenance`.
+    pub fn provenance_sha256(&self) -> Option<&str> {
+        Self::str_ref(&self.provenance_sha256)
+    }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const METAINFO: &[u8] = b"image-type = \"rootfs\"\nchannel = \"test\"\nversion = 1\n";
+
+    fn header() -> ImageHeader {
+        let hdr = ImageHeader::new();
+        hdr.set_metainfo_bytes(METAINFO).unwrap();
+        hdr
+    }
+
+    #[test]
+    fn boot_tries_in_flags() {
+        let hdr = ImageHeader::new();
+        let flags = ImageHeader::FLAG_PREFER_BOOT | ImageHeader::FLAG_HASH_TREE | ImageHeader::FLAG_DATA_COMPRESSED;
+        hdr.set_flag(flags);
+        assert_eq!(hdr.boot_tries(), 0);
+
+        hdr.set_boot_tries(ImageHeader::DEFAULT_BOOT_TRIES);
+        assert_eq!(hdr.boot_tries(), ImageHeader::DEFAULT_BOOT_TRIES);
+        assert!(hdr.has_flag(flags));
+
+        // Values larger than fit in the nibble are clamped
+        hdr.set_boot_tries(200);
+        assert_eq!(hdr.boot_tries(), ImageHeader::MAX_BOOT_TRIES);
+        assert!(hdr.has_flag(flags));
+
+        hdr.clear_flag(ImageHeader::FLAG_HASH_TREE);
+        assert_eq!(hdr.boot_tries(), ImageHeader::MAX_BOOT_TRIES);
+        assert!(!hdr.has_flag(ImageHeader::FLAG_HASH_TREE));
+
+        hdr.set_boot_tries(0);
+        assert_eq!(hdr.boot_tries(), 0);
+        assert_eq!(hdr.flags(), ImageHeader::FLAG_PREFER_BOOT | ImageHeader::FLAG_DATA_COMPRESSED);
+    }
+
+    #[test]
+    fn signature_block_roundtrip() {
+        let (a, b, c) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let hdr = header();
+        hdr.add_signature(&a).unwrap();
+        hdr.add_signature(&b).unwrap();
+        hdr.add_signature(&c).unwrap();
+        // Signing again with the same key does not add another block
+        hdr.add_signature(&b).unwrap();
+
+        assert!(hdr.verify_signature(a.public_key()));
+        let blocks = hdr.signature_blocks();
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].0 == b.public_key());
+        assert!(blocks[1].0 == c.public_key());
+
+        let mut bytes = Vec::new();
+        hdr.write_header(&mut bytes).unwrap();
+        let hdr = ImageHeader::from_reader(&mut bytes.as_slice()).unwrap();
+        assert_eq!(hdr.signature_block_count(), 2);
+        let keys = ChannelKeys::new(vec![a.public_key(), b.public_key(), c.public_key()], 3).unwrap();
+        assert_eq!(hdr.valid_signature_count(&keys), 3);
+        assert!(hdr.verify_signatures(&keys));
+
+        hdr.clear_signature_blocks();
+        assert_eq!(hdr.signature_block_count(), 0);
+        assert_eq!(hdr.valid_signature_count(&keys), 1);
+        assert!(!hdr.verify_signatures(&keys));
+    }
+
+    #[test]
+    fn signature_below_threshold() {
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        let unknown = KeyPair::generate();
+        let hdr = header();
+        hdr.add_signature(&a).unwrap();
+        hdr.add_signature(&unknown).unwrap();
+        let keys = ChannelKeys::new(vec![a.public_key(), b.public_key()], 2).unwrap();
+        assert_eq!(hdr.valid_signature_count(&keys), 1);
+        assert!(!hdr.verify_signatures(&keys));
+    }
+}
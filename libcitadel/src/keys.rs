@@ -10,7 +10,7 @@ use hex;
 ///
 ///
 
-#[derive(Clone)]
+#[derive(Clone,PartialEq)]
 pub struct PublicKey(sign::PublicKey);
 pub struct KeyPair(Seed);
 pub struct Signature(sign::Signature);
@@ -23,7 +23,14 @@ impl PublicKey {
         if bytes.len() != PUBLICKEYBYTES {
             bail!("Hex encoded public key has invalid length: {}", bytes.len());
         }
-        let pubkey = sign::PublicKey::from_slice(&bytes)
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<PublicKey> {
+        if bytes.len() != PUBLICKEYBYTES {
+            bail!("Public key has invalid length: {}", bytes.len());
+        }
+        let pubkey = sign::PublicKey::from_slice(bytes)
             .expect("PublicKey::from_slice() failed");
         Ok(PublicKey(pubkey))
     }
@@ -32,6 +39,10 @@ impl PublicKey {
         hex::encode(&(self.0).0)
     }
 
+    pub fn to_bytes(&self) -> &[u8] {
+        &(self.0).0
+    }
+
     pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
         let sig = sign::Signature::from_slice(signature)
             .expect("Signature::from_slice() failed");
@@ -87,3 +98,144 @@ impl Signature {
     }
 }
 
+
+///
+/// The set of public keys which are trusted to sign images for a channel
+/// and the number of distinct keys from this set which must have produced
+/// a valid signature over an image header before the image is accepted.
+///
+/// Rolling the key for a channel is done by listing both the old and new
+/// keys in the set and signing images with both keys until every machine
+/// has been updated to trust the new key.
+///
+#[derive(Clone)]
+pub struct ChannelKeys {
+    keys: Vec<PublicKey>,
+    threshold: usize,
+}
+
+impl ChannelKeys {
+    pub fn new(keys: Vec<PublicKey>, threshold: usize) -> Result<ChannelKeys> {
+        let threshold = if threshold == 0 { 1 } else { threshold };
+        if threshold > keys.len() {
+            bail!("Signature threshold ({}) is larger than number of trusted keys ({})", threshold, keys.len());
+        }
+        Ok(ChannelKeys { keys, threshold })
+    }
+
+    pub fn single(key: PublicKey) -> ChannelKeys {
+        ChannelKeys { keys: vec![key], threshold: 1 }
+    }
+
+    /// Parse a comma separated list of hex encoded public keys.
+    pub fn from_hex_list(hex: &str, threshold: usize) -> Result<ChannelKeys> {
+        let mut keys = Vec::new();
+        for h in hex.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let key = PublicKey::from_hex(h)?;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        if keys.is_empty() {
+            bail!("No public keys found in key list");
+        }
+        ChannelKeys::new(keys, threshold)
+    }
+
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    /// Return the first key in the set
+    pub fn primary(&self) -> &PublicKey {
+        &self.keys[0]
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Count the number of distinct trusted keys for which a valid signature
+    /// over `data` exists. Signatures are provided as pairs of an optional
+    /// key which claims to have produced the signature and the signature bytes.
+    /// If no key is provided, every trusted key is tried.
+    pub fn count_valid(&self, data: &[u8], signatures: &[(Option<PublicKey>, Vec<u8>)]) -> usize {
+        let mut valid = vec![false; self.keys.len()];
+        for (claimed, sig) in signatures {
+            for (idx, key) in self.keys.iter().enumerate() {
+                if valid[idx] {
+                    continue;
+                }
+                if let Some(ref claimed) = *claimed {
+                    if claimed != key {
+                        continue;
+                    }
+                }
+                if key.verify(data, sig) {
+                    valid[idx] = true;
+                    break;
+                }
+            }
+        }
+        valid.iter().filter(|v| **v).count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DATA: &[u8] = b"metainfo bytes";
+
+    fn signature(keys: &KeyPair) -> Vec<u8> {
+        keys.sign(DATA).to_bytes().to_vec()
+    }
+
+    #[test]
+    fn threshold_larger_than_keys() {
+        let k1 = KeyPair::generate().public_key();
+        let k2 = KeyPair::generate().public_key();
+        assert!(ChannelKeys::new(vec![k1.clone()], 2).is_err());
+        assert_eq!(ChannelKeys::new(vec![k1, k2], 0).unwrap().threshold(), 1);
+    }
+
+    #[test]
+    fn count_valid_signers() {
+        let (a, b, c) = (KeyPair::generate(), KeyPair::generate(), KeyPair::generate());
+        let unknown = KeyPair::generate();
+        let keys = ChannelKeys::new(vec![a.public_key(), b.public_key(), c.public_key()], 2).unwrap();
+
+        // The same key signing twice only counts once
+        let sigs = vec![
+            (None, signature(&a)),
+            (Some(a.public_key()), signature(&a)),
+        ];
+        assert_eq!(keys.count_valid(DATA, &sigs), 1);
+
+        // Signatures from keys which are not trusted do not count
+        let sigs = vec![
+            (None, signature(&unknown)),
+            (Some(unknown.public_key()), signature(&unknown)),
+            (Some(b.public_key()), signature(&b)),
+        ];
+        assert_eq!(keys.count_valid(DATA, &sigs), 1);
+
+        // A signature is only checked against the key which claims it
+        let sigs = vec![(Some(a.public_key()), signature(&b))];
+        assert_eq!(keys.count_valid(DATA, &sigs), 0);
+
+        // Signatures over different data do not count
+        let sigs = vec![(None, a.sign(b"other").to_bytes().to_vec())];
+        assert_eq!(keys.count_valid(DATA, &sigs), 0);
+
+        let sigs = vec![
+            (None, signature(&c)),
+            (Some(a.public_key()), signature(&a)),
+        ];
+        assert_eq!(keys.count_valid(DATA, &sigs), 2);
+    }
+}
@@ -26,6 +26,11 @@ mod cmdline;
 mod header;
 mod partition;
 mod resource;
+mod delta;
+mod compress;
+mod progress;
+mod manifest;
+mod rollback;
 pub mod util;
 pub mod verity;
 mod realmfs;
@@ -42,15 +47,22 @@ pub use crate::cmdline::CommandLine;
 pub use crate::header::{ImageHeader,MetaInfo};
 pub use crate::partition::Partition;
 pub use crate::resource::ResourceImage;
-pub use crate::keys::{KeyPair,PublicKey,Signature};
-pub use crate::realmfs::{RealmFS,Mountpoint,Activation};
+pub use crate::delta::BlockDelta;
+pub use crate::compress::Compression;
+pub use crate::progress::{Progress,NoProgress,ProgressReader};
+pub use crate::manifest::{ChannelManifest,ManifestImage};
+pub use crate::rollback::VersionFloor;
+pub use crate::keys::{KeyPair,PublicKey,Signature,ChannelKeys};
+pub use crate::realmfs::{RealmFS,Mountpoint,Activation,Snapshot,RealmFSRecipe,RealmFSDiff,PackageChange,RealmFSBundle,BundleInfo,RealmFSGarbage,GarbageItem,GarbageKind,ActivationReconciler,Provenance,ParentImage};
 pub use crate::keyring::{KeyRing,KernelKey};
 pub use crate::exec::{Exec,FileRange};
 pub use crate::realmfs::resizer::{ImageResizer,ResizeSize};
 pub use crate::realm::overlay::RealmOverlay;
 pub use crate::realm::realm::Realm;
-pub use crate::realm::config::{RealmConfig,OverlayType,GLOBAL_CONFIG};
+pub use crate::realm::config::{RealmConfig,OverlayType,ResourceLimit,EgressPolicy,GLOBAL_CONFIG};
 pub use crate::realm::events::RealmEvent;
+pub use crate::realm::devices::DeviceSpec;
+pub use crate::realm::firewall::{RealmFirewall,EgressRule};
 pub use crate::realm::realms::Realms;
 pub use crate::realm::manager::RealmManager;
 pub use crate::log::{LogLevel,Logger,DefaultLogOutput,LogOutput};
@@ -65,23 +77,39 @@ pub fn devkeys() -> KeyPair {
 }
 
 pub fn public_key_for_channel(channel: &str) -> Result<Option<PublicKey>> {
+    let keys = public_keys_for_channel(channel)?;
+    Ok(keys.map(|keys| keys.primary().clone()))
+}
+
+/// Return the set of public keys trusted to sign images for `channel` along
+/// with the number of those keys which must have signed an image for it to be
+/// considered valid.
+///
+/// The trusted keys are never taken from the image itself. For channels other
+/// than 'dev' they are read from /etc/os-release (CITADEL_IMAGE_PUBKEY and
+/// CITADEL_IMAGE_SIGNATURE_THRESHOLD) or from the kernel command line
+/// (citadel.channel and citadel.sigthreshold). Multiple keys are given as a
+/// comma separated list of hex encoded keys.
+pub fn public_keys_for_channel(channel: &str) -> Result<Option<ChannelKeys>> {
     if channel == "dev" {
-        return Ok(Some(devkeys().public_key()));
+        return Ok(Some(ChannelKeys::single(devkeys().public_key())));
     }
 
     // Look in /etc/os-release
     if Some(channel) == OsRelease::citadel_channel() {
         if let Some(hex) = OsRelease::citadel_image_pubkey() {
-            let pubkey = PublicKey::from_hex(hex)?;
-            return Ok(Some(pubkey));
+            let threshold = OsRelease::citadel_image_signature_threshold().unwrap_or(1);
+            let keys = ChannelKeys::from_hex_list(hex, threshold)?;
+            return Ok(Some(keys));
         }
     }
 
     // Does kernel command line have citadel.channel=name:[hex encoded pubkey]
     if Some(channel) == CommandLine::channel_name() {
         if let Some(hex) = CommandLine::channel_pubkey() {
-            let pubkey = PublicKey::from_hex(hex)?;
-            return Ok(Some(pubkey))
+            let threshold = CommandLine::signature_threshold().unwrap_or(1);
+            let keys = ChannelKeys::from_hex_list(hex, threshold)?;
+            return Ok(Some(keys))
         }
     }
 
@@ -0,0 +1,284 @@
+use std::fs;
+use std::path::Path;
+
+use sodiumoxide::crypto::sign::SIGNATUREBYTES;
+
+use crate::{Result,KeyPair,PublicKey,ChannelKeys,public_keys_for_channel};
+
+///
+/// A channel manifest lists the images which are currently available from an
+/// update server for a single channel.
+///
+/// The manifest is a TOML document stored in a file called `manifest` in the
+/// directory for the channel. A second file `manifest.sig` contains one or
+/// more ed25519 signatures over the exact bytes of the manifest file. The
+/// manifest is only accepted if enough of these signatures were produced by
+/// the keys trusted for the channel (see `public_keys_for_channel()`).
+///
+/// ```text
+/// channel = "prod"
+/// timestamp = "1554222222"
+///
+/// [[image]]
+/// image-type = "rootfs"
+/// version = 12
+/// filename = "citadel-rootfs-prod-012.img"
+/// shasum = "..."
+/// size = 4096000
+/// ```
+///
+/// The `shasum` and `size` fields describe the complete image file including
+/// the header as it is stored on the server.
+///
+#[derive(Serialize,Deserialize,Clone)]
+pub struct ChannelManifest {
+    channel: String,
+    #[serde(default)]
+    timestamp: String,
+    #[serde(default, rename = "image")]
+    images: Vec<ManifestImage>,
+}
+
+#[derive(Serialize,Deserialize,Clone)]
+pub struct ManifestImage {
+    #[serde(rename = "image-type")]
+    image_type: String,
+    version: u32,
+    filename: String,
+    shasum: String,
+    size: u64,
+    #[serde(rename = "kernel-version")]
+    kernel_version: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Default)]
+struct ManifestSignatures {
+    #[serde(default, rename = "signature")]
+    signatures: Vec<ManifestSignature>,
+}
+
+#[derive(Serialize,Deserialize)]
+struct ManifestSignature {
+    #[serde(rename = "public-key")]
+    public_key: String,
+    signature: String,
+}
+
+impl ChannelManifest {
+    pub const MANIFEST_FILENAME: &'static str = "manifest";
+    pub const SIGNATURE_FILENAME: &'static str = "manifest.sig";
+
+    pub fn new(channel: &str, timestamp: &str) -> ChannelManifest {
+        ChannelManifest {
+            channel: channel.to_string(),
+            timestamp: timestamp.to_string(),
+            images: Vec::new(),
+        }
+    }
+
+    /// Parse `bytes` as a manifest document without verifying any signatures.
+    pub fn parse_bytes(bytes: &[u8]) -> Result<ChannelManifest> {
+        let manifest = toml::from_slice::<ChannelManifest>(bytes)?;
+        for image in &manifest.images {
+            image.validate()?;
+        }
+        Ok(manifest)
+    }
+
+    /// Parse the manifest in `bytes` and verify it against the signatures in `sigbytes`
+    /// using the keys trusted for the channel named in the manifest.
+    pub fn parse_verified(bytes: &[u8], sigbytes: &[u8]) -> Result<ChannelManifest> {
+        let manifest = Self::parse_bytes(bytes)?;
+        let keys = match public_keys_for_channel(manifest.channel())? {
+            Some(keys) => keys,
+            None => bail!("No public key available for channel {}", manifest.channel()),
+        };
+        manifest.verify(bytes, sigbytes, &keys)?;
+        Ok(manifest)
+    }
+
+    fn verify(&self, bytes: &[u8], sigbytes: &[u8], keys: &ChannelKeys) -> Result<()> {
+        let sigs = toml::from_slice::<ManifestSignatures>(sigbytes)?;
+        let mut signatures = Vec::new();
+        for sig in &sigs.signatures {
+            let pubkey = PublicKey::from_hex(&sig.public_key)?;
+            let signature = hex::decode(&sig.signature)?;
+            if signature.len() != SIGNATUREBYTES {
+                bail!("Manifest signature has invalid length {} (expected {})", signature.len(), SIGNATUREBYTES);
+            }
+            signatures.push((Some(pubkey), signature));
+        }
+        let count = keys.count_valid(bytes, &signatures);
+        if count < keys.threshold() {
+            bail!("Manifest signature verification failed ({} of {} required signatures valid)", count, keys.threshold());
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(toml::to_vec(self)?)
+    }
+
+    /// Sign the manifest file in directory `dir` with `keys` and add the signature to
+    /// the signature file in the same directory.
+    pub fn sign_file<P: AsRef<Path>>(dir: P, keys: &KeyPair) -> Result<()> {
+        let dir = dir.as_ref();
+        let bytes = fs::read(dir.join(Self::MANIFEST_FILENAME))?;
+        Self::parse_bytes(&bytes)?;
+
+        let sigpath = dir.join(Self::SIGNATURE_FILENAME);
+        let mut sigs = if sigpath.exists() {
+            toml::from_slice::<ManifestSignatures>(&fs::read(&sigpath)?)?
+        } else {
+            ManifestSignatures::default()
+        };
+        let public_key = keys.public_key().to_hex();
+        sigs.signatures.retain(|s| s.public_key != public_key);
+        sigs.signatures.push(ManifestSignature {
+            public_key,
+            signature: hex::encode(keys.sign(&bytes).to_bytes()),
+        });
+        fs::write(sigpath, toml::to_vec(&sigs)?)?;
+        Ok(())
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    pub fn timestamp(&self) -> &str {
+        &self.timestamp
+    }
+
+    /// Return `true` if this manifest was created before `other`. Timestamps
+    /// which cannot be parsed as a number of seconds are treated as zero.
+    pub fn is_older_than(&self, other: &ChannelManifest) -> bool {
+        let parse = |s: &str| s.parse::<u64>().unwrap_or(0);
+        parse(&self.timestamp) < parse(&other.timestamp)
+    }
+
+    pub fn images(&self) -> &[ManifestImage] {
+        &self.images
+    }
+
+    pub fn add_image(&mut self, image: ManifestImage) {
+        self.images.push(image);
+    }
+}
+
+impl ManifestImage {
+    pub fn new(image_type: &str, version: u32, filename: &str, shasum: &str, size: u64, kernel_version: Option<&str>) -> ManifestImage {
+        ManifestImage {
+            image_type: image_type.to_string(),
+            version,
+            filename: filename.to_string(),
+            shasum: shasum.to_string(),
+            size,
+            kernel_version: kernel_version.map(|s| s.to_string()),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        // filename is used to build a path when the image is downloaded
+        if self.filename.contains('/') || self.filename.starts_with('.') || !self.filename.ends_with(".img") {
+            bail!("Manifest contains invalid image filename '{}'", self.filename);
+        }
+        Ok(())
+    }
+
+    pub fn image_type(&self) -> &str {
+        &self.image_type
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn shasum(&self) -> &str {
+        &self.shasum
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn kernel_version(&self) -> Option<&str> {
+        self.kernel_version.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+channel = "test"
+timestamp = "1554222222"
+
+[[image]]
+image-type = "rootfs"
+version = 12
+filename = "citadel-rootfs-test-012.img"
+shasum = "abcd"
+size = 4096000
+"#;
+
+    fn sign(bytes: &[u8], keys: &[&KeyPair]) -> Vec<u8> {
+        let signatures = keys.iter().map(|k| ManifestSignature {
+            public_key: k.public_key().to_hex(),
+            signature: hex::encode(k.sign(bytes).to_bytes()),
+        }).collect();
+        toml::to_vec(&ManifestSignatures { signatures }).unwrap()
+    }
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = ChannelManifest::parse_bytes(MANIFEST.as_bytes()).unwrap();
+        assert_eq!(manifest.channel(), "test");
+        assert_eq!(manifest.timestamp(), "1554222222");
+        assert_eq!(manifest.images().len(), 1);
+        let image = &manifest.images()[0];
+        assert_eq!(image.image_type(), "rootfs");
+        assert_eq!(image.version(), 12);
+        assert_eq!(image.size(), 4096000);
+
+        let bad = MANIFEST.replace("citadel-rootfs-test-012.img", "../citadel-rootfs-test-012.img");
+        assert!(ChannelManifest::parse_bytes(bad.as_bytes()).is_err());
+        let bad = MANIFEST.replace("citadel-rootfs-test-012.img", ".hidden.img");
+        assert!(ChannelManifest::parse_bytes(bad.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn verify_manifest() {
+        let (a, b) = (KeyPair::generate(), KeyPair::generate());
+        let unknown = KeyPair::generate();
+        let bytes = MANIFEST.as_bytes();
+        let manifest = ChannelManifest::parse_bytes(bytes).unwrap();
+
+        let keys = ChannelKeys::new(vec![a.public_key(), b.public_key()], 1).unwrap();
+        assert!(manifest.verify(bytes, &sign(bytes, &[&a]), &keys).is_ok());
+        assert!(manifest.verify(bytes, &sign(bytes, &[&unknown]), &keys).is_err());
+        assert!(manifest.verify(bytes, &sign(b"other", &[&a]), &keys).is_err());
+        assert!(manifest.verify(bytes, b"", &keys).is_err());
+
+        let keys = ChannelKeys::new(vec![a.public_key(), b.public_key()], 2).unwrap();
+        assert!(manifest.verify(bytes, &sign(bytes, &[&a, &unknown]), &keys).is_err());
+        assert!(manifest.verify(bytes, &sign(bytes, &[&a, &b]), &keys).is_ok());
+
+        let short = format!("[[signature]]\npublic-key = \"{}\"\nsignature = \"abcd\"\n", a.public_key().to_hex());
+        assert!(manifest.verify(bytes, short.as_bytes(), &keys).is_err());
+    }
+
+    #[test]
+    fn manifest_timestamps() {
+        let old = ChannelManifest::new("test", "1554222222");
+        let new = ChannelManifest::new("test", "1554222300");
+        assert!(old.is_older_than(&new));
+        assert!(!new.is_older_than(&old));
+        assert!(!new.is_older_than(&new));
+    }
+}
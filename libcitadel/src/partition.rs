@@ -1,6 +1,6 @@
 use std::path::{Path,PathBuf};
 use std::fs;
-use crate::{Result,ImageHeader,MetaInfo,Mounts,PublicKey,public_key_for_channel};
+use crate::{Result,ImageHeader,MetaInfo,Mounts,ChannelKeys,VersionFloor,public_keys_for_channel};
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -13,8 +13,8 @@ pub struct Partition {
 #[derive(Clone)]
 struct HeaderInfo {
     header: Arc<ImageHeader>,
-    // None if no public key available for channel named in metainfo
-    pubkey: Option<PublicKey>,
+    // None if no public keys available for channel named in metainfo
+    keys: Option<ChannelKeys>,
 }
 
 impl Partition {
@@ -41,7 +41,7 @@ impl Partition {
         }
 
         let metainfo = header.metainfo();
-        let pubkey = match public_key_for_channel(metainfo.channel()) {
+        let keys = match public_keys_for_channel(metainfo.channel()) {
             Ok(result) => result,
             Err(err) => {
                 warn!("Error parsing pubkey for channel '{}': {}", metainfo.channel(), err);
@@ -51,7 +51,7 @@ impl Partition {
 
         let header = Arc::new(header);
         Ok(Some(HeaderInfo {
-            header, pubkey,
+            header, keys,
         }))
     }
 
@@ -96,24 +96,48 @@ impl Partition {
         self.header().has_flag(ImageHeader::FLAG_PREFER_BOOT)
     }
 
+    pub fn is_try_boot(&self) -> bool {
+        self.header().status() == ImageHeader::STATUS_TRY_BOOT
+    }
+
+    pub fn boot_tries(&self) -> u8 {
+        self.header().boot_tries()
+    }
+
     pub fn is_sig_failed(&self) -> bool {
         self.header().status() == ImageHeader::STATUS_BAD_SIG
     }
 
     pub fn is_signature_valid(&self) -> bool {
         if let Some(ref hinfo) = self.hinfo {
-            if let Some(ref pubkey) = hinfo.pubkey {
-                return pubkey.verify(
-                    &self.header().metainfo_bytes(),
-                    &self.header().signature())
+            if let Some(ref keys) = hinfo.keys {
+                return self.header().verify_signatures(keys);
             }
         }
         false
     }
 
+    /// Return the number of distinct trusted keys which have a valid
+    /// signature on the header, or 0 if no keys are available for the channel.
+    pub fn valid_signature_count(&self) -> usize {
+        match self.hinfo {
+            Some(HeaderInfo { keys: Some(ref keys), ..}) => self.header().valid_signature_count(keys),
+            _ => 0,
+        }
+    }
+
+    /// Return the number of valid signatures required for the channel of this
+    /// partition, or 0 if no keys are available for the channel.
+    pub fn signature_threshold(&self) -> usize {
+        match self.hinfo {
+            Some(HeaderInfo { keys: Some(ref keys), ..}) => keys.threshold(),
+            _ => 0,
+        }
+    }
+
     pub fn has_public_key(&self) -> bool {
         if let Some(ref h) = self.hinfo {
-            h.pubkey.is_some()
+            h.keys.is_some()
         } else {
             false
         }
@@ -137,7 +161,8 @@ impl Partition {
     /// Called at boot to perform various checks and possibly
     /// update the status field to an error state.
     ///
-    /// Mark `STATUS_TRY_BOOT` partition as `STATUS_FAILED`.
+    /// Mark `STATUS_TRY_BOOT` partition with no boot tries
+    /// remaining as `STATUS_FAILED`.
     ///
     /// If a partition that had prior signature failure now
     /// has a valid signature set to STATUS_NEW
@@ -146,9 +171,13 @@ impl Partition {
         if !self.is_initialized() {
             return Ok(())
         }
-        if self.header().status() == ImageHeader::STATUS_TRY_BOOT {
-            warn!("Partition {} has STATUS_TRY_BOOT, assuming it failed boot attempt and marking STATUS_FAILED", self.path().display());
-            self.write_status(ImageHeader::STATUS_FAILED)?;
+        if self.is_try_boot() {
+            if self.boot_tries() == 0 {
+                warn!("Partition {} has STATUS_TRY_BOOT and no boot tries remaining, marking STATUS_FAILED", self.path().display());
+                self.write_status(ImageHeader::STATUS_FAILED)?;
+            } else {
+                warn!("Partition {} has STATUS_TRY_BOOT, previous boot attempt did not complete ({} tries remaining)", self.path().display(), self.boot_tries());
+            }
         }
         if self.is_sig_failed() && self.is_signature_valid() {
             self.write_status(ImageHeader::STATUS_NEW)?;
@@ -156,9 +185,41 @@ impl Partition {
         Ok(())
     }
 
+    /// Called on the partition selected to boot. A `STATUS_NEW` partition is changed to
+    /// `STATUS_TRY_BOOT` and for a partition in `STATUS_TRY_BOOT` state the number of boot
+    /// tries remaining is decremented.
+    pub fn begin_boot_attempt(&mut self) -> Result<()> {
+        if let Some(tries) = self.count_boot_attempt() {
+            info!("Attempting boot of partition {} ({} tries remaining after this attempt)", self.path().display(), tries);
+            self.header().write_partition(&self.path)?;
+        }
+        Ok(())
+    }
+
+    // Update the header in memory for a boot attempt and return the number of
+    // tries remaining, or `None` if the partition is not counting boot attempts.
+    fn count_boot_attempt(&self) -> Option<u8> {
+        if self.is_new() {
+            // Partitions written before boot counting was added have a count of 0
+            if self.boot_tries() == 0 {
+                self.header().set_boot_tries(ImageHeader::DEFAULT_BOOT_TRIES);
+            }
+            self.header().set_status(ImageHeader::STATUS_TRY_BOOT);
+        } else if !self.is_try_boot() {
+            return None;
+        }
+        let tries = self.boot_tries().saturating_sub(1);
+        self.header().set_boot_tries(tries);
+        Some(tries)
+    }
+
     pub fn bless(&mut self) -> Result<()> {
         if self.header().status() == ImageHeader::STATUS_TRY_BOOT {
+            self.header().set_boot_tries(0);
             self.write_status(ImageHeader::STATUS_GOOD)?;
+            if let Err(err) = VersionFloor::update_from_metainfo(&self.metainfo()) {
+                warn!("Failed to update version floor: {}", err);
+            }
         }
         Ok(())
     }
@@ -219,3 +280,46 @@ fn path_filename(path: &Path) -> &str {
     ""
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn partition(status: u8, tries: u8) -> Partition {
+        let header = ImageHeader::new();
+        header.set_status(status);
+        header.set_flag(ImageHeader::FLAG_PREFER_BOOT);
+        header.set_boot_tries(tries);
+        let hinfo = HeaderInfo { header: Arc::new(header), keys: None };
+        Partition::new(Path::new("/dev/mapper/citadel-rootfs-a"), Some(hinfo), false)
+    }
+
+    #[test]
+    fn boot_attempt_new_partition() {
+        let p = partition(ImageHeader::STATUS_NEW, 0);
+        assert_eq!(p.count_boot_attempt(), Some(ImageHeader::DEFAULT_BOOT_TRIES - 1));
+        assert!(p.is_try_boot());
+        assert!(p.header().has_flag(ImageHeader::FLAG_PREFER_BOOT));
+
+        let p = partition(ImageHeader::STATUS_NEW, 5);
+        assert_eq!(p.count_boot_attempt(), Some(4));
+    }
+
+    #[test]
+    fn boot_attempt_try_boot_partition() {
+        let p = partition(ImageHeader::STATUS_TRY_BOOT, 2);
+        assert_eq!(p.count_boot_attempt(), Some(1));
+        assert_eq!(p.count_boot_attempt(), Some(0));
+        assert_eq!(p.count_boot_attempt(), Some(0));
+        assert!(p.is_try_boot());
+        assert!(p.header().has_flag(ImageHeader::FLAG_PREFER_BOOT));
+    }
+
+    #[test]
+    fn boot_attempt_good_partition() {
+        let p = partition(ImageHeader::STATUS_GOOD, 0);
+        assert_eq!(p.count_boot_attempt(), None);
+        assert!(p.is_good());
+        assert_eq!(p.boot_tries(), 0);
+    }
+}
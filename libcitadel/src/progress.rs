@@ -0,0 +1,50 @@
+use std::io::{self,Read};
+
+///
+/// Receives progress updates from long running image operations such as
+/// writing an image to a partition, decompressing an image or generating a
+/// dm-verity hash tree.
+///
+/// An operation calls `begin()` with a description and the total amount of
+/// work (usually a number of bytes or blocks), then calls `update()` with the
+/// amount of work completed so far, and finally calls `end()`. A single
+/// operation may report several stages by calling `begin()` more than once.
+///
+/// `update()` may be called very frequently, so implementations should avoid
+/// doing any expensive work unless the displayed value has changed.
+///
+pub trait Progress {
+    fn begin(&mut self, _message: &str, _total: u64) {}
+    fn update(&mut self, _done: u64) {}
+    fn end(&mut self) {}
+}
+
+/// A `Progress` implementation which ignores all updates.
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+///
+/// Wraps a `Read` instance and reports the number of bytes read so far
+/// to a `Progress` instance.
+///
+pub struct ProgressReader<'a, R: Read> {
+    reader: R,
+    count: u64,
+    progress: &'a mut dyn Progress,
+}
+
+impl <'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(reader: R, progress: &'a mut dyn Progress) -> Self {
+        ProgressReader { reader, count: 0, progress }
+    }
+}
+
+impl <'a, R: Read> Read for ProgressReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.count += n as u64;
+        self.progress.update(self.count);
+        Ok(n)
+    }
+}
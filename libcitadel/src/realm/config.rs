@@ -44,6 +44,147 @@ impl OverlayType {
     }
 }
 
+/// Policy for network traffic sent from a Realm to destinations outside of the realm
+///
+/// The policy cannot be enforced for a realm which uses a network namespace (`netns`),
+/// so such a realm fails to start with any policy other than `AllowAll`.
+#[derive(PartialEq,Debug,Copy,Clone)]
+pub enum EgressPolicy {
+    /// Allow all outgoing traffic
+    AllowAll,
+    /// Drop all outgoing traffic which is not a reply to an incoming connection
+    DenyAll,
+    /// Only allow outgoing traffic to the destinations listed in `egress-allow`
+    Allowlist,
+}
+
+impl EgressPolicy {
+    pub fn from_str_value(value: &str) -> Self {
+        if value == "allow-all" {
+            EgressPolicy::AllowAll
+        } else if value == "deny-all" {
+            EgressPolicy::DenyAll
+        } else if value == "allowlist" {
+            EgressPolicy::Allowlist
+        } else {
+            // Fail closed rather than silently allowing all traffic
+            warn!("Invalid egress policy: '{}'", value);
+            EgressPolicy::DenyAll
+        }
+    }
+
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            EgressPolicy::AllowAll => "allow-all",
+            EgressPolicy::DenyAll => "deny-all",
+            EgressPolicy::Allowlist => "allowlist",
+        }
+    }
+}
+
+/// A systemd resource control setting which can be configured for a Realm.
+///
+/// Resource limits are added to the `[Service]` section of the generated
+/// `realm-$name.service` unit and apply to all processes running in the realm.
+#[derive(PartialEq,Debug,Copy,Clone)]
+pub enum ResourceLimit {
+    /// Hard limit on memory use (`memory-max`), such as "4G"
+    MemoryMax,
+    /// Memory use above which processes are throttled and reclaimed (`memory-high`)
+    MemoryHigh,
+    /// Relative share of CPU time from 1 to 10000 (`cpu-weight`). The default is 100.
+    CpuWeight,
+    /// Maximum CPU time as a percentage of one CPU (`cpu-quota`). 200 allows two full CPUs.
+    CpuQuota,
+    /// Maximum number of processes and threads (`tasks-max`)
+    TasksMax,
+    /// Relative share of block IO from 1 to 10000 (`io-weight`). The default is 100.
+    IoWeight,
+}
+
+impl ResourceLimit {
+    const ALL: &'static [ResourceLimit] = &[
+        ResourceLimit::MemoryMax, ResourceLimit::MemoryHigh,
+        ResourceLimit::CpuWeight, ResourceLimit::CpuQuota,
+        ResourceLimit::TasksMax, ResourceLimit::IoWeight,
+    ];
+
+    pub fn all() -> &'static [ResourceLimit] {
+        Self::ALL
+    }
+
+    /// Name of the key for this limit in a realm config file.
+    pub fn config_key(self) -> &'static str {
+        match self {
+            ResourceLimit::MemoryMax => "memory-max",
+            ResourceLimit::MemoryHigh => "memory-high",
+            ResourceLimit::CpuWeight => "cpu-weight",
+            ResourceLimit::CpuQuota => "cpu-quota",
+            ResourceLimit::TasksMax => "tasks-max",
+            ResourceLimit::IoWeight => "io-weight",
+        }
+    }
+
+    /// A short description of this limit for display to the user.
+    pub fn description(self) -> &'static str {
+        match self {
+            ResourceLimit::MemoryMax => "Maximum memory",
+            ResourceLimit::MemoryHigh => "Memory throttle limit",
+            ResourceLimit::CpuWeight => "CPU weight",
+            ResourceLimit::CpuQuota => "CPU quota (%)",
+            ResourceLimit::TasksMax => "Maximum tasks",
+            ResourceLimit::IoWeight => "IO weight",
+        }
+    }
+
+    fn is_memory(self) -> bool {
+        self == ResourceLimit::MemoryMax || self == ResourceLimit::MemoryHigh
+    }
+
+    /// Check that `value` is a valid value for this limit. Memory limits are a number
+    /// of bytes with an optional K, M, G or T suffix, a percentage of physical memory
+    /// or "infinity". All other limits are positive integers.
+    pub fn validate(self, value: &str) -> Result<()> {
+        if self.is_memory() {
+            if !Self::is_valid_memory_value(value) {
+                bail!("invalid value '{}' for {}: expected a size such as 512M or 4G", value, self.config_key());
+            }
+            return Ok(());
+        }
+        let n = value.parse::<u32>()
+            .map_err(|_| format_err!("invalid value '{}' for {}: expected a number", value, self.config_key()))?;
+        let max = match self {
+            ResourceLimit::CpuWeight | ResourceLimit::IoWeight => 10000,
+            _ => u32::MAX,
+        };
+        if n == 0 || n > max {
+            bail!("value {} for {} is out of range (1-{})", n, self.config_key(), max);
+        }
+        Ok(())
+    }
+
+    fn is_valid_memory_value(value: &str) -> bool {
+        if value == "infinity" {
+            return true;
+        }
+        let digits = value.trim_end_matches(|c| "KMGT%".contains(c));
+        let suffix_len = value.len() - digits.len();
+        !digits.is_empty() && suffix_len <= 1 && digits.chars().all(|c| c.is_ascii_digit())
+    }
+
+    /// The systemd unit file directive which sets this limit to `value`.
+    pub fn unit_directive(self, value: &str) -> String {
+        match self {
+            ResourceLimit::MemoryMax => format!("MemoryMax={}", value),
+            ResourceLimit::MemoryHigh => format!("MemoryHigh={}", value),
+            ResourceLimit::CpuWeight => format!("CPUWeight={}", value),
+            ResourceLimit::CpuQuota => format!("CPUQuota={}%", value),
+            ResourceLimit::TasksMax => format!("TasksMax={}", value),
+            ResourceLimit::IoWeight => format!("IOWeight={}", value),
+        }
+    }
+}
+
 /// Content of a Realm configuration file
 #[derive (Serialize,Deserialize,Clone)]
 pub struct RealmConfig {
@@ -109,6 +250,32 @@ pub struct RealmConfig {
 
     pub netns: Option<String>,
 
+    pub devices: Option<Vec<String>>,
+
+    #[serde(rename="egress-policy")]
+    pub egress_policy: Option<String>,
+
+    #[serde(rename="egress-allow")]
+    pub egress_allow: Option<Vec<String>>,
+
+    #[serde(rename="memory-max")]
+    pub memory_max: Option<String>,
+
+    #[serde(rename="memory-high")]
+    pub memory_high: Option<String>,
+
+    #[serde(rename="cpu-weight")]
+    pub cpu_weight: Option<u32>,
+
+    #[serde(rename="cpu-quota")]
+    pub cpu_quota: Option<u32>,
+
+    #[serde(rename="tasks-max")]
+    pub tasks_max: Option<u32>,
+
+    #[serde(rename="io-weight")]
+    pub io_weight: Option<u32>,
+
     #[serde(skip)]
     pub parent: Option<Box<RealmConfig>>,
 
@@ -209,6 +376,15 @@ impl RealmConfig {
             overlay: Some(DEFAULT_OVERLAY.into()),
             terminal_scheme: None,
             netns: None,
+            memory_max: None,
+            memory_high: None,
+            cpu_weight: None,
+            cpu_quota: None,
+            tasks_max: None,
+            io_weight: None,
+            devices: None,
+            egress_policy: None,
+            egress_allow: None,
             parent: None,
             loaded: None,
             path: PathBuf::new(),
@@ -239,6 +415,15 @@ impl RealmConfig {
             overlay: None,
             terminal_scheme: None,
             netns: None,
+            memory_max: None,
+            memory_high: None,
+            cpu_weight: None,
+            cpu_quota: None,
+            tasks_max: None,
+            io_weight: None,
+            devices: None,
+            egress_policy: None,
+            egress_allow: None,
             parent: None,
             loaded: None,
             path: PathBuf::new(),
@@ -370,6 +555,12 @@ impl RealmConfig {
         self.str_vec_value(|c| c.extra_bindmounts_ro.as_ref())
     }
 
+    /// A list of devices to pass through to the realm, such as "webcam", "fido",
+    /// "usb:1050:0407" or "/dev/ttyUSB0". See `DeviceSpec` for the supported values.
+    pub fn devices(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.devices.as_ref())
+    }
+
     /// A list of names of realms this realm depends on. When this realm is started
     /// these realms will also be started if not already running.
     pub fn realm_depends(&self) -> Vec<&str> {
@@ -411,6 +602,68 @@ impl RealmConfig {
         self.netns().is_some()
     }
 
+    /// The policy for outgoing network traffic from this realm. The default is to
+    /// allow all traffic.
+    pub fn egress_policy(&self) -> EgressPolicy {
+        self.str_value(|c| c.egress_policy.as_ref())
+            .map_or(EgressPolicy::AllowAll, EgressPolicy::from_str_value)
+    }
+
+    /// Set the egress policy string variable according to the `EgressPolicy` argument.
+    pub fn set_egress_policy(&mut self, policy: EgressPolicy) {
+        self.egress_policy = Some(policy.to_str_value().to_string());
+    }
+
+    /// A list of destinations this realm may connect to when the egress policy is
+    /// `allowlist`, such as "10.8.0.0/16" or "vpn.example.com:1194/udp".
+    /// See `EgressRule` for the supported values.
+    pub fn egress_allow(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.egress_allow.as_ref())
+    }
+
+    /// The value of resource `limit` for this realm, or `None` if no limit is configured.
+    pub fn resource_limit(&self, limit: ResourceLimit) -> Option<String> {
+        match limit {
+            ResourceLimit::MemoryMax => self.str_value(|c| c.memory_max.as_ref()).map(String::from),
+            ResourceLimit::MemoryHigh => self.str_value(|c| c.memory_high.as_ref()).map(String::from),
+            ResourceLimit::CpuWeight => self.u32_value(|c| c.cpu_weight).map(|n| n.to_string()),
+            ResourceLimit::CpuQuota => self.u32_value(|c| c.cpu_quota).map(|n| n.to_string()),
+            ResourceLimit::TasksMax => self.u32_value(|c| c.tasks_max).map(|n| n.to_string()),
+            ResourceLimit::IoWeight => self.u32_value(|c| c.io_weight).map(|n| n.to_string()),
+        }
+    }
+
+    /// Set resource `limit` in this config to `value`, or remove it if `value` is `None`.
+    /// Returns an error without changing the config if `value` is not valid.
+    pub fn set_resource_limit(&mut self, limit: ResourceLimit, value: Option<&str>) -> Result<()> {
+        if let Some(value) = value {
+            limit.validate(value)?;
+        }
+        let number = value.and_then(|v| v.parse::<u32>().ok());
+        let string = value.map(String::from);
+        match limit {
+            ResourceLimit::MemoryMax => self.memory_max = string,
+            ResourceLimit::MemoryHigh => self.memory_high = string,
+            ResourceLimit::CpuWeight => self.cpu_weight = number,
+            ResourceLimit::CpuQuota => self.cpu_quota = number,
+            ResourceLimit::TasksMax => self.tasks_max = number,
+            ResourceLimit::IoWeight => self.io_weight = number,
+        }
+        Ok(())
+    }
+
+    fn u32_value<F>(&self, get: F) -> Option<u32>
+        where F: Fn(&RealmConfig) -> Option<u32>
+    {
+        if let Some(val) = get(self) {
+            return Some(val)
+        }
+        if let Some(ref parent) = self.parent {
+            return parent.u32_value(get);
+        }
+        None
+    }
+
     fn str_vec_value<F>(&self, get: F) -> Vec<&str>
         where F: Fn(&RealmConfig) -> Option<&Vec<String>>
     {
@@ -448,3 +701,29 @@ impl RealmConfig {
         false
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resource_limits() {
+        assert!(ResourceLimit::MemoryMax.validate("4G").is_ok());
+        assert!(ResourceLimit::MemoryHigh.validate("536870912").is_ok());
+        assert!(ResourceLimit::MemoryMax.validate("50%").is_ok());
+        assert!(ResourceLimit::MemoryMax.validate("infinity").is_ok());
+        assert!(ResourceLimit::MemoryMax.validate("4GB").is_err());
+        assert!(ResourceLimit::MemoryMax.validate("G").is_err());
+        assert!(ResourceLimit::CpuWeight.validate("10000").is_ok());
+        assert!(ResourceLimit::CpuWeight.validate("10001").is_err());
+        assert!(ResourceLimit::TasksMax.validate("0").is_err());
+
+        let mut config = RealmConfig::empty();
+        config.parent = Some(Box::new(RealmConfig { cpu_weight: Some(50), ..RealmConfig::empty() }));
+        assert_eq!(config.resource_limit(ResourceLimit::CpuWeight), Some("50".to_string()));
+        config.set_resource_limit(ResourceLimit::CpuQuota, Some("200")).unwrap();
+        assert!(config.set_resource_limit(ResourceLimit::CpuQuota, Some("x")).is_err());
+        assert_eq!(config.cpu_quota, Some(200));
+        assert_eq!(ResourceLimit::CpuQuota.unit_directive("200"), "CPUQuota=200%");
+    }
+}
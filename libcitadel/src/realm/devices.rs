@@ -0,0 +1,207 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use crate::Result;
+
+const SYS_CLASS_PATH: &str = "/sys/class";
+const SYS_USB_DEVICES_PATH: &str = "/sys/bus/usb/devices";
+const SYS_BLOCK_PATH: &str = "/sys/block";
+
+// Report descriptor item which selects the FIDO Alliance HID usage page (0xF1D0)
+const FIDO_USAGE_PAGE: [u8; 3] = [0x06, 0xD0, 0xF1];
+
+// Limit on how deep the sysfs directory of a USB device is searched for device nodes
+const MAX_SYSFS_DEPTH: usize = 8;
+
+///
+/// A device or class of devices which is passed through to a realm.
+///
+/// Devices are listed in the `devices` key of the realm config file:
+///
+/// ```text
+/// devices = [ "webcam", "fido", "usb:1050:0407", "/dev/ttyUSB0" ]
+/// ```
+///
+///   * `webcam` All video capture devices (`/dev/video*`)
+///   * `fido` All FIDO/U2F security keys (the `/dev/hidraw*` nodes with the FIDO usage page)
+///   * `usb:<vendor>:<product>` All device nodes belonging to USB devices with the
+///     given hexadecimal vendor and product ids
+///   * `/dev/...` A single device node
+///
+#[derive(Clone,Debug,PartialEq)]
+pub enum DeviceSpec {
+    Webcam,
+    Fido,
+    Usb { vendor: u16, product: u16 },
+    Node(PathBuf),
+}
+
+impl DeviceSpec {
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "webcam" {
+            Ok(DeviceSpec::Webcam)
+        } else if s == "fido" {
+            Ok(DeviceSpec::Fido)
+        } else if let Some(ids) = s.strip_prefix("usb:") {
+            let ids = ids.split(':').collect::<Vec<_>>();
+            if ids.len() != 2 {
+                bail!("invalid USB device '{}': expected usb:<vendor>:<product>", s);
+            }
+            let parse_id = |id: &str| u16::from_str_radix(id, 16)
+                .map_err(|_| format_err!("invalid USB device '{}': '{}' is not a hexadecimal id", s, id));
+            Ok(DeviceSpec::Usb { vendor: parse_id(ids[0])?, product: parse_id(ids[1])? })
+        } else if s.starts_with("/dev/") && !s.contains(|c: char| c.is_whitespace()) && !s.contains("..") {
+            Ok(DeviceSpec::Node(PathBuf::from(s)))
+        } else {
+            bail!("invalid device '{}': expected webcam, fido, usb:<vendor>:<product> or a path in /dev", s);
+        }
+    }
+
+    /// Find the device nodes which currently exist for this device. Returns an empty
+    /// list if no matching devices are connected.
+    pub fn device_nodes(&self) -> Result<Vec<PathBuf>> {
+        let mut nodes = match self {
+            DeviceSpec::Webcam => Self::class_devices("video4linux", |_| true)?,
+            DeviceSpec::Fido => Self::class_devices("hidraw", Self::is_fido_hidraw)?,
+            DeviceSpec::Usb { vendor, product } => Self::usb_devices(*vendor, *product)?,
+            DeviceSpec::Node(path) if path.exists() => vec![path.clone()],
+            DeviceSpec::Node(_) => Vec::new(),
+        };
+        nodes.sort();
+        nodes.dedup();
+        Ok(nodes)
+    }
+
+    /// Device nodes of removable block devices such as USB storage devices and the
+    /// partitions on them, which can be attached to a running realm.
+    pub fn removable_block_devices() -> Result<Vec<PathBuf>> {
+        let mut nodes = Vec::new();
+        if !Path::new(SYS_BLOCK_PATH).exists() {
+            return Ok(nodes);
+        }
+        for entry in fs::read_dir(SYS_BLOCK_PATH)? {
+            let path = entry?.path();
+            let removable = fs::read_to_string(path.join("removable"))
+                .map(|s| s.trim() == "1")
+                .unwrap_or(false);
+            if !removable {
+                continue;
+            }
+            nodes.extend(Self::uevent_device_node(&path));
+            for child in fs::read_dir(&path)? {
+                let child = child?.path();
+                if child.join("partition").exists() {
+                    nodes.extend(Self::uevent_device_node(&child));
+                }
+            }
+        }
+        nodes.sort();
+        Ok(nodes)
+    }
+
+    // Device nodes for entries in /sys/class/$class for which `filter` returns true
+    fn class_devices<F>(class: &str, filter: F) -> Result<Vec<PathBuf>>
+        where F: Fn(&Path) -> bool
+    {
+        let class_path = Path::new(SYS_CLASS_PATH).join(class);
+        if !class_path.exists() {
+            return Ok(Vec::new());
+        }
+        let mut nodes = Vec::new();
+        for entry in fs::read_dir(class_path)? {
+            let path = entry?.path();
+            if filter(&path) {
+                if let Some(node) = Self::uevent_device_node(&path) {
+                    nodes.push(node);
+                }
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn is_fido_hidraw(path: &Path) -> bool {
+        fs::read(path.join("device/report_descriptor"))
+            .map(|desc| desc.windows(FIDO_USAGE_PAGE.len()).any(|w| w == FIDO_USAGE_PAGE))
+            .unwrap_or(false)
+    }
+
+    fn usb_devices(vendor: u16, product: u16) -> Result<Vec<PathBuf>> {
+        let mut nodes = Vec::new();
+        if !Path::new(SYS_USB_DEVICES_PATH).exists() {
+            return Ok(nodes);
+        }
+        for entry in fs::read_dir(SYS_USB_DEVICES_PATH)? {
+            let path = entry?.path();
+            if Self::read_usb_id(&path, "idVendor") == Some(vendor) && Self::read_usb_id(&path, "idProduct") == Some(product) {
+                // Resolve the symlink so that the device directory can be searched
+                let path = path.canonicalize()?;
+                Self::collect_device_nodes(&path, 0, &mut nodes)?;
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn read_usb_id(path: &Path, name: &str) -> Option<u16> {
+        let s = fs::read_to_string(path.join(name)).ok()?;
+        u16::from_str_radix(s.trim(), 16).ok()
+    }
+
+    // Collect device nodes for the sysfs device directory `dir` and all of the child
+    // devices below it, such as the hidraw, tty and video nodes of a USB device.
+    fn collect_device_nodes(dir: &Path, depth: usize, nodes: &mut Vec<PathBuf>) -> Result<()> {
+        if let Some(node) = Self::uevent_device_node(dir) {
+            nodes.push(node);
+        }
+        if depth == MAX_SYSFS_DEPTH {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            // Symlinks such as 'subsystem' and 'driver' lead out of the device tree
+            if entry.file_type()?.is_dir() {
+                Self::collect_device_nodes(&entry.path(), depth + 1, nodes)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Read the DEVNAME entry from the uevent file of a sysfs device directory
+    fn uevent_device_node(dir: &Path) -> Option<PathBuf> {
+        let uevent = fs::read_to_string(dir.join("uevent")).ok()?;
+        uevent.lines()
+            .find_map(|line| line.strip_prefix("DEVNAME="))
+            .map(|devname| Path::new("/dev").join(devname))
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSpec::Webcam => write!(f, "webcam"),
+            DeviceSpec::Fido => write!(f, "fido"),
+            DeviceSpec::Usb { vendor, product } => write!(f, "usb:{:04x}:{:04x}", vendor, product),
+            DeviceSpec::Node(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_devices() {
+        assert_eq!(DeviceSpec::parse("webcam").unwrap(), DeviceSpec::Webcam);
+        assert_eq!(DeviceSpec::parse("fido").unwrap(), DeviceSpec::Fido);
+        assert_eq!(DeviceSpec::parse("usb:1050:0407").unwrap(), DeviceSpec::Usb { vendor: 0x1050, product: 0x0407 });
+        assert_eq!(DeviceSpec::parse("/dev/ttyUSB0").unwrap(), DeviceSpec::Node(PathBuf::from("/dev/ttyUSB0")));
+        assert!(DeviceSpec::parse("usb:1050").is_err());
+        assert!(DeviceSpec::parse("usb:xyz:0407").is_err());
+        assert!(DeviceSpec::parse("/dev/../etc/shadow").is_err());
+        assert!(DeviceSpec::parse("/tmp/foo").is_err());
+        assert_eq!(DeviceSpec::Usb { vendor: 0x1050, product: 0x407 }.to_string(), "usb:1050:0407");
+    }
+}
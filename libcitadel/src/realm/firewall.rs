@@ -0,0 +1,370 @@
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::net::{Ipv4Addr,SocketAddr,ToSocketAddrs};
+use std::path::Path;
+use std::process::{Command,Stdio};
+
+use crate::{Result,EgressPolicy};
+use crate::realm::network::NetworkZone;
+
+const NFT_PATH: &str = "/usr/sbin/nft";
+
+// Maximum length of a network interface name
+const MAX_IFNAME_LEN: usize = 15;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+///
+/// A destination which a realm with an `allowlist` egress policy is permitted to reach.
+///
+/// Entries in the `egress-allow` list of the realm config have the form
+/// `address[:port][/protocol]` where address is an IPv4 address, a network in
+/// CIDR notation or a hostname:
+///
+/// ```text
+/// egress-allow = [ "10.8.0.0/16", "vpn.example.com:1194/udp", "192.168.1.10:443" ]
+/// ```
+///
+/// Hostnames are resolved when the realm is started. If a port is given without a
+/// protocol, both tcp and udp traffic to the port are allowed.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub struct EgressRule {
+    address: String,
+    port: Option<u16>,
+    protocol: Option<Protocol>,
+}
+
+impl EgressRule {
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (rest, protocol) = if let Some(rest) = s.strip_suffix("/tcp") {
+            (rest, Some(Protocol::Tcp))
+        } else if let Some(rest) = s.strip_suffix("/udp") {
+            (rest, Some(Protocol::Udp))
+        } else {
+            (s, None)
+        };
+
+        let (address, port) = match rest.rfind(':') {
+            Some(idx) => {
+                let port = rest[idx + 1..].parse::<u16>()
+                    .map_err(|_| format_err!("invalid port in egress rule '{}'", s))?;
+                (&rest[..idx], Some(port))
+            },
+            None => (rest, None),
+        };
+
+        if protocol.is_some() && port.is_none() {
+            bail!("egress rule '{}' has a protocol but no port", s);
+        }
+        if !Self::is_valid_address(address) {
+            bail!("invalid address in egress rule '{}'", s);
+        }
+        Ok(EgressRule { address: address.to_string(), port, protocol })
+    }
+
+    fn is_valid_address(address: &str) -> bool {
+        if let Some(idx) = address.find('/') {
+            let mask = address[idx + 1..].parse::<u8>();
+            return address[..idx].parse::<Ipv4Addr>().is_ok() && mask.map(|m| m <= 32).unwrap_or(false);
+        }
+        !address.is_empty() && address.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    }
+
+    // Resolve the address of this rule to a list of IPv4 addresses or networks
+    fn destinations(&self) -> Result<Vec<String>> {
+        if self.address.contains('/') || self.address.parse::<Ipv4Addr>().is_ok() {
+            return Ok(vec![self.address.clone()]);
+        }
+        let mut addrs = (self.address.as_str(), 0).to_socket_addrs()
+            .map_err(|e| format_err!("failed to resolve egress host '{}': {}", self.address, e))?
+            .filter_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr.ip().to_string()),
+                SocketAddr::V6(_) => None,
+            })
+            .collect::<Vec<_>>();
+        addrs.sort();
+        addrs.dedup();
+        if addrs.is_empty() {
+            bail!("egress host '{}' has no IPv4 address", self.address);
+        }
+        Ok(addrs)
+    }
+
+    // The nftables match expressions for this rule, without the source address
+    fn matches(&self) -> Result<Vec<String>> {
+        let destinations = self.destinations()?;
+        let daddr = if destinations.len() == 1 {
+            format!("ip daddr {}", destinations[0])
+        } else {
+            format!("ip daddr {{ {} }}", destinations.join(", "))
+        };
+        let protocols = match (self.port, self.protocol) {
+            (None, _) => return Ok(vec![daddr]),
+            (Some(_), Some(protocol)) => vec![protocol],
+            (Some(_), None) => vec![Protocol::Tcp, Protocol::Udp],
+        };
+        let port = self.port.unwrap_or_default();
+        Ok(protocols.into_iter()
+            .map(|p| format!("{} {} dport {}", daddr, p.as_str(), port))
+            .collect())
+    }
+}
+
+///
+/// The nftables rules which enforce the egress policy of a running realm.
+///
+/// Each realm with an egress policy other than `allow-all` has its own table
+/// (`bridge realm-$name`) which filters all traffic entering the zone bridge from
+/// the host side of the virtual ethernet link of the realm (`vb-$name`). This covers
+/// traffic to other realms on the same bridge, to the host itself (such as DNS on
+/// the gateway address) and traffic routed out of the zone.
+///
+/// Since root in the realm can configure any address on its interface, traffic with
+/// a source address other than the address allocated to the realm is dropped, as is
+/// all traffic which is not IPv4 apart from ARP for the allocated address. The
+/// remaining traffic is dropped unless it is a reply to a connection from outside or
+/// matches one of the allowed destinations.
+///
+pub struct RealmFirewall {
+    realm_name: String,
+    address: Ipv4Addr,
+    policy: EgressPolicy,
+    rules: Vec<EgressRule>,
+}
+
+impl RealmFirewall {
+
+    pub fn new(realm_name: &str, address: Ipv4Addr, policy: EgressPolicy, rules: Vec<EgressRule>) -> Self {
+        RealmFirewall { realm_name: realm_name.to_string(), address, policy, rules }
+    }
+
+    fn table_name(realm_name: &str) -> String {
+        format!("realm-{}", realm_name)
+    }
+
+    // Name of the host side of the virtual ethernet link systemd-nspawn creates for the
+    // realm. Longer names are shortened by systemd-nspawn in a way which cannot be
+    // predicted before the realm is started.
+    fn host_interface(&self) -> Result<String> {
+        let ifname = format!("vb-{}", self.realm_name);
+        if ifname.len() > MAX_IFNAME_LEN {
+            bail!("cannot apply egress policy to realm-{} because the name is longer than {} characters",
+                  self.realm_name, MAX_IFNAME_LEN - "vb-".len());
+        }
+        Ok(ifname)
+    }
+
+    /// Generate the nftables ruleset for this realm in the syntax read by `nft -f`
+    pub fn ruleset(&self) -> Result<String> {
+        let ifname = self.host_interface()?;
+        let mut matches = Vec::new();
+        if self.policy == EgressPolicy::Allowlist {
+            for rule in &self.rules {
+                matches.extend(rule.matches()?);
+            }
+        }
+
+        let mut s = String::new();
+        let table = Self::table_name(&self.realm_name);
+        writeln!(s, "table bridge {} {{", table)?;
+        for hook in &["input", "forward"] {
+            writeln!(s, "    chain {} {{", hook)?;
+            writeln!(s, "        type filter hook {} priority 0; policy accept;", hook)?;
+            writeln!(s, "        iifname \"{}\" jump egress", ifname)?;
+            writeln!(s, "    }}")?;
+        }
+        writeln!(s, "    chain egress {{")?;
+        writeln!(s, "        arp saddr ip {{ 0.0.0.0, {} }} accept", self.address)?;
+        writeln!(s, "        ether type != ip drop")?;
+        writeln!(s, "        ip saddr != {} drop", self.address)?;
+        writeln!(s, "        ct state established,related accept")?;
+        for m in &matches {
+            writeln!(s, "        {} accept", m)?;
+        }
+        writeln!(s, "        drop")?;
+        writeln!(s, "    }}")?;
+        writeln!(s, "}}")?;
+        Ok(s)
+    }
+
+    /// Install the rules for this realm, replacing any rules which were previously
+    /// installed for a realm with the same name.
+    pub fn install(&self) -> Result<()> {
+        let ruleset = self.ruleset()?;
+        info!("Installing egress firewall rules for realm-{}", self.realm_name);
+        replace_table("bridge", &Self::table_name(&self.realm_name), &ruleset)
+            .map_err(|e| format_err!("failed to install egress firewall rules for realm-{}: {}", self.realm_name, e))
+    }
+
+    /// Remove the rules for the realm `realm_name` if they are installed.
+    pub fn remove(realm_name: &str) -> Result<()> {
+        delete_table("bridge", &Self::table_name(realm_name))
+    }
+}
+
+///
+/// The nftables rules for a network zone with an upstream interface.
+///
+/// Traffic from the bridge of the zone is only forwarded to the upstream interface
+/// and masqueraded there, and the bridge only receives forwarded traffic from the
+/// upstream interface.
+///
+pub struct ZoneFirewall<'a> {
+    zone: &'a NetworkZone,
+}
+
+impl <'a> ZoneFirewall<'a> {
+
+    pub fn new(zone: &'a NetworkZone) -> Self {
+        ZoneFirewall { zone }
+    }
+
+    fn table_name(&self) -> String {
+        format!("zone-{}", self.zone.name())
+    }
+
+    /// Generate the nftables ruleset for this zone, or `None` if the zone has no
+    /// upstream interface.
+    pub fn ruleset(&self) -> Result<Option<String>> {
+        let upstream = match self.zone.upstream() {
+            Some(upstream) => upstream,
+            None => return Ok(None),
+        };
+        let bridge = self.zone.bridge_name();
+        let mut s = String::new();
+        writeln!(s, "table inet {} {{", self.table_name())?;
+        writeln!(s, "    chain forward {{")?;
+        writeln!(s, "        type filter hook forward priority 0; policy accept;")?;
+        writeln!(s, "        iifname \"{}\" oifname != \"{}\" drop", bridge, upstream)?;
+        writeln!(s, "        oifname \"{}\" iifname != \"{}\" drop", bridge, upstream)?;
+        writeln!(s, "    }}")?;
+        writeln!(s, "    chain postrouting {{")?;
+        writeln!(s, "        type nat hook postrouting priority 100; policy accept;")?;
+        writeln!(s, "        ip saddr {} oifname \"{}\" masquerade", self.zone.network(), upstream)?;
+        writeln!(s, "    }}")?;
+        writeln!(s, "}}")?;
+        Ok(Some(s))
+    }
+
+    /// Install the rules for this zone if it has an upstream interface, replacing
+    /// any rules previously installed for the zone.
+    pub fn install(&self) -> Result<()> {
+        match self.ruleset()? {
+            Some(ruleset) => replace_table("inet", &self.table_name(), &ruleset)
+                .map_err(|e| format_err!("failed to install firewall rules for network zone {}: {}", self.zone.name(), e)),
+            None => delete_table("inet", &self.table_name()),
+        }
+    }
+}
+
+// Atomically replace the table `table` in `family` with the table in `ruleset`.
+// Declaring the table before deleting it ensures the delete succeeds if it does not exist yet.
+fn replace_table(family: &str, table: &str, ruleset: &str) -> Result<()> {
+    let mut child = Command::new(NFT_PATH)
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format_err!("failed to execute {}: {}", NFT_PATH, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "table {} {}", family, table)?;
+        writeln!(stdin, "delete table {} {}", family, table)?;
+        stdin.write_all(ruleset.as_bytes())?;
+    }
+    if !child.wait()?.success() {
+        bail!("{} failed to load table {}", NFT_PATH, table);
+    }
+    Ok(())
+}
+
+// Delete the table `table` in `family` if it exists
+fn delete_table(family: &str, table: &str) -> Result<()> {
+    // Without nft no rules can have been installed
+    if !Path::new(NFT_PATH).exists() {
+        return Ok(());
+    }
+    let exists = Command::new(NFT_PATH)
+        .args(["list", "table", family, table])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .map_err(|e| format_err!("failed to execute {}: {}", NFT_PATH, e))?;
+    if exists {
+        cmd!(NFT_PATH, "delete table {} {}", family, table)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_egress_rules() {
+        let rule = EgressRule::parse("vpn.example.com:1194/udp").unwrap();
+        assert_eq!(rule, EgressRule { address: "vpn.example.com".to_string(), port: Some(1194), protocol: Some(Protocol::Udp) });
+        let rule = EgressRule::parse("10.8.0.0/16").unwrap();
+        assert_eq!(rule, EgressRule { address: "10.8.0.0/16".to_string(), port: None, protocol: None });
+        assert!(EgressRule::parse("10.8.0.0/16:443/tcp").is_ok());
+        assert!(EgressRule::parse("10.8.0.0/33").is_err());
+        assert!(EgressRule::parse("host.example.com/tcp").is_err());
+        assert!(EgressRule::parse("host:http").is_err());
+        assert!(EgressRule::parse("bad host").is_err());
+
+        let rules = vec![EgressRule::parse("10.8.0.0/16").unwrap(), EgressRule::parse("192.168.1.1:1194").unwrap()];
+        let firewall = RealmFirewall::new("main", "172.17.0.2".parse().unwrap(), EgressPolicy::Allowlist, rules);
+        let ruleset = firewall.ruleset().unwrap();
+        assert!(ruleset.starts_with("table bridge realm-main {"));
+        assert!(ruleset.contains("iifname \"vb-main\" jump egress"));
+        assert!(ruleset.contains("ip daddr 10.8.0.0/16 accept"));
+        assert!(ruleset.contains("ip daddr 192.168.1.1 udp dport 1194 accept"));
+        assert!(ruleset.trim_end().ends_with("drop\n    }\n}"));
+
+        let too_long = RealmFirewall::new("much-too-long", "172.17.0.2".parse().unwrap(), EgressPolicy::DenyAll, Vec::new());
+        assert!(too_long.ruleset().is_err());
+    }
+
+    #[test]
+    fn spoofed_source_dropped() {
+        let firewall = RealmFirewall::new("main", "172.17.0.2".parse().unwrap(), EgressPolicy::Allowlist,
+                                          vec![EgressRule::parse("10.8.0.0/16").unwrap()]);
+        let ruleset = firewall.ruleset().unwrap();
+        let lines = ruleset.lines().map(|l| l.trim()).collect::<Vec<_>>();
+        let position = |rule: &str| lines.iter().position(|l| *l == rule).unwrap();
+
+        // Traffic from any other address or which is not IPv4 (such as IPv6) is dropped
+        // before replies or allowed destinations are accepted
+        let spoofed = position("ip saddr != 172.17.0.2 drop");
+        let not_ipv4 = position("ether type != ip drop");
+        let established = position("ct state established,related accept");
+        let allowed = position("ip daddr 10.8.0.0/16 accept");
+        assert!(not_ipv4 < established && spoofed < established && established < allowed);
+        assert!(position("arp saddr ip { 0.0.0.0, 172.17.0.2 } accept") < not_ipv4);
+    }
+
+    #[test]
+    fn zone_ruleset() {
+        let zone = toml::from_str::<NetworkZone>("name = \"vpn\"\nnetwork = \"172.18.0.0/24\"\nupstream = \"tun0\"").unwrap();
+        let ruleset = ZoneFirewall::new(&zone).ruleset().unwrap().unwrap();
+        assert!(ruleset.starts_with("table inet zone-vpn {"));
+        assert!(ruleset.contains("iifname \"vz-vpn\" oifname != \"tun0\" drop"));
+        assert!(ruleset.contains("ip saddr 172.18.0.0/24 oifname \"tun0\" masquerade"));
+        assert!(ZoneFirewall::new(&NetworkZone::clear()).ruleset().unwrap().is_none());
+    }
+}
@@ -1,13 +1,15 @@
 use std::collections::HashSet;
 use std::fs;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::{Mountpoint, Activation,Result, Realms, RealmFS, Realm, util};
+use crate::{Mountpoint, Activation,Result, Realms, RealmFS, Realm, util, Provenance, RealmFirewall, DeviceSpec};
 use crate::realmfs::realmfs_set::RealmFSSet;
+use crate::realmfs::image_lock::ImageLock;
 
 use super::systemd::Systemd;
-use super::network::NetworkConfig;
+use super::network::{NetworkConfig,NetworkZone};
 use super::events::{RealmEventListener, RealmEvent};
 use crate::realm::realms::HasCurrentChanged;
 
@@ -35,7 +37,13 @@ impl RealmManager {
 
     fn create_network_config() -> Result<NetworkConfig> {
         let mut network = NetworkConfig::new();
-        network.add_bridge("clear", "172.17.0.0/24")?;
+        network.add_zone(NetworkZone::clear())?;
+        for zone in NetworkZone::load_zones() {
+            let name = zone.name().to_string();
+            if let Err(e) = network.add_zone(zone) {
+                warn!("Failed to add network zone '{}': {}", name, e);
+            }
+        }
         Ok(network)
     }
 
@@ -117,6 +125,59 @@ impl RealmManager {
         self.systemd.machinectl_copy_to(realm, from.as_ref(), to.as_ref())
     }
 
+    ///
+    /// Make the device node `device` (such as a USB storage device) available inside
+    /// the running `realm` without restarting it. The device remains attached until
+    /// it is detached with `detach_device()` or the realm is stopped. A device can only
+    /// be attached to one realm at a time.
+    ///
+    /// Only removable block devices and devices matching the `devices` list in the
+    /// realm config can be attached.
+    ///
+    pub fn attach_device(&self, realm: &Realm, device: &Path) -> Result<()> {
+        if !realm.is_active() {
+            bail!("Cannot attach device to realm-{} because it is not running", realm.name());
+        }
+        let device = device.canonicalize()
+            .map_err(|e| format_err!("device {} not found: {}", device.display(), e))?;
+        let file_type = device.metadata()?.file_type();
+        if !device.starts_with("/dev") || !(file_type.is_block_device() || file_type.is_char_device()) {
+            bail!("{} is not a device node", device.display());
+        }
+        if !self.is_attachable_device(realm, &device)? {
+            bail!("{} is not a removable block device or a device configured for realm-{}", device.display(), realm.name());
+        }
+        for r in self.active_realms(false) {
+            if r.attached_devices().contains(&device) {
+                bail!("Device {} is already attached to realm-{}", device.display(), r.name());
+            }
+        }
+        info!("Attaching device {} to realm-{}", device.display(), realm.name());
+        self.systemd.attach_device(realm, &device)?;
+        let mut attached = realm.attached_devices();
+        attached.push(device);
+        realm.set_attached_devices(&attached)
+    }
+
+    fn is_attachable_device(&self, realm: &Realm, device: &Path) -> Result<bool> {
+        let mut allowed = DeviceSpec::removable_block_devices()?;
+        allowed.extend(self.systemd.realm_device_nodes(realm));
+        Ok(allowed.iter().any(|node| node.canonicalize().map(|n| n == device).unwrap_or(false)))
+    }
+
+    /// Remove a device which was attached to the running `realm` with `attach_device()`.
+    pub fn detach_device(&self, realm: &Realm, device: &Path) -> Result<()> {
+        let device = device.canonicalize().unwrap_or_else(|_| device.to_path_buf());
+        let mut attached = realm.attached_devices();
+        if !attached.contains(&device) {
+            bail!("Device {} is not attached to realm-{}", device.display(), realm.name());
+        }
+        attached.retain(|d| *d != device);
+        info!("Detaching device {} from realm-{}", device.display(), realm.name());
+        self.systemd.detach_device(realm, &device, &attached)?;
+        realm.set_attached_devices(&attached)
+    }
+
     pub fn realm_list(&self) -> Vec<Realm> {
         self.inner_mut().realms.sorted()
     }
@@ -296,6 +357,11 @@ impl RealmManager {
         // XXX do something to detect realmfs/overlay that is not cleaned up
         realm.set_active(false);
 
+        // Realm may have exited without stop_realm() being called
+        if let Err(e) = RealmFirewall::remove(realm.name()) {
+            warn!("error removing egress firewall rules for realm-{}: {}", realm.name(), e);
+        }
+
         if realm.is_current() {
             self.choose_some_current_realm();
         }
@@ -359,6 +425,7 @@ impl RealmManager {
         if realmfs.is_in_use() {
             bail!("Cannot delete realmfs because it is in use");
         }
+        let _lock = ImageLock::acquire(realmfs.name(), "delete")?;
         realmfs.deactivate()?;
         if realmfs.is_activated() {
             bail!("Unable to deactive Realmfs, cannot delete");
@@ -366,6 +433,7 @@ impl RealmManager {
         self.inner_mut().realmfs_set.remove(realmfs.name());
         info!("Removing RealmFS image file {}", realmfs.path().display());
         fs::remove_file(realmfs.path())?;
+        Provenance::remove(realmfs.path())?;
         Ok(())
     }
 }
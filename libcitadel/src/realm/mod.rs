@@ -8,6 +8,8 @@ pub(crate) mod realm;
 pub (crate) mod network;
 pub(crate) mod create;
 pub(crate) mod events;
+pub(crate) mod devices;
+pub(crate) mod firewall;
 mod systemd;
 
 pub(crate) use self::network::BridgeAllocator;
@@ -8,22 +8,168 @@ use crate::Result;
 
 const REALMS_RUN_PATH: &str = "/run/citadel/realms";
 
+const CLEAR_ZONE: &str = "clear";
 const CLEAR_BRIDGE_NETWORK: &str = "172.17.0.0/24";
 
+const NETWORK_ZONES_PATH: &str = "/storage/realms/network-zones";
+
+// Bridge interfaces are named vz-$zone and interface names are limited to 15 characters
+const MAX_ZONE_NAME: usize = 12;
+
 const MIN_MASK: usize = 16;
 const MAX_MASK: usize = 24;
 const RESERVED_START: u8 = 200;
 
+///
+/// A network zone which realms can be connected to with the `network-zone` config key.
+///
+/// Each zone has its own bridge (`vz-$name`) created by systemd-nspawn and its own
+/// subnet from which addresses are allocated to realms. The `clear` zone always
+/// exists, and additional zones are declared in /storage/realms/network-zones:
+///
+/// ```text
+/// [[zone]]
+/// name = "vpn"
+/// network = "172.18.0.0/24"
+/// upstream = "tun0"
+/// ```
+///
+/// If `upstream` is set, traffic from the zone is only forwarded to and from that
+/// interface, so that realms in a zone for a VPN cannot reach the network when the
+/// VPN is down.
+///
+#[derive(Deserialize,Clone,Debug,PartialEq)]
+pub struct NetworkZone {
+    name: String,
+    network: String,
+    upstream: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NetworkZonesFile {
+    #[serde(default)]
+    zone: Vec<NetworkZone>,
+}
+
+impl NetworkZone {
+
+    pub fn clear() -> Self {
+        NetworkZone {
+            name: CLEAR_ZONE.to_string(),
+            network: CLEAR_BRIDGE_NETWORK.to_string(),
+            upstream: None,
+        }
+    }
+
+    /// Load the additional zones declared in /storage/realms/network-zones. Zones
+    /// which are not valid are logged and ignored.
+    pub fn load_zones() -> Vec<NetworkZone> {
+        let path = Path::new(NETWORK_ZONES_PATH);
+        if !path.exists() {
+            return Vec::new();
+        }
+        match fs::read_to_string(path).map_err(|e| e.into()).and_then(|s| Self::parse_zones(&s)) {
+            Ok(zones) => zones,
+            Err(e) => {
+                warn!("Error loading network zones from {}: {}", path.display(), e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn parse_zones(s: &str) -> Result<Vec<NetworkZone>> {
+        let file = toml::from_str::<NetworkZonesFile>(s)?;
+        let mut zones: Vec<NetworkZone> = Vec::new();
+        for zone in file.zone {
+            if let Err(e) = zone.validate() {
+                warn!("Ignoring network zone '{}': {}", zone.name, e);
+            } else if zones.iter().any(|z| z.name == zone.name) {
+                warn!("Ignoring duplicate network zone '{}'", zone.name);
+            } else if let Some(other) = zones.iter().chain(Some(&Self::clear())).find(|z| z.overlaps(&zone)) {
+                warn!("Ignoring network zone '{}': network {} overlaps network {} of zone '{}'", zone.name, zone.network, other.network, other.name);
+            } else {
+                zones.push(zone);
+            }
+        }
+        Ok(zones)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name == CLEAR_ZONE {
+            bail!("the {} zone cannot be redefined", CLEAR_ZONE);
+        }
+        if self.name.is_empty() || self.name.len() > MAX_ZONE_NAME {
+            bail!("zone name must be between 1 and {} characters", MAX_ZONE_NAME);
+        }
+        if !self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            bail!("zone name may only contain letters, numbers and '-'");
+        }
+        parse_network(&self.network)?;
+        if let Some(ref upstream) = self.upstream {
+            if upstream.is_empty() || upstream.len() > 15 || upstream.contains(|c: char| c.is_whitespace() || c == '"' || c == '/') {
+                bail!("invalid upstream interface name '{}'", upstream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return `true` if the network of this zone shares any addresses with the network of `other`.
+    pub fn overlaps(&self, other: &NetworkZone) -> bool {
+        match (network_range(&self.network), network_range(&other.network)) {
+            (Ok((start, end)), Ok((other_start, other_end))) => start <= other_end && other_start <= end,
+            _ => false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    pub fn upstream(&self) -> Option<&str> {
+        self.upstream.as_deref()
+    }
+
+    /// Name of the bridge interface systemd-nspawn creates for this zone.
+    pub fn bridge_name(&self) -> String {
+        format!("vz-{}", self.name)
+    }
+
+    /// `true` for the zone which exists by default
+    pub fn is_clear(&self) -> bool {
+        self.name == CLEAR_ZONE
+    }
+}
+
 /// Manage ip address assignment for bridges
 pub struct NetworkConfig {
     allocators: HashMap<String, BridgeAllocator>,
+    zones: HashMap<String, NetworkZone>,
 }
 
 impl NetworkConfig {
     pub fn new() -> NetworkConfig {
         NetworkConfig {
             allocators: HashMap::new(),
+            zones: HashMap::new(),
+        }
+    }
+
+    /// Add a network zone and the bridge allocator for the subnet of the zone.
+    pub fn add_zone(&mut self, zone: NetworkZone) -> Result<()> {
+        if let Some(other) = self.zones.values().find(|z| z.overlaps(&zone)) {
+            bail!("network {} of zone '{}' overlaps network {} of zone '{}'", zone.network(), zone.name(), other.network(), other.name());
         }
+        self.add_bridge(zone.name(), zone.network())?;
+        self.zones.insert(zone.name().to_owned(), zone);
+        Ok(())
+    }
+
+    pub fn zone(&self, name: &str) -> Option<&NetworkZone> {
+        self.zones.get(name)
     }
 
     pub fn add_bridge(&mut self, name: &str, network: &str) -> Result<()> {
@@ -54,6 +200,21 @@ impl NetworkConfig {
         }
     }
 
+    /// The gateway address of `bridge` with the prefix length of the bridge network,
+    /// such as "172.17.0.1/24".
+    pub fn gateway_cidr(&self, bridge: &str) -> Result<String> {
+        match self.allocators.get(bridge) {
+            Some(allocator) => Ok(format!("{}/{}", allocator.gateway(), allocator.mask_size)),
+            None => bail!("Failed to return gateway address for bridge {} because it does not exist", bridge),
+        }
+    }
+
+    /// The address currently allocated to `realm_name` on `bridge`, if any.
+    pub fn allocated_address(&self, bridge: &str, realm_name: &str) -> Option<Ipv4Addr> {
+        self.allocators.get(bridge)
+            .and_then(|allocator| allocator.allocation_for(realm_name))
+    }
+
     pub fn allocate_reserved(&mut self, bridge: &str, realm_name: &str, octet: u8) -> Result<String> {
         match self.allocators.get_mut(bridge) {
             Some(allocator) => allocator.allocate_reserved(realm_name, octet),
@@ -83,28 +244,11 @@ impl BridgeAllocator {
 
 
     pub fn default_bridge() -> Result<BridgeAllocator> {
-        BridgeAllocator::for_bridge("clear", CLEAR_BRIDGE_NETWORK)
+        BridgeAllocator::for_bridge(CLEAR_ZONE, CLEAR_BRIDGE_NETWORK)
     }
 
     pub fn for_bridge(bridge: &str, network: &str) -> Result<BridgeAllocator> {
-        let (addr_str, mask_size) = match network.find('/') {
-            Some(idx) => {
-                let (net,bits) = network.split_at(idx);
-                (net.to_owned(), bits[1..].parse()?)
-            },
-            None => (network.to_owned(), 24),
-        };
-        if mask_size > MAX_MASK || mask_size < MIN_MASK {
-            bail!("Unsupported network mask size of {}", mask_size);
-        }
-        
-        let mask = (1u32 << (32 - mask_size)) - 1;
-        let ip = addr_str.parse::<Ipv4Addr>()?;
-
-        if (u32::from(ip) & mask) != 0 {
-            bail!("network {} has masked bits with netmask /{}", addr_str, mask_size);
-        }
-
+        let (ip, mask_size) = parse_network(network)?;
         let mut conf = BridgeAllocator::new(bridge, ip, mask_size);
         conf.load_state()?;
         Ok(conf)
@@ -134,6 +278,10 @@ impl BridgeAllocator {
 
     }
 
+    pub fn allocation_for(&self, realm_name: &str) -> Option<Ipv4Addr> {
+        self.allocations.get(realm_name).cloned()
+    }
+
     fn store_allocation(&mut self, realm_name: &str, address: Ipv4Addr) -> Result<()> {
         self.allocated.insert(address);
         if let Some(old) = self.allocations.insert(realm_name.to_string(), address) {
@@ -238,3 +386,81 @@ impl BridgeAllocator {
         Ok(())
     }
 }
+
+// Parse a network address such as "172.17.0.0/24" into the address and mask size. If
+// no mask size is given a /24 network is assumed.
+fn parse_network(network: &str) -> Result<(Ipv4Addr, usize)> {
+    let (addr_str, mask_size) = match network.find('/') {
+        Some(idx) => {
+            let (net,bits) = network.split_at(idx);
+            (net.to_owned(), bits[1..].parse()?)
+        },
+        None => (network.to_owned(), 24),
+    };
+    if !(MIN_MASK..=MAX_MASK).contains(&mask_size) {
+        bail!("Unsupported network mask size of {}", mask_size);
+    }
+
+    let mask = (1u32 << (32 - mask_size)) - 1;
+    let ip = addr_str.parse::<Ipv4Addr>()?;
+
+    if (u32::from(ip) & mask) != 0 {
+        bail!("network {} has masked bits with netmask /{}", addr_str, mask_size);
+    }
+    Ok((ip, mask_size))
+}
+
+// The first and last address of `network`
+fn network_range(network: &str) -> Result<(u32, u32)> {
+    let (ip, mask_size) = parse_network(network)?;
+    let start = u32::from(ip);
+    Ok((start, start | ((1u32 << (32 - mask_size)) - 1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_zones() {
+        let zones = NetworkZone::parse_zones(r#"
+            [[zone]]
+            name = "vpn"
+            network = "172.18.0.0/24"
+            upstream = "tun0"
+
+            [[zone]]
+            name = "clear"
+            network = "172.19.0.0/24"
+
+            [[zone]]
+            name = "much-too-long-name"
+            network = "172.20.0.0/24"
+
+            [[zone]]
+            name = "lab"
+            network = "172.21.0.0/24"
+
+            [[zone]]
+            name = "overlap"
+            network = "172.17.0.0/16"
+
+            [[zone]]
+            name = "lab2"
+            network = "172.21.0.128/25"
+
+            [[zone]]
+            name = "badnet"
+            network = "172.22.0.1/24"
+        "#).unwrap();
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones[0].name(), "vpn");
+        assert_eq!(zones[0].upstream(), Some("tun0"));
+        assert_eq!(zones[0].bridge_name(), "vz-vpn");
+        assert_eq!(zones[1].name(), "lab");
+        assert_eq!(zones[1].upstream(), None);
+        assert!(!zones[0].overlaps(&NetworkZone::clear()));
+        assert!(NetworkZone::parse_zones("[[zone]]\nname = \"wide\"\nnetwork = \"172.17.0.128/25\"").unwrap().is_empty());
+        assert!(NetworkZone::parse_zones("").unwrap().is_empty());
+    }
+}
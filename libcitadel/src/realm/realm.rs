@@ -11,10 +11,12 @@ use super::realms::Realms;
 use super::systemd::Systemd;
 
 use crate::realmfs::{Mountpoint, Activation};
-use crate::{symlink, util, Result, RealmFS, CommandLine, RealmManager};
+use crate::{symlink, util, Result, RealmFS, CommandLine, RealmManager, NoProgress};
 
 
 const MAX_REALM_NAME_LEN:usize = 128;
+const ATTACHED_DEVICES_FILE: &str = "attached-devices";
+const DEVICE_NODES_FILE: &str = "device-nodes";
 const ALWAYS_LOAD_TIMESTAMP: bool = true;
 
 #[derive(Clone,Copy,PartialEq)]
@@ -255,6 +257,13 @@ impl Realm {
         Self::remove_symlink(self.rootfs_symlink());
         Self::remove_symlink(self.run_path().join("home"));
 
+        if let Err(e) = self.set_attached_devices(&[]) {
+            warn!("failed to remove list of attached devices: {}", e);
+        }
+        if let Err(e) = self.set_device_nodes(&[]) {
+            warn!("failed to remove list of device nodes: {}", e);
+        }
+
         if let Err(e) = fs::remove_dir(self.run_path()) {
             warn!("failed to remove run directory {}: {}", self.run_path().display(), e);
         }
@@ -299,7 +308,7 @@ impl Realm {
         if name == default.name() {
             Ok(default)
         } else {
-            default.fork(name)
+            default.fork(name, &mut NoProgress)
         }
     }
 
@@ -317,7 +326,7 @@ impl Realm {
         } else if let Some(base) = manager.realmfs_by_name("base") {
             // If default image name is something other than 'base' and does
             // not exist, create it as a fork of 'base'
-            base.fork(default)
+            base.fork(default, &mut NoProgress)
         } else {
             Err(format_err!("Default RealmFS '{}' does not exist and neither does 'base'", default))
         }
@@ -357,6 +366,47 @@ impl Realm {
         self.run_path().join(name)
     }
 
+    /// Device nodes which have been attached to this running realm with
+    /// `RealmManager::attach_device()`.
+    pub fn attached_devices(&self) -> Vec<PathBuf> {
+        self.read_path_list(ATTACHED_DEVICES_FILE)
+    }
+
+    pub(crate) fn set_attached_devices(&self, devices: &[PathBuf]) -> Result<()> {
+        self.write_path_list(ATTACHED_DEVICES_FILE, devices)
+    }
+
+    /// Device nodes for the devices in the realm config which were found when
+    /// this realm was started.
+    pub(crate) fn device_nodes(&self) -> Vec<PathBuf> {
+        self.read_path_list(DEVICE_NODES_FILE)
+    }
+
+    pub(crate) fn set_device_nodes(&self, devices: &[PathBuf]) -> Result<()> {
+        self.write_path_list(DEVICE_NODES_FILE, devices)
+    }
+
+    fn read_path_list(&self, name: &str) -> Vec<PathBuf> {
+        fs::read_to_string(self.run_path_file(name))
+            .map(|s| s.lines().map(PathBuf::from).collect())
+            .unwrap_or_default()
+    }
+
+    fn write_path_list(&self, name: &str, devices: &[PathBuf]) -> Result<()> {
+        let path = self.run_path_file(name);
+        if devices.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let content = devices.iter()
+            .map(|d| format!("{}\n", d.display()))
+            .collect::<String>();
+        fs::write(path, content)?;
+        Ok(())
+    }
+
     /// Return `Arc<RealmConfig>` containing the configuration of this realm.
     /// If the config file has not yet been loaded from disk, it is lazy loaded
     /// the first time this method is called.
@@ -8,13 +8,17 @@ const SYSTEMCTL_PATH: &str = "/usr/bin/systemctl";
 const MACHINECTL_PATH: &str = "/usr/bin/machinectl";
 const SYSTEMD_NSPAWN_PATH: &str = "/run/systemd/nspawn";
 const SYSTEMD_UNIT_PATH: &str = "/run/systemd/system";
+const SYSTEMD_NETWORK_PATH: &str = "/run/systemd/network";
+const NETWORKCTL_PATH: &str = "/usr/bin/networkctl";
+const NSENTER_PATH: &str = "/usr/bin/nsenter";
 
 use crate::Result;
 
-use crate::Realm;
+use crate::{Realm,ResourceLimit,DeviceSpec,EgressPolicy,EgressRule,RealmFirewall};
 use std::sync::Mutex;
 use std::process::Stdio;
 use crate::realm::network::NetworkConfig;
+use crate::realm::firewall::ZoneFirewall;
 
 pub struct Systemd {
     network: Mutex<NetworkConfig>,
@@ -29,6 +33,12 @@ impl Systemd {
 
     pub fn start_realm(&self, realm: &Realm, rootfs: &Path) -> Result<()> {
         self.write_realm_launch_config(realm, rootfs)?;
+        if let Err(e) = self.setup_network_zone(realm).and_then(|_| self.install_firewall(realm)) {
+            // Do not start a realm without the network policy it is configured with
+            self.remove_realm_launch_config(realm)?;
+            self.network.lock().unwrap().free_allocation_for(realm.config().network_zone(), realm.name())?;
+            return Err(e);
+        }
         self.systemctl_start(&self.realm_service_name(realm))?;
         if realm.config().ephemeral_home() {
             self.setup_ephemeral_home(realm)?;
@@ -60,7 +70,10 @@ impl Systemd {
                 let src = src.canonicalize()?;
                 if src.starts_with(&home) && src.exists() {
                     let dst = Path::new("/home/user").join(&dir);
-                    self.machinectl_bind(realm, &src, &dst)?;
+                    // A missing persistent directory should not prevent the realm from starting
+                    if let Err(e) = self.machinectl_bind(realm, &src, &dst) {
+                        warn!("failed to bind {} into realm-{}: {}", dst.display(), realm.name(), e);
+                    }
                 }
             }
         }
@@ -68,9 +81,74 @@ impl Systemd {
         Ok(())
     }
 
+    // Configure the host side of the bridge for the network zone of the realm. The
+    // default zone is configured by the system, but for additional zones a networkd
+    // config file is written which assigns the gateway address to the bridge.
+    fn setup_network_zone(&self, realm: &Realm) -> Result<()> {
+        let config = realm.config();
+        if !config.network() || config.has_netns() {
+            return Ok(());
+        }
+        let netconf = self.network.lock().unwrap();
+        let zone = match netconf.zone(config.network_zone()) {
+            Some(zone) if !zone.is_clear() => zone,
+            _ => return Ok(()),
+        };
+
+        let mut s = String::new();
+        writeln!(s, "[Match]")?;
+        writeln!(s, "Name={}", zone.bridge_name())?;
+        writeln!(s, "Driver=bridge")?;
+        writeln!(s)?;
+        writeln!(s, "[Network]")?;
+        writeln!(s, "Address={}", netconf.gateway_cidr(zone.name())?)?;
+        writeln!(s, "LinkLocalAddressing=no")?;
+        writeln!(s, "IPForward=yes")?;
+        if zone.upstream().is_none() {
+            writeln!(s, "IPMasquerade=yes")?;
+        }
+
+        let path = Path::new(SYSTEMD_NETWORK_PATH).join(format!("80-citadel-zone-{}.network", zone.name()));
+        if fs::read_to_string(&path).ok().as_ref() != Some(&s) {
+            self.write_launch_config_file(&path, &s)
+                .map_err(|e| format_err!("failed to write network config file {}: {}", path.display(), e))?;
+            cmd!(NETWORKCTL_PATH, "reload")?;
+        }
+
+        ZoneFirewall::new(zone).install()
+    }
+
+    // Install nftables rules enforcing the egress policy of the realm on the address
+    // allocated to it by `generate_network_config()`.
+    fn install_firewall(&self, realm: &Realm) -> Result<()> {
+        let config = realm.config();
+        let policy = config.egress_policy();
+        if policy == EgressPolicy::AllowAll || !config.network() {
+            return Ok(());
+        }
+        if config.has_netns() {
+            // The rules cannot be applied to a realm in another network namespace, so fail closed
+            bail!("Cannot apply egress policy of realm-{} because it uses network namespace {}",
+                  realm.name(), config.netns().unwrap_or_default());
+        }
+        let rules = config.egress_allow().iter()
+            .map(|s| EgressRule::parse(s))
+            .collect::<Result<Vec<_>>>()?;
+
+        let address = self.network.lock().unwrap()
+            .allocated_address(config.network_zone(), realm.name())
+            .ok_or_else(|| format_err!("no address allocated for realm-{}", realm.name()))?;
+
+        RealmFirewall::new(realm.name(), address, policy, rules).install()
+    }
+
     pub fn stop_realm(&self, realm: &Realm) -> Result<()> {
         self.systemctl_stop(&self.realm_service_name(realm))?;
         self.remove_realm_launch_config(realm)?;
+        // Remove the rules without returning early so the address allocation is still freed
+        if let Err(e) = RealmFirewall::remove(realm.name()) {
+            warn!("error removing egress firewall rules for realm-{}: {}", realm.name(), e);
+        }
 
         let mut network = self.network.lock().unwrap();
         network.free_allocation_for(realm.config().network_zone(), realm.name())?;
@@ -111,10 +189,13 @@ impl Systemd {
     fn machinectl_bind(&self, realm: &Realm, from: &Path, to: &Path) -> Result<()> {
         let from = from.display().to_string();
         let to = to.display().to_string();
-        Command::new(MACHINECTL_PATH)
+        let status = Command::new(MACHINECTL_PATH)
             .args(&["--mkdir", "bind", realm.name(), from.as_str(), to.as_str() ])
             .status()
             .map_err(|e| format_err!("failed to machinectl bind {} {} {}: {}", realm.name(), from, to, e))?;
+        if !status.success() {
+            bail!("machinectl bind of {} to {} in realm-{} failed", from, to, realm.name());
+        }
         Ok(())
     }
 
@@ -205,17 +286,19 @@ impl Systemd {
     }
 
     fn write_realm_launch_config(&self, realm: &Realm, rootfs: &Path) -> Result<()> {
+        let devices = self.realm_device_nodes(realm);
         let nspawn_path = self.realm_nspawn_path(realm);
-        let nspawn_content = self.generate_nspawn_file(realm)?;
+        let nspawn_content = self.generate_nspawn_file(realm, &devices)?;
         self.write_launch_config_file(&nspawn_path, &nspawn_content)
             .map_err(|e| format_err!("failed to write nspawn config file {}: {}", nspawn_path.display(), e))?;
 
         let service_path = self.realm_service_path(realm);
-        let service_content = self.generate_service_file(realm, rootfs);
+        let service_content = self.generate_service_file(realm, rootfs, &devices)?;
         self.write_launch_config_file(&service_path, &service_content)
             .map_err(|e| format_err!("failed to write service config file {}: {}", service_path.display(), e))?;
 
-        Ok(())
+        // Remembered so that the device rules can be rebuilt when an attached device is removed
+        realm.set_device_nodes(&devices)
     }
 
     /// Write the string `content` to file `path`. If the directory does
@@ -233,14 +316,30 @@ impl Systemd {
         Ok(())
     }
 
-    fn generate_nspawn_file(&self, realm: &Realm) -> Result<String> {
+    // Find the device nodes for all devices listed in the realm config
+    pub(crate) fn realm_device_nodes(&self, realm: &Realm) -> Vec<PathBuf> {
+        let mut nodes = Vec::new();
+        for device in realm.config().devices() {
+            let result = DeviceSpec::parse(device).and_then(|spec| spec.device_nodes());
+            match result {
+                Ok(ref v) if v.is_empty() => info!("No device found for '{}' in realm-{}", device, realm.name()),
+                Ok(v) => nodes.extend(v),
+                Err(e) => warn!("ignoring device for realm-{}: {}", realm.name(), e),
+            }
+        }
+        nodes.sort();
+        nodes.dedup();
+        nodes
+    }
+
+    fn generate_nspawn_file(&self, realm: &Realm, devices: &[PathBuf]) -> Result<String> {
         Ok(NSPAWN_FILE_TEMPLATE
-            .replace("$EXTRA_BIND_MOUNTS", &self.generate_extra_bind_mounts(realm)?)
+            .replace("$EXTRA_BIND_MOUNTS", &self.generate_extra_bind_mounts(realm, devices)?)
             .replace("$EXTRA_FILE_OPTIONS", &self.generate_extra_file_options(realm)?)
             .replace("$NETWORK_CONFIG", &self.generate_network_config(realm)?))
     }
 
-    fn generate_extra_bind_mounts(&self, realm: &Realm) -> Result<String> {
+    fn generate_extra_bind_mounts(&self, realm: &Realm, devices: &[PathBuf]) -> Result<String> {
         let config = realm.config();
         let mut s = String::new();
 
@@ -279,6 +378,10 @@ impl Systemd {
             writeln!(s, "BindReadOnly=/run/user/1000/wayland-0:/run/user/host/wayland-0")?;
         }
 
+        for device in devices {
+            writeln!(s, "Bind={}", device.display())?;
+        }
+
         for bind in config.extra_bindmounts() {
             if self.is_valid_bind_item(bind) {
                 writeln!(s, "Bind={}", bind)?;
@@ -315,6 +418,9 @@ impl Systemd {
             }
             let mut netconf = self.network.lock().unwrap();
             let zone = config.network_zone();
+            if netconf.zone(zone).is_none() {
+                bail!("realm-{} uses network zone '{}' which is not configured", realm.name(), zone);
+            }
             let addr = if let Some(addr) = config.reserved_ip() {
                 netconf.allocate_reserved(zone, realm.name(), addr)?
             } else {
@@ -324,7 +430,7 @@ impl Systemd {
             writeln!(s, "Environment=IFCONFIG_IP={}", addr)?;
             writeln!(s, "Environment=IFCONFIG_GW={}", gw)?;
             writeln!(s, "[Network]")?;
-            writeln!(s, "Zone=clear")?;
+            writeln!(s, "Zone={}", zone)?;
         } else {
             writeln!(s, "[Network]")?;
             writeln!(s, "Private=true")?;
@@ -332,14 +438,131 @@ impl Systemd {
         Ok(s)
     }
 
-    fn generate_service_file(&self, realm: &Realm, rootfs: &Path) -> String {
+    fn generate_service_file(&self, realm: &Realm, rootfs: &Path, devices: &[PathBuf]) -> Result<String> {
         let rootfs = rootfs.display().to_string();
         let netns_arg = match realm.config().netns() {
             Some(netns) => format!("--network-namespace-path=/run/netns/{}", netns),
             None => "".into(),
         };
 
-        REALM_SERVICE_TEMPLATE.replace("$REALM_NAME", realm.name()).replace("$ROOTFS", &rootfs).replace("$NETNS_ARG", &netns_arg)
+        Ok(REALM_SERVICE_TEMPLATE.replace("$REALM_NAME", realm.name()).replace("$ROOTFS", &rootfs).replace("$NETNS_ARG", &netns_arg)
+            .replace("$RESOURCE_LIMITS", &self.generate_resource_limits(realm))
+            .replace("$DEVICE_POLICY", &self.generate_device_policy(realm, devices)?))
+    }
+
+    // When a realm has a list of devices, access to devices is restricted to the devices
+    // which are added to the realm, including those enabled by the kvm, gpu and sound options.
+    // Realms without a device list keep the default policy which does not restrict access.
+    fn generate_device_policy(&self, realm: &Realm, devices: &[PathBuf]) -> Result<String> {
+        let mut s = String::new();
+        if !self.has_device_policy(realm) {
+            return Ok(s);
+        }
+        writeln!(s, "DevicePolicy=closed")?;
+        for rule in self.device_allow_rules(realm, devices) {
+            writeln!(s, "DeviceAllow={}", rule)?;
+        }
+        Ok(s)
+    }
+
+    fn has_device_policy(&self, realm: &Realm) -> bool {
+        !realm.config().devices().is_empty()
+    }
+
+    fn device_allow_rules(&self, realm: &Realm, devices: &[PathBuf]) -> Vec<String> {
+        let config = realm.config();
+        let mut rules = vec!["/dev/net/tun rwm".to_string(), "char-pts rw".to_string()];
+
+        if config.kvm() {
+            rules.push("/dev/kvm rw".to_string());
+        }
+
+        if config.gpu() {
+            rules.push("/dev/dri/renderD128 rw".to_string());
+            if config.gpu_card0() {
+                rules.push("/dev/dri/card0 rw".to_string());
+            }
+        }
+
+        if config.sound() {
+            rules.push("char-alsa rw".to_string());
+        }
+
+        for device in devices {
+            rules.push(format!("{} rw", device.display()));
+        }
+        rules
+    }
+
+    /// Make the device node `device` available at the same path inside the running
+    /// `realm` and allow access to it if the realm has a restrictive device policy.
+    pub fn attach_device(&self, realm: &Realm, device: &Path) -> Result<()> {
+        self.machinectl_bind(realm, device, device)?;
+        if self.has_device_policy(realm) {
+            // Only add the rule once the bind succeeded, and remove the node again if it cannot be added
+            if let Err(e) = self.set_device_allow(realm, &[format!("DeviceAllow={} rw", device.display())]) {
+                if let Err(err) = self.remove_device_node(realm, device) {
+                    warn!("failed to remove {} from realm-{}: {}", device.display(), realm.name(), err);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the device node `device` which was attached with `attach_device()` from the running
+    /// `realm`. `attached` is the list of devices which remain attached to the realm.
+    pub fn detach_device(&self, realm: &Realm, device: &Path, attached: &[PathBuf]) -> Result<()> {
+        self.remove_device_node(realm, device)?;
+
+        if self.has_device_policy(realm) {
+            // Individual device rules cannot be removed, so reset the rules and add them again
+            let mut devices = realm.device_nodes();
+            devices.extend(attached.iter().cloned());
+            let mut properties = vec!["DeviceAllow=".to_string()];
+            properties.extend(self.device_allow_rules(realm, &devices).into_iter().map(|rule| format!("DeviceAllow={}", rule)));
+            self.set_device_allow(realm, &properties)?;
+        }
+        Ok(())
+    }
+
+    // Unmount and remove the device node `device` which was bound into the running `realm`
+    fn remove_device_node(&self, realm: &Realm, device: &Path) -> Result<()> {
+        let pid = match realm.leader_pid() {
+            Some(pid) => pid,
+            None => bail!("cannot find leader pid of realm-{}", realm.name()),
+        };
+        // The device may already have been unplugged and removed
+        if let Err(e) = cmd!(NSENTER_PATH, "--target {} --mount /usr/bin/umount {}", pid, device.display()) {
+            warn!("failed to unmount {} in realm-{}: {}", device.display(), realm.name(), e);
+        }
+        cmd!(NSENTER_PATH, "--target {} --mount /usr/bin/rm -f {}", pid, device.display())
+    }
+
+    fn set_device_allow(&self, realm: &Realm, properties: &[String]) -> Result<()> {
+        let status = Command::new(SYSTEMCTL_PATH)
+            .args(["set-property", "--runtime", &self.realm_service_name(realm)])
+            .args(properties)
+            .status()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        if !status.success() {
+            bail!("failed to set device rules for realm-{}", realm.name());
+        }
+        Ok(())
+    }
+
+    fn generate_resource_limits(&self, realm: &Realm) -> String {
+        let config = realm.config();
+        let mut s = String::new();
+        for &limit in ResourceLimit::all() {
+            if let Some(value) = config.resource_limit(limit) {
+                match limit.validate(&value) {
+                    Ok(()) => s.push_str(&(limit.unit_directive(&value) + "\n")),
+                    Err(e) => warn!("ignoring resource limit for realm-{}: {}", realm.name(), e),
+                }
+            }
+        }
+        s
     }
 }
 
@@ -371,4 +594,7 @@ KillMode=mixed
 Type=notify
 RestartForceExitStatus=133
 SuccessExitStatus=133
+
+$RESOURCE_LIMITS
+$DEVICE_POLICY
 "###;
@@ -1,7 +1,7 @@
 use std::collections::HashSet;
 use std::path::Path;
 
-use crate::{RealmFS, Result, ImageHeader, CommandLine, PublicKey, LoopDevice};
+use crate::{RealmFS, Result, ImageHeader, CommandLine, ChannelKeys, LoopDevice, NoProgress};
 use crate::realmfs::mountpoint::Mountpoint;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use crate::verity::Verity;
@@ -298,7 +298,7 @@ impl <'a> VerityActivator <'a> {
 
     fn generate_verity(&self) -> Result<()> {
         info!("Generating verity hash tree");
-        Verity::new(self.realmfs.path()).generate_image_hashtree(&self.header.metainfo())?;
+        Verity::new(self.realmfs.path()).generate_image_hashtree(&self.header.metainfo(), &mut NoProgress)?;
         info!("Writing header...");
         self.header.set_flag(ImageHeader::FLAG_HASH_TREE);
         self.header.write_header_to(self.realmfs.path())?;
@@ -307,24 +307,24 @@ impl <'a> VerityActivator <'a> {
     }
 
     fn verify_signature(&self) -> Result<()> {
-        let pubkey = self.public_key()?;
-        if !self.realmfs.header().verify_signature(pubkey) {
+        let keys = self.public_keys()?;
+        if !self.realmfs.header().verify_signatures(&keys) {
             bail!("header signature verification failed on realmfs image '{}'", self.realmfs.name());
         }
         info!("header signature verified on realmfs image '{}'", self.realmfs.name());
         Ok(())
     }
 
-    fn public_key(&self) -> Result<PublicKey> {
-        let pubkey = if self.realmfs.metainfo().channel() == RealmFS::USER_KEYNAME {
-            self.realmfs.sealing_keys()?.public_key()
+    fn public_keys(&self) -> Result<ChannelKeys> {
+        let keys = if self.realmfs.metainfo().channel() == RealmFS::USER_KEYNAME {
+            ChannelKeys::single(self.realmfs.sealing_keys()?.public_key())
         } else {
-            match self.realmfs.header().public_key()? {
-                Some(pubkey) => pubkey,
+            match self.realmfs.header().public_keys()? {
+                Some(keys) => keys,
                 None => bail!("No public key available for channel {}", self.realmfs.metainfo().channel()),
             }
         };
-        Ok(pubkey)
+        Ok(keys)
     }
 }
 
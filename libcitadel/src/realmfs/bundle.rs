@@ -0,0 +1,412 @@
+use std::fs::{self,File};
+use std::io::{self,BufReader,BufWriter,Read,Write};
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime,UNIX_EPOCH};
+
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::{Result,RealmFS,RealmManager,Compression,Progress,ProgressReader,UtsName,Provenance};
+use super::image_lock::ImageLock;
+
+// Version of the bundle format written by export()
+const BUNDLE_VERSION: u32 = 1;
+
+// Names of the files stored inside a bundle
+const BUNDLE_INFO: &str = "bundle.toml";
+const BUNDLE_IMAGE: &str = "image.zst";
+const BUNDLE_PROVENANCE: &str = "provenance.toml";
+
+const TAR: &str = "/usr/bin/tar";
+
+///
+/// A RealmFS image packaged as a single file so that it can be moved to another machine.
+///
+/// Sealed RealmFS images are signed with the `realmfs-user` key which is generated
+/// when Citadel is installed, so a sealed image copied from another machine will not
+/// verify. A bundle is an uncompressed tar archive which contains a zstd compressed
+/// copy of the image (without the dm-verity hash tree), the provenance file of the
+/// image if it has one, and a small TOML file (`bundle.toml`) describing the image
+/// and where it came from:
+///
+/// ```text
+/// bundle-version = 1
+/// realmfs-name = "main"
+/// version = 3
+/// sealed = true
+/// channel = "realmfs-user"
+/// verity-root = "..."
+/// exported-from = "citadel"
+/// exported-at = 1571322245
+/// image-size = 4294971392
+/// image-sha256 = "..."
+/// provenance-sha256 = "..."
+/// metainfo = "..."
+/// ```
+///
+/// When a bundle is imported the image is verified against the recorded size and
+/// hash, and images which were sealed when they were exported are sealed again
+/// with the local sealing keys.
+///
+pub struct RealmFSBundle;
+
+/// The description of the image stored in a bundle.
+#[derive(Serialize,Deserialize)]
+pub struct BundleInfo {
+    #[serde(rename = "bundle-version")]
+    bundle_version: u32,
+    #[serde(rename = "realmfs-name")]
+    name: String,
+    version: u32,
+    sealed: bool,
+    channel: Option<String>,
+    #[serde(rename = "verity-root")]
+    verity_root: Option<String>,
+    #[serde(rename = "recipe-hash")]
+    recipe_hash: Option<String>,
+    #[serde(rename = "exported-from")]
+    exported_from: String,
+    #[serde(rename = "exported-at")]
+    exported_at: u64,
+    #[serde(rename = "image-size")]
+    image_size: u64,
+    #[serde(rename = "image-sha256")]
+    image_sha256: String,
+    #[serde(rename = "provenance-sha256")]
+    provenance_sha256: Option<String>,
+    metainfo: String,
+}
+
+impl BundleInfo {
+    fn new(realmfs: &RealmFS, image_size: u64, image_sha256: String, provenance_sha256: Option<String>) -> Result<Self> {
+        let metainfo = realmfs.metainfo();
+        let non_empty = |s: &str| if s.is_empty() { None } else { Some(s.to_string()) };
+        Ok(BundleInfo {
+            bundle_version: BUNDLE_VERSION,
+            name: realmfs.name().to_string(),
+            version: metainfo.version(),
+            sealed: realmfs.is_sealed(),
+            channel: non_empty(metainfo.channel()),
+            verity_root: non_empty(metainfo.verity_root()),
+            recipe_hash: metainfo.recipe_hash().map(|s| s.to_string()),
+            exported_from: UtsName::uname().nodename().to_string(),
+            exported_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            image_size,
+            image_sha256,
+            provenance_sha256,
+            metainfo: String::from_utf8(realmfs.header().metainfo_bytes())?,
+        })
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        let info = toml::from_str::<BundleInfo>(s)
+            .map_err(|e| format_err!("failed to parse {}: {}", BUNDLE_INFO, e))?;
+        if info.bundle_version != BUNDLE_VERSION {
+            bail!("unsupported bundle version {}", info.bundle_version);
+        }
+        if !RealmFS::is_valid_name(&info.name) {
+            bail!("bundle has invalid RealmFS name '{}'", info.name);
+        }
+        Ok(info)
+    }
+
+    /// Name of the exported RealmFS image.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Return `true` if the image was sealed when it was exported.
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// The dm-verity root hash of the image if it was sealed when it was exported.
+    pub fn verity_root(&self) -> Option<&str> {
+        self.verity_root.as_deref()
+    }
+
+    /// Host name of the machine the image was exported from.
+    pub fn exported_from(&self) -> &str {
+        &self.exported_from
+    }
+
+    /// Time the image was exported in seconds since the epoch.
+    pub fn exported_at(&self) -> u64 {
+        self.exported_at
+    }
+}
+
+impl RealmFSBundle {
+
+    ///
+    /// Export `realmfs` to a new bundle file at `target`.
+    ///
+    /// An unsealed image cannot be exported while it is activated since it
+    /// may be modified during the export, and it is locked during the export.
+    ///
+    pub fn export(realmfs: &RealmFS, target: &Path, progress: &mut dyn Progress) -> Result<()> {
+        if target.exists() {
+            bail!("Cannot export RealmFS because {} already exists", target.display());
+        }
+        let _lock = if realmfs.is_sealed() {
+            None
+        } else {
+            Some(ImageLock::acquire(realmfs.name(), "export")?)
+        };
+        if !realmfs.is_sealed() && realmfs.is_activated() {
+            bail!("Cannot export unsealed RealmFS '{}' because it is currently activated", realmfs.name());
+        }
+
+        let staging = Self::staging_dir(target);
+        let result = Self::stage_export(realmfs, &staging, progress).and_then(|files|
+            cmd!(TAR, "-C {} -cf {} {}", staging.display(), target.display(), files.join(" ")));
+
+        Self::remove_staging(&staging);
+        if result.is_err() && target.exists() {
+            let _ = fs::remove_file(target);
+        }
+        result
+    }
+
+    // Write the files of the bundle to `staging` and return the list of file names
+    fn stage_export(realmfs: &RealmFS, staging: &Path, progress: &mut dyn Progress) -> Result<Vec<&'static str>> {
+        fs::create_dir_all(staging)?;
+
+        // The dm-verity hash tree is not exported since it is generated again when the
+        // image is sealed with the sealing keys of the machine importing the bundle.
+        let image_size = (realmfs.metainfo_nblocks() * 4096) as u64;
+        info!("Compressing RealmFS image {}", realmfs.path().display());
+        progress.begin("Compressing RealmFS image", image_size);
+        let file = File::open(realmfs.path())?;
+        let mut reader = Sha256Reader::new(ProgressReader::new(file.take(image_size), progress));
+        let n = Compression::Zstd.compress_to(&mut reader, staging.join(BUNDLE_IMAGE))?;
+        let image_sha256 = reader.finish();
+        progress.end();
+        if n != image_size {
+            bail!("RealmFS image file {} is shorter than expected", realmfs.path().display());
+        }
+
+        let mut files = vec![BUNDLE_INFO, BUNDLE_IMAGE];
+        let provenance = Provenance::path_for(realmfs.path());
+        let provenance_sha256 = if provenance.exists() {
+            let bytes = fs::read(&provenance)?;
+            fs::write(staging.join(BUNDLE_PROVENANCE), &bytes)?;
+            files.push(BUNDLE_PROVENANCE);
+            Some(hash_bytes(&bytes))
+        } else {
+            None
+        };
+
+        let info = BundleInfo::new(realmfs, image_size, image_sha256, provenance_sha256)?;
+        fs::write(staging.join(BUNDLE_INFO), toml::to_string(&info)?)?;
+        Ok(files)
+    }
+
+    /// Read the description of the image stored in the bundle file at `path`.
+    pub fn read_info(path: &Path) -> Result<BundleInfo> {
+        if !path.exists() {
+            bail!("Bundle file {} does not exist", path.display());
+        }
+        let s = cmd_with_output!(TAR, "-xOf {} {}", path.display(), BUNDLE_INFO)
+            .map_err(|e| format_err!("bundle does not contain {}: {}", BUNDLE_INFO, e))?;
+        BundleInfo::parse(&s)
+    }
+
+    ///
+    /// Import the bundle file at `path` as a new RealmFS image in the default image
+    /// directory, optionally with a new name, and add it to `manager`.
+    ///
+    /// Images which were sealed when they were exported are sealed again with the
+    /// local sealing keys. Nothing in a bundle proves which machine it was exported
+    /// from, so the caller must decide whether the image is trusted before importing
+    /// it, for example by displaying the information returned by `read_info()`.
+    ///
+    pub fn import(path: &Path, new_name: Option<&str>, manager: &Arc<RealmManager>, progress: &mut dyn Progress) -> Result<RealmFS> {
+        let info = Self::read_info(path)?;
+        let name = new_name.unwrap_or(&info.name);
+        if !RealmFS::is_valid_name(name) {
+            bail!("Not a valid RealmFS image name '{}'", name);
+        }
+        let _lock = ImageLock::acquire(name, "import")?;
+        if manager.realmfs_name_exists(name) || RealmFS::image_path(name).exists() {
+            bail!("A RealmFS image named '{}' already exists", name);
+        }
+
+        // Holding the lock for the new name ensures no other import uses this directory
+        let staging = Path::new(RealmFS::BASE_PATH).join(format!(".import-{}", name));
+        Self::remove_staging(&staging);
+        fs::create_dir_all(&staging)?;
+
+        let result = Self::import_staged(path, &staging, &info, name, manager, progress);
+        Self::remove_staging(&staging);
+        result
+    }
+
+    fn import_staged(path: &Path, staging: &Path, info: &BundleInfo, name: &str, manager: &Arc<RealmManager>, progress: &mut dyn Progress) -> Result<RealmFS> {
+        info!("Importing RealmFS '{}' exported from {} as '{}'", info.name, info.exported_from, name);
+        cmd!(TAR, "-C {} -xf {} {}", staging.display(), path.display(), BUNDLE_IMAGE)?;
+        let image = staging.join(format!("{}-realmfs.img", name));
+        Self::extract_image(info, &staging.join(BUNDLE_IMAGE), &image, progress)?;
+        fs::remove_file(staging.join(BUNDLE_IMAGE))?;
+        Self::extract_provenance(path, staging, info, &image)?;
+
+        let mut realmfs = RealmFS::load_from_path(&image)?;
+        realmfs.set_manager(manager.clone());
+        if realmfs.name() != info.name {
+            bail!("bundle image is not an image of RealmFS '{}'", info.name);
+        }
+        if info.sealed && !realmfs.has_sealing_keys() {
+            bail!("Cannot import sealed RealmFS '{}' because no sealing keys are available", info.name);
+        }
+
+        // The imported image is still signed with the keys of the machine it was
+        // exported from, so it is unsealed and (if it was sealed) sealed again.
+        realmfs.unseal_renamed(name)?;
+        let mut realmfs = RealmFS::load_from_path(&image)?;
+        realmfs.set_manager(manager.clone());
+        if info.sealed {
+            realmfs.seal(None)?;
+            let old = realmfs.path_with_extension("old");
+            if old.exists() {
+                fs::remove_file(old)?;
+            }
+        }
+
+        // Unlike a rename, creating a link fails if the target already exists
+        let target = RealmFS::image_path(name);
+        fs::hard_link(&image, &target)
+            .map_err(|e| format_err!("failed to install imported image as {}: {}", target.display(), e))?;
+        Provenance::rename(&image, &target)?;
+        let mut realmfs = RealmFS::load_from_path(&target)?;
+        realmfs.set_manager(manager.clone());
+        manager.realmfs_added(&realmfs);
+        Ok(realmfs)
+    }
+
+    // Extract the provenance file from the bundle if it has one, verify it against the hash
+    // in the bundle info and restore it next to `image`.
+    fn extract_provenance(path: &Path, staging: &Path, info: &BundleInfo, image: &Path) -> Result<()> {
+        let expected = match info.provenance_sha256 {
+            Some(ref hash) => hash,
+            None => return Ok(()),
+        };
+        cmd!(TAR, "-C {} -xf {} {}", staging.display(), path.display(), BUNDLE_PROVENANCE)?;
+        let provenance = staging.join(BUNDLE_PROVENANCE);
+        if hash_bytes(&fs::read(&provenance)?) != *expected {
+            bail!("bundle provenance file does not match sha256 hash recorded in bundle");
+        }
+        fs::rename(&provenance, Provenance::path_for(image))?;
+        Ok(())
+    }
+
+    // Decompress the image stored in a bundle and verify the size and hash recorded in the bundle info.
+    // These only detect a damaged bundle. They are stored in the bundle itself, so they say
+    // nothing about where the image came from.
+    fn extract_image(info: &BundleInfo, compressed: &Path, image: &Path, progress: &mut dyn Progress) -> Result<()> {
+        info!("Decompressing RealmFS image");
+        let file = File::open(compressed)?;
+        progress.begin("Decompressing RealmFS image", file.metadata()?.len());
+        let decoder = Compression::Zstd.decoder(BufReader::new(ProgressReader::new(file, progress)))?;
+        let mut reader = Sha256Reader::new(decoder);
+        let mut out = BufWriter::new(File::create(image)?);
+        let n = io::copy(&mut reader, &mut out)
+            .map_err(|e| format_err!("failed to decompress bundle image: {}", e))?;
+        out.flush()?;
+        let image_sha256 = reader.finish();
+        progress.end();
+
+        if n != info.image_size {
+            bail!("bundle image has size {} but {} expected", n, info.image_size);
+        }
+        if image_sha256 != info.image_sha256 {
+            bail!("bundle image does not match sha256 hash recorded in bundle");
+        }
+        Ok(())
+    }
+
+    fn staging_dir(target: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.tmp", target.display()))
+    }
+
+    fn remove_staging(staging: &Path) {
+        if staging.exists() {
+            if let Err(e) = fs::remove_dir_all(staging) {
+                warn!("failed to remove bundle staging directory {}: {}", staging.display(), e);
+            }
+        }
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> String {
+    hex::encode(sha256::hash(bytes).as_ref())
+}
+
+// Calculates the sha256 hash of all data read through it
+struct Sha256Reader<R: Read> {
+    reader: R,
+    state: sha256::State,
+}
+
+impl <R: Read> Sha256Reader<R> {
+    fn new(reader: R) -> Self {
+        Sha256Reader { reader, state: sha256::State::new() }
+    }
+
+    fn finish(self) -> String {
+        hex::encode(self.state.finalize().as_ref())
+    }
+}
+
+impl <R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.state.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn sha256_reader() {
+        let mut reader = Sha256Reader::new(Cursor::new(b"abc".to_vec()));
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"abc");
+        assert_eq!(reader.finish(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn parse_info() {
+        let toml = r#"
+bundle-version = 1
+realmfs-name = "main"
+version = 3
+sealed = true
+verity-root = "abcd"
+exported-from = "citadel"
+exported-at = 1571322245
+image-size = 4096
+image-sha256 = "1234"
+metainfo = "image-type = \"realmfs\""
+"#;
+        let info = BundleInfo::parse(toml).unwrap();
+        assert_eq!(info.name(), "main");
+        assert!(info.is_sealed());
+        assert_eq!(info.verity_root(), Some("abcd"));
+        assert!(info.provenance_sha256.is_none());
+
+        let info = BundleInfo::parse(&format!("{}provenance-sha256 = \"5678\"\n", toml)).unwrap();
+        assert_eq!(info.provenance_sha256.as_deref(), Some("5678"));
+
+        assert!(BundleInfo::parse(&toml.replace("\"main\"", "\"-bad\"")).is_err());
+        assert!(BundleInfo::parse(&toml.replace("bundle-version = 1", "bundle-version = 2")).is_err());
+    }
+}
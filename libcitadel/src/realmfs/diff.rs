@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap,HashMap};
+use std::fs::{self,File};
+use std::io::{BufReader,Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path,PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::{Result,RealmFS};
+
+// Location of the dpkg database relative to the root of an image
+pub(crate) const DPKG_STATUS: &str = "var/lib/dpkg/status";
+
+///
+/// The differences between the files and installed packages of two RealmFS images.
+///
+/// Files are compared by type, ownership, permissions, symlink target and content.
+/// Regular files with the same size, modification time and inode change time are
+/// assumed to have the same content, otherwise the content is compared byte for byte.
+/// Since images are copied at the block level, a file which has not been changed
+/// since an image was forked has exactly the same inode change time in both images.
+///
+#[derive(Serialize,Default)]
+pub struct RealmFSDiff {
+    added: Vec<String>,
+    removed: Vec<String>,
+    modified: Vec<String>,
+    packages: Vec<PackageChange>,
+}
+
+/// A package which was added, removed or changed version between two images.
+#[derive(Serialize)]
+pub struct PackageChange {
+    name: String,
+    #[serde(rename = "old-version")]
+    old_version: Option<String>,
+    #[serde(rename = "new-version")]
+    new_version: Option<String>,
+}
+
+impl PackageChange {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Version in the first image, or `None` if the package was added.
+    pub fn old_version(&self) -> Option<&str> {
+        self.old_version.as_deref()
+    }
+
+    /// Version in the second image, or `None` if the package was removed.
+    pub fn new_version(&self) -> Option<&str> {
+        self.new_version.as_deref()
+    }
+}
+
+#[derive(PartialEq)]
+enum EntryType {
+    File,
+    Dir,
+    Symlink(PathBuf),
+    Other,
+}
+
+struct Entry {
+    path: PathBuf,
+    etype: EntryType,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl Entry {
+    fn is_modified(&self, other: &Entry) -> Result<bool> {
+        if self.etype != other.etype || self.mode != other.mode || self.uid != other.uid || self.gid != other.gid {
+            return Ok(true);
+        }
+        if self.etype != EntryType::File {
+            return Ok(false);
+        }
+        if self.size != other.size {
+            return Ok(true);
+        }
+        if self.mtime == other.mtime && self.ctime == other.ctime {
+            return Ok(false);
+        }
+        Ok(!files_equal(&self.path, &other.path)?)
+    }
+}
+
+impl RealmFSDiff {
+
+    ///
+    /// Compare the content of two RealmFS images.
+    ///
+    /// Both images are activated if they are not already activated and the read-only
+    /// mountpoints are compared. Images which were activated to perform the comparison
+    /// are deactivated again afterwards.
+    ///
+    pub fn compare(a: &RealmFS, b: &RealmFS) -> Result<Self> {
+        Self::with_mountpoint(a, |root_a| {
+            Self::with_mountpoint(b, |root_b| Self::compare_directories(root_a, root_b))
+        })
+    }
+
+    fn with_mountpoint<F,R>(realmfs: &RealmFS, f: F) -> Result<R>
+        where F: FnOnce(&Path) -> Result<R>
+    {
+        let was_activated = realmfs.is_activated();
+        let activation = realmfs.activate()?;
+        let result = f(activation.mountpoint().path());
+        if !was_activated {
+            if let Err(e) = realmfs.deactivate() {
+                warn!("error deactivating RealmFS {}: {}", realmfs.name(), e);
+            }
+        }
+        result
+    }
+
+    /// Compare two directory trees, such as the mounted root directories of two images.
+    pub fn compare_directories(root_a: &Path, root_b: &Path) -> Result<Self> {
+        let entries_a = Self::scan(root_a)?;
+        let mut entries_b = Self::scan(root_b)?;
+        let mut diff = RealmFSDiff::default();
+
+        for (path, entry_a) in &entries_a {
+            match entries_b.remove(path) {
+                Some(entry_b) => if entry_a.is_modified(&entry_b)? {
+                    diff.modified.push(path.clone());
+                },
+                None => diff.removed.push(path.clone()),
+            }
+        }
+        diff.added = entries_b.into_keys().collect();
+        diff.packages = Self::compare_packages(root_a, root_b)?;
+        Ok(diff)
+    }
+
+    // Return all entries below `root` keyed by absolute path within the image
+    fn scan(root: &Path) -> Result<BTreeMap<String, Entry>> {
+        let mut entries = BTreeMap::new();
+        for dent in WalkDir::new(root).same_file_system(true).min_depth(1) {
+            let dent = dent?;
+            let meta = dent.metadata()?;
+            let ftype = meta.file_type();
+            let etype = if ftype.is_symlink() {
+                EntryType::Symlink(fs::read_link(dent.path())?)
+            } else if ftype.is_dir() {
+                EntryType::Dir
+            } else if ftype.is_file() {
+                EntryType::File
+            } else {
+                EntryType::Other
+            };
+            let name = Path::new("/").join(dent.path().strip_prefix(root)?);
+            entries.insert(name.display().to_string(), Entry {
+                path: dent.path().to_path_buf(),
+                etype,
+                mode: meta.mode(),
+                uid: meta.uid(),
+                gid: meta.gid(),
+                size: meta.len(),
+                mtime: (meta.mtime(), meta.mtime_nsec()),
+                ctime: (meta.ctime(), meta.ctime_nsec()),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn compare_packages(root_a: &Path, root_b: &Path) -> Result<Vec<PackageChange>> {
+        let packages_a = read_dpkg_status(&root_a.join(DPKG_STATUS))?;
+        let mut packages_b = read_dpkg_status(&root_b.join(DPKG_STATUS))?;
+        let mut changes = Vec::new();
+
+        for (name, version_a) in packages_a {
+            let version_b = packages_b.remove(&name);
+            if version_b.as_ref() != Some(&version_a) {
+                changes.push(PackageChange { name, old_version: Some(version_a), new_version: version_b });
+            }
+        }
+        for (name, version_b) in packages_b {
+            changes.push(PackageChange { name, old_version: None, new_version: Some(version_b) });
+        }
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(changes)
+    }
+
+    /// Files which exist only in the second image.
+    pub fn added(&self) -> &[String] {
+        &self.added
+    }
+
+    /// Files which exist only in the first image.
+    pub fn removed(&self) -> &[String] {
+        &self.removed
+    }
+
+    /// Files which exist in both images but differ.
+    pub fn modified(&self) -> &[String] {
+        &self.modified
+    }
+
+    /// Packages which were installed, removed or changed version.
+    pub fn packages(&self) -> &[PackageChange] {
+        &self.packages
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty() && self.packages.is_empty()
+    }
+}
+
+fn files_equal(a: &Path, b: &Path) -> Result<bool> {
+    let mut reader_a = BufReader::new(File::open(a)?);
+    let mut reader_b = BufReader::new(File::open(b)?);
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader_a.read(&mut buf_a)?;
+        if n == 0 {
+            let mut end = [0u8; 1];
+            return Ok(reader_b.read(&mut end)? == 0);
+        }
+        reader_b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+// Return the versions of all installed packages listed in a dpkg status file. Packages
+// which are installed for more than one architecture are named 'package:arch'.
+pub(crate) fn read_dpkg_status(path: &Path) -> Result<HashMap<String, String>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    Ok(parse_dpkg_status(&fs::read_to_string(path)?))
+}
+
+fn parse_dpkg_status(content: &str) -> HashMap<String, String> {
+    let mut installed = Vec::new();
+    for stanza in content.split("\n\n") {
+        let field = |name: &str| stanza.lines()
+            .find(|line| line.starts_with(name) && line[name.len()..].starts_with(':'))
+            .map(|line| line[name.len() + 1..].trim().to_string());
+
+        let is_installed = field("Status").map(|s| s.ends_with(" installed")).unwrap_or(false);
+        if let (true, Some(package), Some(version)) = (is_installed, field("Package"), field("Version")) {
+            installed.push((package, field("Architecture").unwrap_or_default(), version));
+        }
+    }
+
+    let mut counts = HashMap::new();
+    for (package, _, _) in &installed {
+        *counts.entry(package.clone()).or_insert(0) += 1;
+    }
+    installed.into_iter().map(|(package, arch, version)| {
+        if counts[&package] > 1 {
+            (format!("{}:{}", package, arch), version)
+        } else {
+            (package, version)
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::sys::stat::utimes;
+    use nix::sys::time::{TimeVal, TimeValLike};
+    use crate::util::TestDir;
+
+    const STATUS_A: &str = "\
+Package: bash
+Status: install ok installed
+Architecture: amd64
+Version: 5.0-4
+
+Package: libc6
+Status: install ok installed
+Architecture: amd64
+Version: 2.28-10
+
+Package: libc6
+Status: install ok installed
+Architecture: i386
+Version: 2.28-10
+
+Package: vim
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 2:8.1.0875-5
+";
+
+    #[test]
+    fn dpkg_status() {
+        let packages = parse_dpkg_status(STATUS_A);
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages["bash"], "5.0-4");
+        assert_eq!(packages["libc6:i386"], "2.28-10");
+        assert!(!packages.contains_key("vim"));
+    }
+
+    #[test]
+    fn compare_trees() {
+        let base = TestDir::new("realmfs-diff-test");
+        let (a, b) = (base.path().join("a"), base.path().join("b"));
+        for root in &[&a, &b] {
+            fs::create_dir_all(root.join("etc")).unwrap();
+            fs::create_dir_all(root.join("var/lib/dpkg")).unwrap();
+            fs::write(root.join("etc/same"), "same").unwrap();
+        }
+        fs::write(a.join("etc/changed"), "aaaa").unwrap();
+        fs::write(b.join("etc/changed"), "bbbb").unwrap();
+        // same size so contents are only compared if the timestamps differ
+        let mtime = TimeVal::seconds(1_000_000);
+        utimes(&a.join("etc/changed"), &mtime, &mtime).unwrap();
+        fs::write(a.join("etc/removed"), "").unwrap();
+        fs::write(b.join("etc/added"), "").unwrap();
+        fs::write(a.join(DPKG_STATUS), STATUS_A).unwrap();
+        fs::write(b.join(DPKG_STATUS), STATUS_A.replace("5.0-4", "5.0-6").replace("deinstall ok config-files", "install ok installed")).unwrap();
+
+        let diff = RealmFSDiff::compare_directories(&a, &b).unwrap();
+        assert_eq!(diff.added(), &["/etc/added".to_string()]);
+        assert_eq!(diff.removed(), &["/etc/removed".to_string()]);
+        assert_eq!(diff.modified(), &["/etc/changed".to_string(), "/var/lib/dpkg/status".to_string()]);
+
+        let packages = diff.packages();
+        assert_eq!(packages.len(), 2);
+        assert_eq!((packages[0].name(), packages[0].old_version(), packages[0].new_version()), ("bash", Some("5.0-4"), Some("5.0-6")));
+        assert_eq!((packages[1].name(), packages[1].old_version(), packages[1].new_version()), ("vim", None, Some("2:8.1.0875-5")));
+    }
+}
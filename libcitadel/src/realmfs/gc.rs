@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path,PathBuf};
+
+use crate::{Result,RealmFS,RealmManager,LoopDevice,GLOBAL_CONFIG};
+use super::image_lock::ImageLock;
+
+///
+/// Files in the RealmFS image directory which can be removed to reclaim space.
+///
+/// A scan collects three kinds of files:
+///
+///   * Backup copies of images created when an image is replaced by
+///     `RealmFS::rotate()` (`name-realmfs.img.N`) or sealed (`name-realmfs.img.old`)
+///   * Copies of sealed images left behind by an update which was interrupted
+///     before it was applied or cleaned up (`name-realmfs.img.update`)
+///   * User RealmFS images which are not used by the configuration of any realm.
+///     The default RealmFS from the global realm configuration is never collected.
+///
+/// Images and update copies which are currently activated, and update copies of
+/// images locked by a running update, are never collected.
+///
+pub struct RealmFSGarbage {
+    items: Vec<GarbageItem>,
+}
+
+#[derive(Clone,Copy,PartialEq)]
+pub enum GarbageKind {
+    Backup,
+    UpdateCopy,
+    UnusedImage,
+}
+
+impl fmt::Display for GarbageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            GarbageKind::Backup => "backup",
+            GarbageKind::UpdateCopy => "interrupted update",
+            GarbageKind::UnusedImage => "unused image",
+        };
+        f.pad(s)
+    }
+}
+
+pub struct GarbageItem {
+    kind: GarbageKind,
+    name: String,
+    path: PathBuf,
+    size: u64,
+    realmfs: Option<RealmFS>,
+}
+
+impl GarbageItem {
+    fn new(kind: GarbageKind, name: &str, path: &Path, realmfs: Option<RealmFS>) -> Result<Self> {
+        // Report the space actually allocated to the file since images are often sparse
+        // or reflink copies.
+        let size = path.metadata()?.blocks() * 512;
+        Ok(GarbageItem { kind, name: name.to_string(), path: path.to_path_buf(), size, realmfs })
+    }
+
+    pub fn kind(&self) -> GarbageKind {
+        self.kind
+    }
+
+    /// Name of the RealmFS this file belongs to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Disk space in bytes allocated to this file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn remove(&self, manager: &RealmManager) -> Result<()> {
+        match self.realmfs {
+            Some(ref realmfs) => manager.delete_realmfs(realmfs),
+            None => {
+                // Prevent an update from starting and creating a new copy while this one is removed
+                let _lock = match self.kind {
+                    GarbageKind::UpdateCopy => Some(ImageLock::acquire(&self.name, "gc")?),
+                    _ => None,
+                };
+                info!("Removing {} {}", self.kind, self.path.display());
+                fs::remove_file(&self.path)?;
+                Ok(())
+            },
+        }
+    }
+}
+
+impl RealmFSGarbage {
+
+    /// Scan the RealmFS image directory for files which can be removed.
+    pub fn scan(manager: &RealmManager) -> Result<Self> {
+        let mut items = Self::unused_images(manager)?;
+        let mut entries = fs::read_dir(RealmFS::BASE_PATH)?
+            .flat_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        for path in entries {
+            let (name, suffix) = match path.file_name().and_then(|s| s.to_str()).and_then(split_image_filename) {
+                Some(parts) => parts,
+                None => continue,
+            };
+            if (suffix == "old" || suffix.chars().all(|c| c.is_ascii_digit())) && !Self::is_attached(&path) {
+                items.push(GarbageItem::new(GarbageKind::Backup, name, &path, None)?);
+            } else if suffix == "update" && !Self::is_attached(&path) && !ImageLock::is_held(name) {
+                items.push(GarbageItem::new(GarbageKind::UpdateCopy, name, &path, None)?);
+            }
+        }
+        Ok(RealmFSGarbage { items })
+    }
+
+    fn unused_images(manager: &RealmManager) -> Result<Vec<GarbageItem>> {
+        let mut referenced = manager.realm_list().iter()
+            .map(|r| r.config().realmfs().to_string())
+            .collect::<HashSet<_>>();
+        referenced.insert(GLOBAL_CONFIG.realmfs().to_string());
+
+        let mut items = Vec::new();
+        for realmfs in manager.realmfs_list() {
+            if referenced.contains(realmfs.name()) || realmfs.is_activated() || !realmfs.is_user_realmfs() {
+                continue;
+            }
+            items.push(GarbageItem::new(GarbageKind::UnusedImage, realmfs.name(), realmfs.path(), Some(realmfs.clone()))?);
+        }
+        Ok(items)
+    }
+
+    // Return true if a loop device is attached to the image file at `path`, which
+    // means that the file is still in use, for example by an update which is still
+    // running or by an activation of a backup. An update which is still copying or
+    // resizing the file is detected by the image lock instead.
+    fn is_attached(path: &Path) -> bool {
+        match LoopDevice::find_devices_for(path) {
+            Ok(devices) => !devices.is_empty(),
+            Err(e) => {
+                warn!("error checking for loop devices attached to {}: {}", path.display(), e);
+                true
+            },
+        }
+    }
+
+    pub fn items(&self) -> &[GarbageItem] {
+        &self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Total disk space in bytes which would be reclaimed by removing all items.
+    pub fn reclaimable_size(&self) -> u64 {
+        self.items.iter().map(|item| item.size).sum()
+    }
+
+    /// Remove all items and return the number of bytes reclaimed. If any item
+    /// cannot be removed a warning is logged and the remaining items are still
+    /// removed.
+    pub fn remove_all(&self, manager: &RealmManager) -> u64 {
+        let mut reclaimed = 0;
+        for item in &self.items {
+            match item.remove(manager) {
+                Ok(()) => reclaimed += item.size,
+                Err(e) => warn!("failed to remove {}: {}", item.path.display(), e),
+            }
+        }
+        reclaimed
+    }
+}
+
+// Split a filename of the form 'name-realmfs.img.suffix' into name and suffix
+fn split_image_filename(filename: &str) -> Option<(&str, &str)> {
+    let idx = filename.find("-realmfs.img.")?;
+    let (name, suffix) = (&filename[..idx], &filename[idx + "-realmfs.img.".len()..]);
+    if RealmFS::is_valid_name(name) && !suffix.is_empty() && !suffix.contains('.') {
+        Some((name, suffix))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn image_filenames() {
+        assert_eq!(split_image_filename("main-realmfs.img.0"), Some(("main", "0")));
+        assert_eq!(split_image_filename("my-image-realmfs.img.update"), Some(("my-image", "update")));
+        assert_eq!(split_image_filename("main-realmfs.img.update.log"), None);
+        assert_eq!(split_image_filename("main-realmfs.img"), None);
+        assert_eq!(split_image_filename("main-realmfs.img."), None);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::thread::{self,ThreadId};
+
+use crate::{Result,RealmFS,FileLock};
+
+lazy_static! {
+    // Image locks currently held by this process, by RealmFS name
+    static ref HELD_LOCKS: Mutex<HashMap<String, HeldLock>> = Mutex::new(HashMap::new());
+}
+
+struct HeldLock {
+    thread: ThreadId,
+    operation: String,
+    count: usize,
+    _lock: FileLock,
+}
+
+///
+/// An advisory lock on a RealmFS image which is held for the duration of an
+/// operation which modifies or replaces the image.
+///
+/// The lock is a `FileLock` on the file `RealmFS::RUN_DIRECTORY/locks/name.lock`
+/// which contains the pid of the process holding the lock and the operation it
+/// is performing. Locks are not waited for. If the image is already locked by
+/// another process (or another thread of this process) an error describing the
+/// holder of the lock is returned instead.
+///
+/// A lock may be acquired again by the thread which already holds it so that
+/// operations such as an update can perform other locked operations such as
+/// resizing on the same image.
+///
+pub(crate) struct ImageLock {
+    name: String,
+}
+
+impl ImageLock {
+
+    /// Acquire the lock for the RealmFS image called `name` for performing `operation`.
+    pub(crate) fn acquire(name: &str, operation: &str) -> Result<ImageLock> {
+        let name = name.to_string();
+        let mut held = HELD_LOCKS.lock().unwrap();
+        if let Some(lock) = held.get_mut(&name) {
+            if lock.thread != thread::current().id() {
+                bail!("RealmFS '{}' image busy: held by pid {} ({})", name, process::id(), lock.operation);
+            }
+            lock.count += 1;
+            return Ok(ImageLock { name });
+        }
+
+        let path = Self::lock_path(&name);
+        let lock = match FileLock::try_acquire(&path)? {
+            Some(lock) => lock,
+            None => bail!("RealmFS '{}' image busy: {}", name, Self::describe_holder(&path)),
+        };
+        lock.write_info(&format!("{} {}", process::id(), operation))?;
+        held.insert(name.clone(), HeldLock {
+            thread: thread::current().id(),
+            operation: operation.to_string(),
+            count: 1,
+            _lock: lock,
+        });
+        Ok(ImageLock { name })
+    }
+
+    /// Return `true` if the lock for the RealmFS image called `name` is currently held
+    /// by any process, including this one.
+    pub(crate) fn is_held(name: &str) -> bool {
+        if HELD_LOCKS.lock().unwrap().contains_key(name) {
+            return true;
+        }
+        match FileLock::is_locked(Self::lock_path(name)) {
+            Ok(locked) => locked,
+            Err(e) => {
+                warn!("error checking lock for RealmFS '{}': {}", name, e);
+                true
+            },
+        }
+    }
+
+    fn lock_path(name: &str) -> PathBuf {
+        Path::new(RealmFS::RUN_DIRECTORY)
+            .join("locks")
+            .join(format!("{}.lock", name))
+    }
+
+    fn describe_holder(path: &Path) -> String {
+        FileLock::read_info(path)
+            .as_ref()
+            .and_then(|info| parse_info(info))
+            .map(|(pid, operation)| format!("held by pid {} ({})", pid, operation))
+            .unwrap_or_else(|| "held by another process".to_string())
+    }
+}
+
+impl Drop for ImageLock {
+    fn drop(&mut self) {
+        let mut held = HELD_LOCKS.lock().unwrap();
+        let release = match held.get_mut(&self.name) {
+            Some(lock) => {
+                lock.count -= 1;
+                lock.count == 0
+            },
+            None => false,
+        };
+        if release {
+            held.remove(&self.name);
+        }
+    }
+}
+
+// Parse lockfile content of the form 'pid operation'
+fn parse_info(info: &str) -> Option<(u32, &str)> {
+    let mut parts = info.trim().splitn(2, ' ');
+    let pid = parts.next()?.parse().ok()?;
+    let operation = parts.next().unwrap_or("unknown operation");
+    Some((pid, operation))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn lockfile_info() {
+        assert_eq!(parse_info("1234 update\n"), Some((1234, "update")));
+        assert_eq!(parse_info("1234"), Some((1234, "unknown operation")));
+        assert_eq!(parse_info(""), None);
+
+        let path = env::temp_dir().join(format!("citadel-image-lock-test-{}.lock", process::id()));
+        let lock = FileLock::try_acquire(&path).unwrap().unwrap();
+        lock.write_info("1234 resize").unwrap();
+        assert_eq!(ImageLock::describe_holder(&path), "held by pid 1234 (resize)");
+
+        let other = thread::spawn({
+            let path = path.clone();
+            move || FileLock::try_acquire(&path).unwrap().is_none()
+        });
+        assert!(other.join().unwrap());
+        assert!(FileLock::is_locked(&path).unwrap());
+        drop(lock);
+        assert!(!path.exists());
+        assert!(!FileLock::is_locked(&path).unwrap());
+
+        // Checking an unlocked lockfile does not remove it
+        fs::write(&path, "").unwrap();
+        assert!(!FileLock::is_locked(&path).unwrap());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,6 +2,14 @@ pub(crate) mod resizer;
 mod activator;
 mod mountpoint;
 mod update;
+mod snapshot;
+mod recipe;
+mod diff;
+mod bundle;
+mod gc;
+mod reconcile;
+mod provenance;
+pub(crate) mod image_lock;
 pub(crate) mod realmfs_set;
 #[allow(clippy::module_inception)]
 mod realmfs;
@@ -9,3 +17,10 @@ mod realmfs;
 pub use self::realmfs::RealmFS;
 pub use self::mountpoint::Mountpoint;
 pub use self::activator::Activation;
+pub use self::snapshot::Snapshot;
+pub use self::recipe::RealmFSRecipe;
+pub use self::diff::{RealmFSDiff,PackageChange};
+pub use self::bundle::{RealmFSBundle,BundleInfo};
+pub use self::gc::{RealmFSGarbage,GarbageItem,GarbageKind};
+pub use self::reconcile::ActivationReconciler;
+pub use self::provenance::{Provenance,ParentImage};
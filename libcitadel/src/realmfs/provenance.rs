@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::time::{SystemTime,UNIX_EPOCH};
+
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::{Result,RealmFS,LoopDevice,util};
+use super::diff::{read_dpkg_status,DPKG_STATUS};
+
+///
+/// Where a RealmFS image came from and what it contains.
+///
+/// The provenance of an image is stored in a TOML file next to the image file
+/// (`name-realmfs.img.provenance`) and records the image it was forked from, how
+/// many updates have been applied to it since, when it was last sealed and the
+/// packages which were installed when it was sealed:
+///
+/// ```text
+/// updates = 3
+/// sealed-at = 1571322245
+///
+/// [parent]
+/// name = "base"
+/// version = 12
+/// forked-at = 1571000000
+///
+/// [packages]
+/// bash = "5.0-4"
+/// ```
+///
+/// When an image is sealed the sha256 hash of the provenance file is stored in the
+/// `provenance-sha256` field of the image metainfo so that it is signed along with
+/// the image header. The provenance of an unsealed image is not verified.
+///
+#[derive(Serialize,Deserialize,Clone,Default)]
+pub struct Provenance {
+    #[serde(default)]
+    updates: u32,
+    #[serde(rename = "sealed-at")]
+    sealed_at: Option<u64>,
+    parent: Option<ParentImage>,
+    #[serde(default)]
+    packages: BTreeMap<String, String>,
+
+    #[serde(skip)]
+    verified: bool,
+}
+
+/// The image a RealmFS image was forked from.
+#[derive(Serialize,Deserialize,Clone)]
+pub struct ParentImage {
+    name: String,
+    version: u32,
+    #[serde(rename = "verity-root")]
+    verity_root: Option<String>,
+    #[serde(rename = "forked-at")]
+    forked_at: u64,
+}
+
+impl ParentImage {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Version from the metainfo of the parent image when it was forked.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Time the image was forked in seconds since the epoch.
+    pub fn forked_at(&self) -> u64 {
+        self.forked_at
+    }
+}
+
+impl Provenance {
+
+    /// Path of the provenance file for the image file at `image`.
+    pub(crate) fn path_for(image: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.provenance", image.display()))
+    }
+
+    ///
+    /// Load the provenance of `realmfs` or return `None` if the image has no provenance file.
+    ///
+    /// If the image is sealed and the metainfo contains a provenance hash, an error
+    /// is returned if the provenance file is missing or does not match the hash.
+    ///
+    pub(crate) fn load(realmfs: &RealmFS) -> Result<Option<Provenance>> {
+        let path = Self::path_for(realmfs.path());
+        if !path.exists() {
+            if realmfs.is_sealed() && realmfs.metainfo().provenance_sha256().is_some() {
+                bail!("provenance file {} is missing but a provenance hash is in the signed image header", path.display());
+            }
+            return Ok(None);
+        }
+        let bytes = fs::read(&path)?;
+        let mut provenance = toml::from_slice::<Provenance>(&bytes)
+            .map_err(|e| format_err!("failed to parse provenance file {}: {}", path.display(), e))?;
+
+        if realmfs.is_sealed() {
+            if let Some(expected) = realmfs.metainfo().provenance_sha256() {
+                if Self::hash_bytes(&bytes) != expected {
+                    bail!("provenance file {} does not match hash in signed image header", path.display());
+                }
+                provenance.verified = true;
+            }
+        }
+        Ok(Some(provenance))
+    }
+
+    /// Load the provenance for the image at `image` without verifying it, or return
+    /// an empty provenance if the file does not exist or cannot be read.
+    pub(crate) fn load_unverified(image: &Path) -> Provenance {
+        let path = Self::path_for(image);
+        if !path.exists() {
+            return Provenance::default();
+        }
+        let result = fs::read(&path).map_err(failure::Error::from)
+            .and_then(|bytes| Ok(toml::from_slice::<Provenance>(&bytes)?));
+        match result {
+            Ok(provenance) => provenance,
+            Err(e) => {
+                warn!("ignoring invalid provenance file {}: {}", path.display(), e);
+                Provenance::default()
+            }
+        }
+    }
+
+    /// Create the provenance for a new image forked from `parent`.
+    pub(crate) fn for_fork(parent: &RealmFS) -> Result<Provenance> {
+        let metainfo = parent.metainfo();
+        let verity_root = Some(metainfo.verity_root())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string());
+        let packages = Self::load_unverified(parent.path()).packages;
+        Ok(Provenance {
+            parent: Some(ParentImage {
+                name: parent.name().to_string(),
+                version: metainfo.version(),
+                verity_root,
+                forked_at: now()?,
+            }),
+            packages,
+            ..Default::default()
+        })
+    }
+
+    /// Write the provenance file for the image at `image` and return the sha256 hash
+    /// of the file.
+    pub(crate) fn save(&self, image: &Path) -> Result<String> {
+        let s = toml::to_string(self)?;
+        fs::write(Self::path_for(image), &s)?;
+        Ok(Self::hash_bytes(s.as_bytes()))
+    }
+
+    /// Copy the provenance file for the image `from` to the image `to`, or remove the
+    /// provenance file of `to` if `from` does not have one.
+    pub(crate) fn copy(from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (Self::path_for(from), Self::path_for(to));
+        if from.exists() {
+            fs::copy(from, to)?;
+        } else if to.exists() {
+            fs::remove_file(to)?;
+        }
+        Ok(())
+    }
+
+    /// Rename the provenance file for the image `from` to belong to the image `to`.
+    pub(crate) fn rename(from: &Path, to: &Path) -> Result<()> {
+        let from = Self::path_for(from);
+        if from.exists() {
+            fs::rename(from, Self::path_for(to))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the provenance file for the image at `image` if it exists.
+    pub(crate) fn remove(image: &Path) -> Result<()> {
+        let path = Self::path_for(image);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn hash_bytes(bytes: &[u8]) -> String {
+        hex::encode(sha256::hash(bytes).as_ref())
+    }
+
+    pub(crate) fn record_update(&mut self) {
+        self.updates += 1;
+    }
+
+    /// Record the seal time and the packages installed in the unsealed image at `image`
+    /// which must not be activated.
+    pub(crate) fn record_seal(&mut self, image: &Path) -> Result<()> {
+        self.sealed_at = Some(now()?);
+        match Self::read_packages(image) {
+            Ok(packages) => self.packages = packages,
+            Err(e) => warn!("Unable to read package inventory from {}: {}", image.display(), e),
+        }
+        Ok(())
+    }
+
+    /// Record the seal time for a sealed copy of an image. The package inventory of
+    /// the original image is kept since the contents of the copy are identical.
+    pub(crate) fn record_sealed_copy(&mut self) -> Result<()> {
+        self.sealed_at = Some(now()?);
+        Ok(())
+    }
+
+    // Mount the image read-only at a temporary directory and read the dpkg database
+    fn read_packages(image: &Path) -> Result<BTreeMap<String, String>> {
+        let mountpoint = env::temp_dir().join(format!("citadel-provenance-{}", std::process::id()));
+        fs::create_dir_all(&mountpoint)?;
+        let result = LoopDevice::with_loop(image, Some(4096), true, |loopdev| {
+            util::mount(loopdev.device_str(), &mountpoint, Some("-oro,noload"))?;
+            let packages = read_dpkg_status(&mountpoint.join(DPKG_STATUS));
+            util::umount(&mountpoint)?;
+            packages
+        });
+        let _ = fs::remove_dir(&mountpoint);
+        Ok(result?.into_iter().collect())
+    }
+
+    /// The image this image was forked from, if known.
+    pub fn parent(&self) -> Option<&ParentImage> {
+        self.parent.as_ref()
+    }
+
+    /// Number of updates applied to the image since it was forked.
+    pub fn updates(&self) -> u32 {
+        self.updates
+    }
+
+    /// Time the image was last sealed in seconds since the epoch.
+    pub fn sealed_at(&self) -> Option<u64> {
+        self.sealed_at
+    }
+
+    /// Name and version of each package installed when the image was last sealed.
+    pub fn packages(&self) -> &BTreeMap<String, String> {
+        &self.packages
+    }
+
+    /// Return `true` if the provenance matches the hash in the signed image header.
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// A short description of the history of the image such as
+    /// "forked from base v12, updated 3 times".
+    pub fn lineage(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ref parent) = self.parent {
+            if parent.version > 0 {
+                parts.push(format!("forked from {} v{}", parent.name, parent.version));
+            } else {
+                parts.push(format!("forked from {}", parent.name));
+            }
+        }
+        match self.updates {
+            0 => {},
+            1 => parts.push("updated 1 time".to_string()),
+            n => parts.push(format!("updated {} times", n)),
+        }
+        if let Some(sealed_at) = self.sealed_at {
+            parts.push(format!("sealed {}", util::format_timestamp(sealed_at)));
+        }
+        if parts.is_empty() {
+            "no recorded history".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lineage() {
+        let mut provenance = Provenance::default();
+        assert_eq!(provenance.lineage(), "no recorded history");
+        provenance.parent = Some(ParentImage {
+            name: "base".to_string(), version: 12, verity_root: None, forked_at: 0,
+        });
+        provenance.record_update();
+        provenance.record_update();
+        provenance.record_update();
+        assert_eq!(provenance.lineage(), "forked from base v12, updated 3 times");
+
+        provenance.packages.insert("bash".to_string(), "5.0-4".to_string());
+        let s = toml::to_string(&provenance).unwrap();
+        let loaded = toml::from_str::<Provenance>(&s).unwrap();
+        assert_eq!(loaded.updates(), 3);
+        assert_eq!(loaded.parent().map(|p| p.name()), Some("base"));
+        assert_eq!(loaded.packages()["bash"], "5.0-4");
+    }
+}
@@ -3,14 +3,18 @@ use std::fs;
 use std::io::Write;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path,PathBuf};
+use std::time::Duration;
 
 use sodiumoxide::randombytes::randombytes;
 use hex;
 
-use crate::{CommandLine, ImageHeader, MetaInfo, Result, KeyRing, KeyPair, Signature, util, RealmManager};
+use crate::{CommandLine, ImageHeader, MetaInfo, Result, KeyRing, KeyPair, Signature, util, RealmManager, Exec, Progress, NoProgress};
 
 use super::resizer::{ImageResizer,ResizeSize};
 use super::update::Update;
+use super::snapshot::Snapshot;
+use super::provenance::Provenance;
+use super::image_lock::ImageLock;
 use crate::realmfs::resizer::Superblock;
 use std::sync::{Arc, Weak};
 use super::activator::Activation;
@@ -109,7 +113,7 @@ impl RealmFS {
         self.manager = Arc::downgrade(&manager);
     }
 
-    fn load_activation(&self) {
+    pub(crate) fn load_activation(&self) {
         self.activation_state.load(self);
     }
 
@@ -176,7 +180,7 @@ impl RealmFS {
         Self::is_valid_realmfs_image(Self::image_path(name))
     }
 
-    fn image_path(name: &str) -> PathBuf {
+    pub(crate) fn image_path(name: &str) -> PathBuf {
         Path::new(Self::BASE_PATH).join(format!("{}-realmfs.img", name))
     }
 
@@ -189,7 +193,7 @@ impl RealmFS {
     /// the string `ext` as an extension to the filename. If the current filename
     /// ends with '.img' then the specified extension is appended to this as '.img.ext'
     /// otherwise it replaces any existing extension.
-    fn path_with_extension(&self, ext: &str) -> PathBuf {
+    pub(crate) fn path_with_extension(&self, ext: &str) -> PathBuf {
         if self.path.extension() == Some(OsStr::new("img")) {
             self.path.with_extension(format!("img.{}", ext))
         } else {
@@ -284,8 +288,8 @@ impl RealmFS {
         self.activation_state.deactivate(&active)
     }
 
-    pub fn fork(&self, new_name: &str) -> Result<Self> {
-        self._fork(new_name, true)
+    pub fn fork(&self, new_name: &str, progress: &mut dyn Progress) -> Result<Self> {
+        self._fork(new_name, true, progress)
     }
 
     /// Create an unsealed copy of this RealmFS image with a new image name.
@@ -300,12 +304,15 @@ impl RealmFS {
             bail!("RealmFS image for name {} already exists", new_name);
         }
 
-        let new_realmfs = self.copy_image(&new_path, new_name, false)?;
+        let _lock = ImageLock::acquire(self.name(), "fork")?;
+        let _new_lock = ImageLock::acquire(new_name, "fork")?;
+        let provenance = Provenance::for_fork(self)?;
+        let new_realmfs = self.copy_image(&new_path, new_name, false, &provenance, &mut NoProgress)?;
         self.with_manager(|m| m.realmfs_added(&new_realmfs));
         Ok(new_realmfs)
     }
 
-    fn _fork(&self, new_name: &str, sealed_fork: bool) -> Result<Self> {
+    fn _fork(&self, new_name: &str, sealed_fork: bool, progress: &mut dyn Progress) -> Result<Self> {
         Self::validate_name(new_name)?;
         info!("forking RealmFS image '{}' to new name '{}'", self.name(), new_name);
         let new_path = self.path_with_filename(format!("{}-realmfs.img", new_name));
@@ -313,7 +320,10 @@ impl RealmFS {
             bail!("RealmFS image for name {} already exists", new_name);
         }
 
-        let new_realmfs = self.copy_image(&new_path, new_name, sealed_fork)?;
+        let _lock = ImageLock::acquire(self.name(), "fork")?;
+        let _new_lock = ImageLock::acquire(new_name, "fork")?;
+        let provenance = Provenance::for_fork(self)?;
+        let new_realmfs = self.copy_image(&new_path, new_name, sealed_fork, &provenance, progress)?;
 
         self.with_manager(|m| m.realmfs_added(&new_realmfs));
         Ok(new_realmfs)
@@ -331,46 +341,76 @@ impl RealmFS {
     pub(crate) fn update_copy(&self) -> Result<Self> {
         let path = self.path_with_extension("update");
         let name = self.name().to_string() + "-update";
-        self.copy_image(&path, &name, false)
+        let provenance = Provenance::load_unverified(self.path());
+        self.copy_image(&path, &name, false, &provenance, &mut NoProgress)
     }
 
-    fn copy_image(&self, path: &Path, name: &str, sealed_copy: bool) -> Result<Self> {
+    fn copy_image(&self, path: &Path, name: &str, sealed_copy: bool, provenance: &Provenance, progress: &mut dyn Progress) -> Result<Self> {
         if path.exists() {
             bail!("Cannot create sealed copy because target path '{}' already exists", path.display());
         }
-        cmd!("/usr/bin/cp", "--reflink=auto {} {}", self.path.display(), path.display())?;
+        Self::copy_image_file(self.path(), path, progress)?;
         let mut realmfs = Self::_load_from_path(path, false)?;
         self.with_manager(|m| realmfs.set_manager(m));
         realmfs.name = Arc::new(name.to_owned());
 
         let result = if sealed_copy {
-            realmfs.write_sealed_copy_header()
+            let mut provenance = provenance.clone();
+            provenance.record_sealed_copy()
+                .and_then(|_| provenance.save(path))
+                .and_then(|hash| realmfs.write_sealed_copy_header(&hash))
         } else {
-            realmfs.unseal()
+            provenance.save(path)
+                .and_then(|_| realmfs.unseal())
         };
 
-        result.map_err(|e|
+        result.map_err(|e| {
+            let _ = Provenance::remove(path);
             if let Err(e) = fs::remove_file(path) {
                 format_err!("failed to remove {} after realmfs fork/copy failed with: {}", path.display(), e)
-            } else { e })?;
+            } else { e }
+        })?;
 
         Ok(realmfs)
     }
 
-    fn write_sealed_copy_header(&self) -> Result<()> {
+    // Copy an image file with 'cp --reflink=auto' and report progress by
+    // checking the size of the new file while the copy is running.
+    fn copy_image_file(from: &Path, path: &Path, progress: &mut dyn Progress) -> Result<()> {
+        let total = from.metadata()?.len();
+        progress.begin("Copying RealmFS image", total);
+        Exec::new("/usr/bin/cp").run_polling(format!("--reflink=auto {} {}", from.display(), path.display()), Duration::from_millis(200), || {
+            if let Ok(meta) = path.metadata() {
+                progress.update(meta.len());
+            }
+        })?;
+        progress.update(total);
+        progress.end();
+        Ok(())
+    }
+
+    fn write_sealed_copy_header(&self, provenance_hash: &str) -> Result<()> {
         let keys = match self.sealing_keys() {
             Ok(keys) => keys,
             Err(err) => bail!("Cannot seal realmfs image, no sealing keys available: {}", err),
         };
         let metainfo = self.metainfo();
-        let metainfo_bytes = self.generate_sealed_metainfo(self.name(), metainfo.verity_salt(), metainfo.verity_root());
+        let metainfo_bytes = self.generate_sealed_metainfo(self.name(), metainfo.verity_salt(), metainfo.verity_root(), Some(provenance_hash));
         let sig = keys.sign(&metainfo_bytes);
         self.write_new_metainfo(&metainfo_bytes, Some(sig))
     }
 
     /// Convert to unsealed RealmFS image by removing dm-verity metadata and hash tree
     pub fn unseal(&self) -> Result<()> {
-        let bytes = Self::generate_unsealed_metainfo(self.name(), self.metainfo().nblocks(), None);
+        let _lock = ImageLock::acquire(self.name(), "unseal")?;
+        self.unseal_renamed(self.name())
+    }
+
+    /// Unseal this image and change the name in the metainfo to `name`. The caller
+    /// must hold the `ImageLock` for `name`.
+    pub(crate) fn unseal_renamed(&self, name: &str) -> Result<()> {
+        let metainfo = self.metainfo();
+        let bytes = Self::generate_unsealed_metainfo(name, metainfo.nblocks(), None, metainfo.recipe_hash());
         self.write_new_metainfo(&bytes, None)?;
         if self.has_verity_tree() {
             self.truncate_verity()?;
@@ -394,10 +434,23 @@ impl RealmFS {
     }
 
     pub fn update_unsealed_metainfo(&self, name: &str, nblocks: usize, owner_realm: Option<String>) -> Result<()> {
+        let metainfo = self.metainfo();
+        self.write_unsealed_metainfo(name, nblocks, owner_realm, metainfo.recipe_hash())
+    }
+
+    /// Record the hash of the recipe this image was built from in the metainfo of an
+    /// unsealed image. The hash is preserved when the image is later sealed.
+    pub(crate) fn set_recipe_hash(&self, recipe_hash: &str) -> Result<()> {
+        let metainfo = self.metainfo();
+        let owner = metainfo.realmfs_owner().map(|s| s.to_owned());
+        self.write_unsealed_metainfo(self.name(), metainfo.nblocks(), owner, Some(recipe_hash))
+    }
+
+    fn write_unsealed_metainfo(&self, name: &str, nblocks: usize, owner_realm: Option<String>, recipe_hash: Option<&str>) -> Result<()> {
         if self.is_sealed() {
             bail!("Cannot update metainfo on sealed realmfs image");
         }
-        let metainfo_bytes = Self::generate_unsealed_metainfo(name, nblocks, owner_realm);
+        let metainfo_bytes = Self::generate_unsealed_metainfo(name, nblocks, owner_realm, recipe_hash);
         self.write_new_metainfo(&metainfo_bytes, None)
     }
 
@@ -409,7 +462,7 @@ impl RealmFS {
         self.header.write_header_to(self.path())
     }
 
-    fn generate_unsealed_metainfo(name: &str, nblocks: usize, owner_realm: Option<String>) -> Vec<u8> {
+    fn generate_unsealed_metainfo(name: &str, nblocks: usize, owner_realm: Option<String>, recipe_hash: Option<&str>) -> Vec<u8> {
         let mut v = Vec::new();
         writeln!(v, "image-type = \"realmfs\"").unwrap();
         writeln!(v, "realmfs-name = \"{}\"", name).unwrap();
@@ -417,14 +470,21 @@ impl RealmFS {
         if let Some(owner) = owner_realm {
             writeln!(v, "realmfs-owner = \"{}\"", owner).unwrap();
         }
+        if let Some(recipe_hash) = recipe_hash {
+            writeln!(v, "recipe-hash = \"{}\"", recipe_hash).unwrap();
+        }
         v
     }
 
-    fn generate_sealed_metainfo(&self, name: &str, verity_salt: &str, verity_root: &str) -> Vec<u8> {
-        let mut v = Self::generate_unsealed_metainfo(name, self.metainfo().nblocks(), None);
+    fn generate_sealed_metainfo(&self, name: &str, verity_salt: &str, verity_root: &str, provenance_hash: Option<&str>) -> Vec<u8> {
+        let metainfo = self.metainfo();
+        let mut v = Self::generate_unsealed_metainfo(name, metainfo.nblocks(), None, metainfo.recipe_hash());
         writeln!(v, "channel = \"{}\"", Self::USER_KEYNAME).unwrap();
         writeln!(v, "verity-salt = \"{}\"", verity_salt).unwrap();
         writeln!(v, "verity-root = \"{}\"", verity_root).unwrap();
+        if let Some(provenance_hash) = provenance_hash {
+            writeln!(v, "provenance-sha256 = \"{}\"", provenance_hash).unwrap();
+        }
         v
     }
 
@@ -479,6 +539,7 @@ impl RealmFS {
             Err(err) => bail!("Cannot seal realmfs image, no sealing keys available: {}", err),
         };
 
+        let _lock = ImageLock::acquire(self.name(), "seal")?;
         if self.is_activated() {
             bail!("Cannot seal RealmFS because it is currently activated");
         }
@@ -488,6 +549,12 @@ impl RealmFS {
             self.truncate_verity()?;
         }
 
+        // Record the installed packages before sealing. The hash of the provenance file is
+        // included in the signed metainfo of the sealed image.
+        let mut provenance = Provenance::load_unverified(self.path());
+        provenance.record_seal(self.path())?;
+        let provenance_hash = provenance.save(self.path())?;
+
         let tmp = self.path_with_extension("sealing");
         if tmp.exists() {
             info!("Temporary copy of realmfs image {} already exists, removing it.", self.name());
@@ -503,7 +570,7 @@ impl RealmFS {
         realmfs.set_manager(self.manager());
 
         let finish = || {
-            realmfs.generate_sealing_verity(&keys, name)?;
+            realmfs.generate_sealing_verity(&keys, name, &provenance_hash)?;
             verbose!("Rename {} to {}", self.path().display(), self.path_with_extension("old").display());
             fs::rename(self.path(), self.path_with_extension("old"))?;
             verbose!("Rename {} to {}", realmfs.path().display(), self.path().display());
@@ -520,22 +587,38 @@ impl RealmFS {
         Ok(())
     }
 
-    fn generate_sealing_verity(&self, keys: &KeyPair, name: &str) -> Result<()> {
+    fn generate_sealing_verity(&self, keys: &KeyPair, name: &str, provenance_hash: &str) -> Result<()> {
         info!("Generating verity hash tree for sealed realmfs ({})", self.path().display());
         let salt = hex::encode(randombytes(32));
-        let output = Verity::new(self.path()).generate_image_hashtree_with_salt(&self.metainfo(), &salt)?;
+        let output = Verity::new(self.path()).generate_image_hashtree_with_salt(&self.metainfo(), &salt, &mut NoProgress)?;
         let root_hash = output.root_hash()
             .ok_or_else(|| format_err!("no root hash returned from verity format operation"))?;
         info!("root hash is {}", output.root_hash().unwrap());
 
         info!("Signing new image with user realmfs keys");
-        let metainfo_bytes = self.generate_sealed_metainfo(name, &salt, &root_hash);
+        let metainfo_bytes = self.generate_sealed_metainfo(name, &salt, &root_hash, Some(provenance_hash));
         let sig = keys.sign(&metainfo_bytes);
 
         self.header().set_flag(ImageHeader::FLAG_HASH_TREE);
         self.write_new_metainfo(&metainfo_bytes, Some(sig))
     }
 
+    /// Return the provenance of this image, or `None` if no provenance has been recorded.
+    ///
+    /// An error is returned if this image is sealed and the provenance does not match
+    /// the hash in the signed image header.
+    pub fn provenance(&self) -> Result<Option<Provenance>> {
+        Provenance::load(self)
+    }
+
+    /// Increment the count of updates applied to this image in the provenance of the image.
+    pub(crate) fn record_update(&self) -> Result<()> {
+        let mut provenance = Provenance::load_unverified(self.path());
+        provenance.record_update();
+        provenance.save(self.path())?;
+        Ok(())
+    }
+
     pub fn has_sealing_keys(&self) -> bool {
         self.sealing_keys().is_ok()
     }
@@ -545,7 +628,7 @@ impl RealmFS {
     }
 
     pub fn rotate(&self, new_file: &Path) -> Result<()> {
-       let backup = |n: usize| Path::new(Self::BASE_PATH).join(format!("{}-realmfs.img.{}", self.name(), n));
+       let backup = |n: usize| self.path_with_filename(format!("{}-realmfs.img.{}", self.name(), n));
 
         for i in (1..NUM_BACKUPS).rev() {
             let from = backup(i - 1);
@@ -558,17 +641,111 @@ impl RealmFS {
         Ok(())
     }
 
+    /// Directory where snapshots of this image are stored.
+    fn snapshot_dir(&self) -> PathBuf {
+        self.path_with_filename("snapshots").join(self.name())
+    }
+
+    /// Create a snapshot of this image called `name` which can later be restored
+    /// with `restore_snapshot()`.
+    ///
+    /// An unsealed image cannot be snapshotted while it is activated since it
+    /// may be modified during the copy.
+    pub fn snapshot(&self, name: &str, note: Option<&str>, progress: &mut dyn Progress) -> Result<Snapshot> {
+        let _lock = ImageLock::acquire(self.name(), "snapshot")?;
+        if !self.is_sealed() && self.is_activated() {
+            bail!("Cannot snapshot unsealed RealmFS '{}' because it is currently activated", self.name());
+        }
+        let dir = self.snapshot_dir();
+        let snapshot = Snapshot::new(&dir, name, self.metainfo().version(), self.is_sealed(), note)?;
+        if snapshot.image_path().exists() {
+            bail!("A snapshot named '{}' already exists for RealmFS '{}'", name, self.name());
+        }
+        fs::create_dir_all(&dir)?;
+
+        info!("Creating snapshot '{}' of RealmFS '{}'", name, self.name());
+        let result = Self::copy_image_file(self.path(), &snapshot.image_path(), progress)
+            .and_then(|_| Provenance::copy(self.path(), &snapshot.image_path()))
+            .and_then(|_| snapshot.save());
+
+        if let Err(e) = result {
+            let _ = snapshot.remove();
+            return Err(e);
+        }
+        Ok(snapshot)
+    }
+
+    /// Return all snapshots of this image ordered from oldest to newest.
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        Snapshot::load_all(&self.snapshot_dir())
+    }
+
+    /// Replace this image with the snapshot called `name`.
+    ///
+    /// The current image is kept as a backup copy in the same way as when an image
+    /// is replaced by `rotate()`, so a restore can itself be undone.
+    pub fn restore_snapshot(&self, name: &str, progress: &mut dyn Progress) -> Result<()> {
+        if self.is_activated() {
+            bail!("Cannot restore snapshot because RealmFS '{}' is currently activated", self.name());
+        }
+        let _lock = ImageLock::acquire(self.name(), "restore")?;
+        let snapshot = Snapshot::load(&self.snapshot_dir(), name)?;
+
+        let tmp = self.path_with_extension("restore");
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
+        }
+        info!("Restoring RealmFS '{}' from snapshot '{}'", self.name(), name);
+        let result = Self::copy_image_file(&snapshot.image_path(), &tmp, progress)
+            .and_then(|_| Self::load_realmfs_header(&tmp))
+            .and_then(|header| {
+                if header.metainfo().realmfs_name() != Some(self.name()) {
+                    bail!("snapshot image is not an image of RealmFS '{}'", self.name());
+                }
+                self.rotate(&tmp)
+            })
+            .and_then(|_| Provenance::copy(&snapshot.image_path(), self.path()));
+
+        if let Err(e) = result {
+            if tmp.exists() {
+                let _ = fs::remove_file(&tmp);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Remove the snapshot called `name`.
+    pub fn remove_snapshot(&self, name: &str) -> Result<()> {
+        Snapshot::load(&self.snapshot_dir(), name)?.remove()
+    }
+
     pub fn auto_resize_size(&self) -> Option<ResizeSize> {
         ImageResizer::auto_resize_size(self)
     }
 
-    pub fn resize_grow_to(&self, size: ResizeSize) -> Result<()> {
+    pub fn resize_grow_to(&self, size: ResizeSize, progress: &mut dyn Progress) -> Result<()> {
         info!("Resizing to {} blocks", size.nblocks());
-        ImageResizer::new(self).grow_to(size)
+        let _lock = ImageLock::acquire(self.name(), "resize")?;
+        ImageResizer::new(self).grow_to(size, progress)
+    }
+
+    pub fn resize_grow_by(&self, size: ResizeSize, progress: &mut dyn Progress) -> Result<()> {
+        let _lock = ImageLock::acquire(self.name(), "resize")?;
+        ImageResizer::new(self).grow_by(size, progress)
+    }
+
+    /// Shrink this unsealed image to `size`, or to the minimum size of the filesystem
+    /// plus some free space if `size` is `None`. See `ImageResizer::shrink()`
+    pub fn shrink(&self, size: Option<ResizeSize>, progress: &mut dyn Progress) -> Result<()> {
+        let _lock = ImageLock::acquire(self.name(), "shrink")?;
+        ImageResizer::new(self).shrink(size, progress)
     }
 
-    pub fn resize_grow_by(&self, size: ResizeSize) -> Result<()> {
-        ImageResizer::new(self).grow_by(size)
+    /// Release space used by free filesystem blocks from the image file.
+    pub fn compact(&self) -> Result<()> {
+        let _lock = ImageLock::acquire(self.name(), "compact")?;
+        ImageResizer::new(self).compact()
     }
 
     pub fn free_size_blocks(&self) -> Result<usize> {
@@ -0,0 +1,286 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path,PathBuf};
+
+use sodiumoxide::crypto::hash::sha256;
+
+use crate::{Result,RealmFS,RealmManager,ResizeSize,Progress};
+
+// Location where the staged recipe files and build script are mounted inside the update container
+const RECIPE_MOUNT: &str = "/run/citadel-recipe";
+
+///
+/// A declarative description of how to build a RealmFS image.
+///
+/// A recipe is a TOML file which names the new image and the RealmFS it is
+/// forked from, and lists packages to install, files to copy into the image
+/// and commands to run:
+///
+/// ```text
+/// name = "devtools"
+/// base = "base"
+/// size = "8g"
+/// packages = [ "git", "build-essential", "vim" ]
+/// commands = [ "update-alternatives --set editor /usr/bin/vim.basic" ]
+///
+/// [[files]]
+/// source = "files/gitconfig"
+/// target = "/etc/gitconfig"
+/// mode = "0644"
+/// ```
+///
+/// `source` paths are relative to the directory containing the recipe file.
+///
+/// The hash of the recipe (including the contents of all files it copies) is
+/// stored in the metainfo of the image it builds so that the image can later
+/// be compared with the current version of the recipe.
+///
+#[derive(Deserialize)]
+pub struct RealmFSRecipe {
+    name: String,
+    base: String,
+    size: Option<String>,
+    #[serde(default)]
+    packages: Vec<String>,
+    #[serde(default)]
+    files: Vec<RecipeFile>,
+    #[serde(default)]
+    commands: Vec<String>,
+
+    #[serde(skip)]
+    basedir: PathBuf,
+    #[serde(skip)]
+    hash: String,
+}
+
+#[derive(Deserialize)]
+struct RecipeFile {
+    source: String,
+    target: String,
+    mode: Option<String>,
+}
+
+impl RecipeFile {
+    fn mode(&self) -> &str {
+        self.mode.as_deref().unwrap_or("0644")
+    }
+}
+
+impl RealmFSRecipe {
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .map_err(|e| format_err!("failed to read recipe file {}: {}", path.display(), e))?;
+        let mut recipe = toml::from_slice::<RealmFSRecipe>(&bytes)
+            .map_err(|e| format_err!("failed to parse recipe file {}: {}", path.display(), e))?;
+        recipe.basedir = path.parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_default();
+        recipe.validate()?;
+        recipe.hash = recipe.calculate_hash(&bytes)?;
+        Ok(recipe)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !RealmFS::is_valid_name(&self.name) {
+            bail!("recipe has invalid RealmFS name '{}'", self.name);
+        }
+        if !RealmFS::is_valid_name(&self.base) {
+            bail!("recipe has invalid base RealmFS name '{}'", self.base);
+        }
+        self.size()?;
+        for file in &self.files {
+            if !file.target.starts_with('/') {
+                bail!("recipe file target '{}' is not an absolute path", file.target);
+            }
+            let mode = file.mode();
+            if mode.is_empty() || mode.len() > 4 || !mode.chars().all(|c| c.is_digit(8)) {
+                bail!("recipe file '{}' has invalid mode '{}'", file.source, mode);
+            }
+        }
+        Ok(())
+    }
+
+    fn calculate_hash(&self, recipe_bytes: &[u8]) -> Result<String> {
+        let mut state = sha256::State::new();
+        state.update(recipe_bytes);
+        for file in &self.files {
+            let path = self.source_path(file);
+            let contents = fs::read(&path)
+                .map_err(|e| format_err!("failed to read recipe file {}: {}", path.display(), e))?;
+            state.update(&contents);
+        }
+        Ok(hex::encode(state.finalize().as_ref()))
+    }
+
+    /// Name of the RealmFS image built by this recipe.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the RealmFS image which is forked to build this recipe.
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    /// Hex encoded sha256 hash of the recipe and all files it copies.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn size(&self) -> Result<Option<ResizeSize>> {
+        match self.size {
+            Some(ref size) => Ok(Some(ResizeSize::parse(size)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return `true` if `realmfs` was built from the current version of this recipe.
+    pub fn is_current(&self, realmfs: &RealmFS) -> bool {
+        realmfs.metainfo().recipe_hash() == Some(self.hash())
+    }
+
+    ///
+    /// Build a new sealed RealmFS image from this recipe.
+    ///
+    /// The base image is forked to an unsealed image, the recipe is applied by running
+    /// a generated script in the update container and the new image is then sealed.
+    /// If any step fails the new image is removed.
+    ///
+    pub fn build(&self, manager: &RealmManager, progress: &mut dyn Progress) -> Result<RealmFS> {
+        let base = manager.realmfs_by_name(&self.base)
+            .ok_or_else(|| format_err!("base RealmFS '{}' does not exist", self.base))?;
+        if manager.realmfs_name_exists(&self.name) {
+            bail!("RealmFS '{}' already exists", self.name);
+        }
+        if !base.has_sealing_keys() {
+            bail!("Cannot build RealmFS '{}' because no sealing keys are available", self.name);
+        }
+
+        let realmfs = base.fork_unsealed(&self.name)?;
+        if let Err(e) = self.apply(&realmfs, progress) {
+            if let Err(err) = manager.delete_realmfs(&realmfs) {
+                warn!("failed to remove RealmFS '{}' after build failed: {}", self.name, err);
+            }
+            return Err(e);
+        }
+        Ok(realmfs)
+    }
+
+    fn apply(&self, realmfs: &RealmFS, progress: &mut dyn Progress) -> Result<()> {
+        if let Some(size) = self.size()? {
+            realmfs.resize_grow_to(size, progress)?;
+        }
+
+        // Staged in a private directory rather than a predictable path in /tmp since it
+        // is bind mounted into the container and the build script is run as root
+        let staging = Path::new(RealmFS::BASE_PATH).join(format!(".recipe-{}", self.name));
+        let result = self.stage(&staging).and_then(|_| {
+            let mut update = realmfs.update();
+            update.bind_ro(&staging, RECIPE_MOUNT);
+            let log = update.default_log_path();
+            update.run_script(&staging.join("build.sh"), &log, progress)
+        });
+        if staging.exists() {
+            if let Err(e) = fs::remove_dir_all(&staging) {
+                warn!("failed to remove recipe staging directory {}: {}", staging.display(), e);
+            }
+        }
+        result?;
+
+        realmfs.set_recipe_hash(&self.hash)?;
+        realmfs.seal(None)
+    }
+
+    // Copy the recipe files and write the build script into the directory `staging`
+    fn stage(&self, staging: &Path) -> Result<()> {
+        if staging.exists() {
+            fs::remove_dir_all(staging)?;
+        }
+        fs::DirBuilder::new().mode(0o700).create(staging)?;
+        let files = staging.join("files");
+        fs::create_dir(&files)?;
+        for (idx, file) in self.files.iter().enumerate() {
+            fs::copy(self.source_path(file), files.join(idx.to_string()))?;
+        }
+        fs::write(staging.join("build.sh"), self.build_script())?;
+        Ok(())
+    }
+
+    fn source_path(&self, file: &RecipeFile) -> PathBuf {
+        self.basedir.join(&file.source)
+    }
+
+    fn build_script(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        writeln!(v, "set -e").unwrap();
+        writeln!(v, "export DEBIAN_FRONTEND=noninteractive").unwrap();
+        if !self.packages.is_empty() {
+            let packages = self.packages.iter()
+                .map(|p| shell_quote(p))
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(v, "apt-get update").unwrap();
+            writeln!(v, "apt-get install -y {}", packages).unwrap();
+            writeln!(v, "apt-get clean").unwrap();
+        }
+        for (idx, file) in self.files.iter().enumerate() {
+            writeln!(v, "install -D -m {} {}/files/{} {}", file.mode(), RECIPE_MOUNT, idx, shell_quote(&file.target)).unwrap();
+        }
+        for command in &self.commands {
+            writeln!(v, "{}", command).unwrap();
+        }
+        v
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::TestDir;
+
+    fn write_recipe(dir: &Path, recipe: &str) -> Result<RealmFSRecipe> {
+        fs::create_dir_all(dir.join("files")).unwrap();
+        fs::write(dir.join("files/gitconfig"), "[core]\n").unwrap();
+        fs::write(dir.join("recipe.toml"), recipe).unwrap();
+        RealmFSRecipe::load(dir.join("recipe.toml"))
+    }
+
+    #[test]
+    fn load_recipe() {
+        let tmp = TestDir::new("recipe-test");
+        let dir = tmp.path();
+        let recipe = write_recipe(dir, r#"
+            name = "devtools"
+            base = "base"
+            size = "8g"
+            packages = [ "git", "it's" ]
+            commands = [ "echo done" ]
+
+            [[files]]
+            source = "files/gitconfig"
+            target = "/etc/gitconfig"
+            "#).unwrap();
+
+        let script = String::from_utf8(recipe.build_script()).unwrap();
+        assert!(script.contains("apt-get install -y 'git' 'it'\\''s'\n"));
+        assert!(script.contains("install -D -m 0644 /run/citadel-recipe/files/0 '/etc/gitconfig'\n"));
+        assert!(script.ends_with("echo done\n"));
+        assert_eq!(recipe.hash().len(), 64);
+
+        // hash includes the contents of copied files
+        let hash = recipe.hash().to_string();
+        fs::write(dir.join("files/gitconfig"), "[user]\n").unwrap();
+        let recipe = RealmFSRecipe::load(dir.join("recipe.toml")).unwrap();
+        assert_ne!(recipe.hash(), hash);
+
+        assert!(write_recipe(dir, "name = \"devtools\"\nbase = \"base\"\nsize = \"8x\"\n").is_err());
+        assert!(write_recipe(dir, "name = \"-bad\"\nbase = \"base\"\n").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use crate::{Result,RealmFS,RealmManager,Mounts,LoopDevice};
+use crate::verity::Verity;
+use super::activator::Activation;
+use super::mountpoint::Mountpoint;
+use super::image_lock::ImageLock;
+
+// Directory containing device-mapper devices
+const DEV_MAPPER: &str = "/dev/mapper";
+
+// Prefix of the names of dm-verity devices created for sealed RealmFS images
+const VERITY_DEVICE_PREFIX: &str = "verity-realmfs-";
+
+///
+/// Brings the RealmFS activation state of the system back in line with the RealmFS
+/// images known to a `RealmManager`.
+///
+/// After a crash or an interrupted operation the system may be left with activations
+/// which no `RealmFS` knows about. A reconciliation pass examines the mountpoints in
+/// `RealmFS::RUN_DIRECTORY`, the dm-verity devices in `/dev/mapper` and the loop
+/// devices attached to RealmFS image files and:
+///
+///   * Re-adopts activations which are mounted where the corresponding `RealmFS`
+///     expects them, by reloading the activation state of every `RealmFS`.
+///   * Tears down mounted activations which do not belong to the current version
+///     of any `RealmFS` image (for example the image was deleted or sealed again
+///     with a new verity root) and which are not used by a running realm or by an
+///     operation holding the lock on the image, such as an update.
+///
+/// In exclusive mode, which may only be used when no other RealmFS operation can
+/// be running (such as during boot), it also:
+///
+///   * Removes mountpoint directories which have nothing mounted on them.
+///   * Closes dm-verity devices which are not mounted.
+///   * Detaches loop devices attached to unsealed images or update copies which
+///     are not mounted.
+///   * Removes copies of images left behind by interrupted updates
+///     (`name-realmfs.img.update`).
+///
+/// These states also exist briefly while an image is being activated, resized or
+/// updated, which is why they are only repaired in exclusive mode.
+///
+pub struct ActivationReconciler<'a> {
+    manager: &'a RealmManager,
+    exclusive: bool,
+    repaired: usize,
+}
+
+impl <'a> ActivationReconciler<'a> {
+
+    pub fn new(manager: &'a RealmManager) -> Self {
+        ActivationReconciler { manager, exclusive: false, repaired: 0 }
+    }
+
+    /// Also repair states which only occur transiently while some other RealmFS
+    /// operation is running.
+    pub fn exclusive(mut self, exclusive: bool) -> Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Perform reconciliation and return the number of stale mountpoints, devices
+    /// and files which were removed.
+    pub fn run(mut self) -> Result<usize> {
+        let realmfs_list = self.manager.realmfs_list();
+        self.reconcile_mountpoints(&realmfs_list)?;
+        if self.exclusive {
+            self.reconcile_verity_devices()?;
+            self.reconcile_loop_devices(&realmfs_list)?;
+        }
+        for realmfs in &realmfs_list {
+            realmfs.load_activation();
+        }
+        if self.repaired > 0 {
+            info!("Removed {} stale RealmFS mountpoints, devices or files", self.repaired);
+        }
+        Ok(self.repaired)
+    }
+
+    fn reconcile_mountpoints(&mut self, realmfs_list: &[RealmFS]) -> Result<()> {
+        if !Path::new(RealmFS::RUN_DIRECTORY).exists() {
+            return Ok(());
+        }
+        let expected = realmfs_list.iter()
+            .flat_map(Self::expected_mountpoints)
+            .collect::<HashSet<_>>();
+        let active = self.manager.active_mountpoints();
+        let mounted = Self::mounted_targets()?;
+
+        for mountpoint in Mountpoint::all_mountpoints()? {
+            if !mounted.contains(mountpoint.path()) {
+                if self.exclusive {
+                    info!("Removing stale mountpoint directory {}", mountpoint);
+                    self.repair(fs::remove_dir(mountpoint.path()).map_err(Into::into));
+                }
+            } else if !expected.contains(&mountpoint) && !active.contains(&mountpoint) && !Self::is_locked(&mountpoint) {
+                info!("Deactivating stale RealmFS activation mounted at {}", mountpoint);
+                let result = match Activation::for_mountpoint(&mountpoint) {
+                    Some(activation) => activation.deactivate(&active).map(|_| ()),
+                    None => mountpoint.deactivate(),
+                };
+                self.repair(result);
+            }
+        }
+        Ok(())
+    }
+
+    // Return true if the image `mountpoint` belongs to is locked by a running operation,
+    // such as an update which has mounted a copy of the image as 'name-update'.
+    fn is_locked(mountpoint: &Mountpoint) -> bool {
+        let realmfs = mountpoint.realmfs();
+        let name = realmfs.strip_suffix("-update").unwrap_or(realmfs);
+        ImageLock::is_held(name)
+    }
+
+    // The mountpoints which an activation of the current version of `realmfs` would use
+    fn expected_mountpoints(realmfs: &RealmFS) -> Vec<Mountpoint> {
+        if realmfs.is_sealed() {
+            vec![Mountpoint::new(realmfs.name(), &realmfs.metainfo().verity_tag())]
+        } else {
+            let (ro, rw) = Mountpoint::new_loop_pair(realmfs.name());
+            vec![ro, rw]
+        }
+    }
+
+    fn reconcile_verity_devices(&mut self) -> Result<()> {
+        if !Path::new(DEV_MAPPER).exists() {
+            return Ok(());
+        }
+        let mounted = Self::mounted_sources()?;
+        for entry in fs::read_dir(DEV_MAPPER)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|s| s.to_str()) {
+                Some(name) if name.starts_with(VERITY_DEVICE_PREFIX) => name,
+                _ => continue,
+            };
+            if !mounted.contains(&path) {
+                info!("Closing stale verity device {}", name);
+                self.repair(Verity::close_device(name));
+            }
+        }
+        Ok(())
+    }
+
+    fn reconcile_loop_devices(&mut self, realmfs_list: &[RealmFS]) -> Result<()> {
+        let mounted = Self::mounted_sources()?;
+
+        // Loop devices for sealed images are owned by their dm-verity device
+        for realmfs in realmfs_list.iter().filter(|r| !r.is_sealed()) {
+            self.detach_unmounted(realmfs.path(), &mounted)?;
+        }
+
+        for path in Self::update_copies()? {
+            if self.detach_unmounted(&path, &mounted)? {
+                info!("Removing image {} left by interrupted update", path.display());
+                self.repair(fs::remove_file(&path).map_err(Into::into));
+            }
+        }
+        Ok(())
+    }
+
+    // Detach all loop devices for `image` which are not mounted. Returns `true` if
+    // no loop devices for `image` remain attached.
+    fn detach_unmounted(&mut self, image: &Path, mounted: &HashSet<PathBuf>) -> Result<bool> {
+        let mut unused = true;
+        for device in LoopDevice::find_devices_for(image)? {
+            if mounted.contains(device.device()) {
+                unused = false;
+            } else {
+                info!("Detaching stale loop device {} for {}", device, image.display());
+                self.repair(device.detach());
+            }
+        }
+        Ok(unused)
+    }
+
+    fn update_copies() -> Result<Vec<PathBuf>> {
+        let mut v = Vec::new();
+        if !Path::new(RealmFS::BASE_PATH).exists() {
+            return Ok(v);
+        }
+        for entry in fs::read_dir(RealmFS::BASE_PATH)? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with("-realmfs.img.update") {
+                v.push(path);
+            }
+        }
+        Ok(v)
+    }
+
+    fn mounted_targets() -> Result<HashSet<PathBuf>> {
+        Ok(Mounts::load()?.mounts().map(|m| m.target_path().to_path_buf()).collect())
+    }
+
+    fn mounted_sources() -> Result<HashSet<PathBuf>> {
+        Ok(Mounts::load()?.mounts().map(|m| m.source_path().to_path_buf()).collect())
+    }
+
+    fn repair(&mut self, result: Result<()>) {
+        match result {
+            Ok(()) => self.repaired += 1,
+            Err(e) => warn!("failed to remove stale RealmFS state: {}", e),
+        }
+    }
+}
@@ -1,16 +1,18 @@
 use std::fs::{File,OpenOptions};
 use std::io::{Read,Seek,SeekFrom};
 use std::path::Path;
+use std::process::Command;
 
 use byteorder::{ByteOrder,LittleEndian};
 
-use crate::{RealmFS,Result,LoopDevice};
+use crate::{RealmFS,Result,LoopDevice,Exec,Progress};
 
 const BLOCK_SIZE: usize  = 4096;
 const BLOCKS_PER_MEG: usize = (1024 * 1024) / BLOCK_SIZE;
 const BLOCKS_PER_GIG: usize = 1024 * BLOCKS_PER_MEG;
 
 const RESIZE2FS: &str = "resize2fs";
+const E2FSCK: &str = "e2fsck";
 
 // If less than 1gb remaining space
 const AUTO_RESIZE_MINIMUM_FREE: ResizeSize = ResizeSize(BLOCKS_PER_GIG);
@@ -38,6 +40,27 @@ impl ResizeSize {
         ResizeSize(n)
     }
 
+    /// Parse a size such as "4g", "512m" or "100000".
+    ///
+    /// The size can be followed by a 'g' or 'm' character to indicate a quantity
+    /// of gigabytes or megabytes. If no unit is provided the size is measured
+    /// in blocks of 4096 bytes.
+    pub fn parse(s: &str) -> Result<Self> {
+        let unit = s.chars().last().filter(|c| c.is_alphabetic());
+        let size = s.chars()
+            .take_while(|c| c.is_numeric())
+            .collect::<String>()
+            .parse::<usize>()
+            .map_err(|_| format_err!("Unable to parse size value '{}'",s))?;
+
+        match unit {
+            Some('g') | Some('G') => Ok(ResizeSize::gigs(size)),
+            Some('m') | Some('M') => Ok(ResizeSize::megs(size)),
+            Some(c) => Err(format_err!("Unknown size unit '{}'", c)),
+            None => Ok(ResizeSize::blocks(size)),
+        }
+    }
+
     pub fn nblocks(&self) -> usize {
         self.0
     }
@@ -57,28 +80,124 @@ impl <'a> ImageResizer<'a> {
         ImageResizer { image }
     }
 
-    pub fn grow_to(&mut self, size: ResizeSize) -> Result<()> {
+    pub fn grow_to(&mut self, size: ResizeSize, progress: &mut dyn Progress) -> Result<()> {
         let target_nblocks = size.nblocks();
         let current_nblocks = self.image.metainfo_nblocks();
         if current_nblocks >= target_nblocks {
             info!("RealmFS image is already larger than requested size, doing nothing");
         } else {
             let size = ResizeSize::blocks(target_nblocks - current_nblocks);
-            self.grow_by(size)?;
+            self.grow_by(size, progress)?;
         }
         Ok(())
     }
 
-    pub fn grow_by(&mut self, size: ResizeSize) -> Result<()> {
+    pub fn grow_by(&mut self, size: ResizeSize, progress: &mut dyn Progress) -> Result<()> {
         let nblocks = size.nblocks();
         let new_nblocks = self.image.metainfo_nblocks() + nblocks;
         if self.image.is_sealed() {
             bail!("Cannot resize sealed image '{}'. unseal first", self.image.name());
         }
-        self.resize(new_nblocks)
+        self.resize(new_nblocks, progress)
+    }
+
+    ///
+    /// Reduce the size of the filesystem and the image file.
+    ///
+    /// If `size` is `None` the image is shrunk to the minimum size of the filesystem
+    /// with an additional 1gb of free space so that it will not immediately need to
+    /// be grown again. Free blocks are also discarded as with `compact()`.
+    ///
+    /// The image must be unsealed and cannot be shrunk while it is activated.
+    ///
+    pub fn shrink(&mut self, size: Option<ResizeSize>, progress: &mut dyn Progress) -> Result<()> {
+        self.check_offline("shrink")?;
+        let current_nblocks = self.image.metainfo().nblocks();
+
+        let new_nblocks = LoopDevice::with_loop(self.image.path(), Some(4096), false, |loopdev| {
+            Self::e2fsck(loopdev.device())?;
+            let target = match size {
+                Some(size) => size.nblocks(),
+                None => Self::minimum_size(loopdev.device())? + AUTO_RESIZE_MINIMUM_FREE.nblocks(),
+            };
+            if target >= current_nblocks {
+                info!("RealmFS image is already smaller than target size, not shrinking");
+                return Ok(None);
+            }
+            info!("Shrinking filesystem from {} blocks to {} blocks", current_nblocks, target);
+            Self::resize2fs(loopdev.device(), Some(target), progress)?;
+            Ok(Some(target))
+        })?;
+
+        if let Some(nblocks) = new_nblocks {
+            ImageResizer::resize_image_file(self.image.path(), nblocks + 1)?;
+            let owner = self.image.metainfo().realmfs_owner().map(|s| s.to_owned());
+            self.image.update_unsealed_metainfo(self.image.name(), nblocks, owner)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Discard all unused blocks of the filesystem so that the space they occupy in the
+    /// image file is released by punching holes in the file.
+    ///
+    pub fn compact(&mut self) -> Result<()> {
+        self.check_offline("compact")?;
+        LoopDevice::with_loop(self.image.path(), Some(4096), false, |loopdev| {
+            Self::e2fsck(loopdev.device())
+        })
+    }
+
+    fn check_offline(&self, operation: &str) -> Result<()> {
+        if self.image.is_sealed() {
+            bail!("Cannot {} sealed image '{}'. unseal first", operation, self.image.name());
+        }
+        if self.image.is_activated() {
+            bail!("Cannot {} image '{}' while it is activated", operation, self.image.name());
+        }
+        Ok(())
+    }
+
+    // Run a forced filesystem check which also discards all unused blocks. resize2fs
+    // requires a freshly checked filesystem before it will shrink it. An exit code of
+    // 1 means that errors were found and corrected.
+    fn e2fsck(device: &Path) -> Result<()> {
+        info!("Running e2fsck on {}", device.display());
+        let output = Command::new(E2FSCK)
+            .args(["-f", "-y", "-E", "discard"])
+            .arg(device)
+            .output()?;
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            verbose!("  {}", line);
+        }
+        match output.status.code() {
+            Some(0) => Ok(()),
+            Some(1) => {
+                warn!("e2fsck corrected errors on filesystem");
+                Ok(())
+            },
+            Some(code) => bail!("command {} failed with exit code: {}", E2FSCK, code),
+            None => bail!("command {} failed with no exit code", E2FSCK),
+        }
+    }
+
+    // Minimum size of filesystem in blocks as estimated by 'resize2fs -P'
+    fn minimum_size(device: &Path) -> Result<usize> {
+        let output = cmd_with_output!(RESIZE2FS, "-P {}", device.display())?;
+        Self::parse_minimum_size(&output)
+            .ok_or_else(|| format_err!("could not parse output of resize2fs -P: {}", output))
+    }
+
+    // Parse the line 'Estimated minimum size of the filesystem: 123456' from 'resize2fs -P'
+    fn parse_minimum_size(output: &str) -> Option<usize> {
+        output.lines()
+            .filter(|line| line.starts_with("Estimated minimum size"))
+            .filter_map(|line| line.split(':').nth(1))
+            .filter_map(|s| s.trim().parse::<usize>().ok())
+            .next()
     }
 
-    fn resize(&self, new_nblocks: usize) -> Result<()> {
+    fn resize(&self, new_nblocks: usize, progress: &mut dyn Progress) -> Result<()> {
         if new_nblocks < self.image.metainfo_nblocks() {
             bail!("Cannot shrink image")
         }
@@ -91,12 +210,11 @@ impl <'a> ImageResizer<'a> {
 
         if let Some(open_loop) = self.notify_open_loops()? {
             info!("Running resize2fs {:?}", open_loop);
-            cmd!(RESIZE2FS, "{}", open_loop.device().display())?;
+            Self::resize2fs(open_loop.device(), None, progress)?;
         } else {
             LoopDevice::with_loop(self.image.path(), Some(4096), false, |loopdev| {
                 info!("Running resize2fs {:?}", loopdev);
-                cmd!(RESIZE2FS, "{}", loopdev.device().display())?;
-                Ok(())
+                Self::resize2fs(loopdev.device(), None, progress)
             })?;
         }
         let owner = self.image.metainfo().realmfs_owner().map(|s| s.to_owned());
@@ -104,6 +222,39 @@ impl <'a> ImageResizer<'a> {
         Ok(())
     }
 
+    // Run resize2fs with the -p flag and report progress from the output. For each pass
+    // resize2fs prints a line 'Begin pass N (max = M)' followed by a label and a progress
+    // bar of 40 'X' characters which are written as the pass runs.
+    //
+    // If `nblocks` is `None` the filesystem is resized to fill the device.
+    fn resize2fs(device: &Path, nblocks: Option<usize>, progress: &mut dyn Progress) -> Result<()> {
+        let mut line = Vec::new();
+        let mut count = 0;
+        let size = nblocks.map(|n| n.to_string()).unwrap_or_default();
+        Exec::new(RESIZE2FS).run_with_stdout(format!("-p {} {}", device.display(), size), |output| {
+            for &b in output {
+                match b {
+                    b'X' => {
+                        count += 1;
+                        progress.update(count);
+                    },
+                    b'\n' => {
+                        if line.starts_with(b"Begin pass") {
+                            let line = String::from_utf8_lossy(&line);
+                            let pass = line.split_whitespace().nth(2).unwrap_or("");
+                            progress.begin(&format!("Resizing filesystem (pass {})", pass), 40);
+                            count = 0;
+                        }
+                        line.clear();
+                    },
+                    _ => line.push(b),
+                }
+            }
+        })?;
+        progress.end();
+        Ok(())
+    }
+
     fn resize_image_file(file: &Path, nblocks: usize) -> Result<()> {
         let len = nblocks * BLOCK_SIZE;
         info!("Resizing image file to {}", len);
@@ -183,3 +334,18 @@ impl Superblock {
         &self.0[offset..]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_minimum_size() {
+        let output = "resize2fs 1.44.5 (15-Dec-2018)\nEstimated minimum size of the filesystem: 123456\n";
+        assert_eq!(ImageResizer::parse_minimum_size(output), Some(123456));
+        assert_eq!(ImageResizer::parse_minimum_size("Estimated minimum size of the filesystem:  42"), Some(42));
+        assert_eq!(ImageResizer::parse_minimum_size("resize2fs 1.44.5 (15-Dec-2018)\n"), None);
+        assert_eq!(ImageResizer::parse_minimum_size("Estimated minimum size of the filesystem: many"), None);
+        assert_eq!(ImageResizer::parse_minimum_size("Filesystem UUID: 1234\n"), None);
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::time::{SystemTime,UNIX_EPOCH};
+
+use crate::{Result,util};
+use super::provenance::Provenance;
+
+// Maximum length of a snapshot name
+const MAX_SNAPSHOT_NAME_LEN: usize = 40;
+
+///
+/// A named copy of a RealmFS image which can later be restored.
+///
+/// Snapshots of an image are stored in a subdirectory named after the RealmFS
+/// below `snapshots/` in the directory containing the image. Each snapshot
+/// consists of a copy of the image file (`name.img`), which is a reflink copy
+/// when the underlying filesystem supports it, and a small TOML file
+/// (`name.toml`) recording when the snapshot was taken, the image version and
+/// an optional note:
+///
+/// ```text
+/// /storage/realms/realmfs-images/snapshots/main/before-upgrade.img
+/// /storage/realms/realmfs-images/snapshots/main/before-upgrade.toml
+/// ```
+///
+#[derive(Serialize,Deserialize,Clone)]
+pub struct Snapshot {
+    name: String,
+    timestamp: u64,
+    version: u32,
+    sealed: bool,
+    note: Option<String>,
+
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+impl Snapshot {
+
+    pub(crate) fn new(dir: &Path, name: &str, version: u32, sealed: bool, note: Option<&str>) -> Result<Self> {
+        Self::validate_name(name)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let note = note.filter(|s| !s.is_empty()).map(|s| s.to_string());
+        Ok(Snapshot {
+            name: name.to_string(), timestamp, version, sealed, note, dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Return `true` if `name` is a valid name for a snapshot.
+    ///
+    /// Snapshot names follow the same rules as RealmFS names.
+    pub fn is_valid_name(name: &str) -> bool {
+        util::is_valid_name(name, MAX_SNAPSHOT_NAME_LEN)
+    }
+
+    fn validate_name(name: &str) -> Result<()> {
+        if !Self::is_valid_name(name) {
+            bail!("Invalid snapshot name '{}'", name);
+        }
+        Ok(())
+    }
+
+    /// Load all snapshots stored in `dir` ordered from oldest to newest.
+    pub(crate) fn load_all(dir: &Path) -> Result<Vec<Snapshot>> {
+        let mut v = Vec::new();
+        if !dir.exists() {
+            return Ok(v);
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("toml") {
+                continue;
+            }
+            match Self::load_file(&path) {
+                Ok(snapshot) => v.push(snapshot),
+                Err(e) => warn!("Ignoring invalid snapshot file {}: {}", path.display(), e),
+            }
+        }
+        v.sort_by_key(|s| s.timestamp);
+        Ok(v)
+    }
+
+    /// Load the snapshot called `name` from `dir`.
+    pub(crate) fn load(dir: &Path, name: &str) -> Result<Snapshot> {
+        Self::validate_name(name)?;
+        let path = dir.join(format!("{}.toml", name));
+        if !path.exists() {
+            bail!("No snapshot named '{}' exists", name);
+        }
+        Self::load_file(&path)
+    }
+
+    fn load_file(path: &Path) -> Result<Snapshot> {
+        let s = fs::read_to_string(path)?;
+        let mut snapshot = toml::from_str::<Snapshot>(&s)?;
+        snapshot.dir = path.parent()
+            .ok_or_else(|| format_err!("snapshot file has no parent directory"))?
+            .to_path_buf();
+        if !snapshot.image_path().exists() {
+            bail!("snapshot image {} does not exist", snapshot.image_path().display());
+        }
+        Ok(snapshot)
+    }
+
+    pub(crate) fn save(&self) -> Result<()> {
+        fs::write(self.info_path(), toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Remove the image and information file for this snapshot.
+    pub(crate) fn remove(&self) -> Result<()> {
+        if self.image_path().exists() {
+            fs::remove_file(self.image_path())?;
+        }
+        Provenance::remove(&self.image_path())?;
+        if self.info_path().exists() {
+            fs::remove_file(self.info_path())?;
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Time the snapshot was taken in seconds since the epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Version from the metainfo of the image when the snapshot was taken.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Return `true` if the image was sealed when the snapshot was taken.
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+
+    /// Path to the copy of the RealmFS image file for this snapshot.
+    pub fn image_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.img", self.name))
+    }
+
+    fn info_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.toml", self.name))
+    }
+}
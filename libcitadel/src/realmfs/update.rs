@@ -1,11 +1,17 @@
-use std::fs;
-use std::process::Command;
+use std::fs::{self,File};
+use std::path::{Path,PathBuf};
+use std::process::{Command,ExitStatus,Stdio};
 
-use crate::{Result, RealmFS };
+use crate::{Result, RealmFS, Progress};
 use crate::realmfs::Mountpoint;
+use super::provenance::Provenance;
+use super::image_lock::ImageLock;
 use crate::realm::BridgeAllocator;
 use crate::ResizeSize;
 
+// Location where an update script is mounted inside the update container
+const UPDATE_SCRIPT_PATH: &str = "/run/citadel-update-script";
+
 enum UpdateType {
     NotSetup,
     Sealed(RealmFS),
@@ -16,14 +22,24 @@ pub struct Update<'a> {
     realmfs: &'a RealmFS,
     network_allocated: bool,
     update_type: UpdateType,
+    binds: Vec<String>,
+    lock: Option<ImageLock>,
 }
 
 impl <'a> Update<'a> {
     pub fn new(realmfs: &'a RealmFS) -> Self {
-        Update { realmfs, network_allocated: false, update_type: UpdateType::NotSetup }
+        Update { realmfs, network_allocated: false, update_type: UpdateType::NotSetup, binds: Vec::new(), lock: None }
+    }
+
+    /// Make the file or directory `source` available read-only at `target` inside the update container.
+    pub fn bind_ro(&mut self, source: &Path, target: &str) {
+        self.binds.push(format!("--bind-ro={}:{}", source.display(), target));
     }
 
     pub fn setup(&mut self) -> Result<()> {
+        // Held until cleanup() so that the image is not changed by another operation
+        // while the update is in progress.
+        self.lock = Some(ImageLock::acquire(self.realmfs.name(), "update")?);
         self.update_type = self.create_update_type()?;
         Ok(())
     }
@@ -32,8 +48,8 @@ impl <'a> Update<'a> {
         self.target_image().auto_resize_size()
     }
 
-    pub fn apply_resize(&self, size: ResizeSize) -> Result<()> {
-        self.target_image().resize_grow_to(size)
+    pub fn apply_resize(&self, size: ResizeSize, progress: &mut dyn Progress) -> Result<()> {
+        self.target_image().resize_grow_to(size, progress)
     }
 
     fn target_image(&self) -> &RealmFS {
@@ -67,6 +83,71 @@ impl <'a> Update<'a> {
     }
 
     pub fn run_update_shell(&mut self, command: &str) -> Result<()> {
+        self.run_in_container(command, &[], None)?;
+        Ok(())
+    }
+
+    ///
+    /// Run `script` non-interactively inside the update container and write
+    /// all output from the script to the file `log`.
+    ///
+    /// If the script exits with a non-zero status the update is cleaned up
+    /// without being applied and an error is returned.
+    ///
+    pub fn run_update_script(&mut self, script: &Path, log: &Path) -> Result<()> {
+        let script = script.canonicalize()
+            .map_err(|e| format_err!("could not find update script {}: {}", script.display(), e))?;
+        let log_file = File::create(log)
+            .map_err(|e| format_err!("could not create log file {}: {}", log.display(), e))?;
+
+        let bind = format!("--bind-ro={}:{}", script.display(), UPDATE_SCRIPT_PATH);
+        let command = format!("/usr/libexec/configure-host0.sh && exec /bin/bash {}", UPDATE_SCRIPT_PATH);
+
+        info!("Running update script {} on RealmFS {} (log: {})", script.display(), self.realmfs.name(), log.display());
+        let status = self.run_in_container(&command, &[bind], Some(log_file))?;
+        if !status.success() {
+            self.cleanup()?;
+            bail!("update script {} failed with {} (see {})", script.display(), status, log.display());
+        }
+        Ok(())
+    }
+
+    ///
+    /// Perform a complete non-interactive update of the image by running `script`
+    /// in the update container.
+    ///
+    /// The image is first resized if it is running low on free space. If the script
+    /// succeeds the update is applied (a sealed image is resealed and replaces the
+    /// original image), otherwise the update is discarded. Note that an unsealed image
+    /// is updated in place so changes made by a failed script are not reverted.
+    ///
+    pub fn run_script(&mut self, script: &Path, log: &Path, progress: &mut dyn Progress) -> Result<()> {
+        self.setup()?;
+        let result = self.resize_if_needed(progress)
+            .and_then(|_| self.run_update_script(script, log))
+            .and_then(|_| self.apply_update());
+
+        if let Err(e) = result {
+            let _ = self.cleanup();
+            return Err(e);
+        }
+        self.cleanup()
+    }
+
+    fn resize_if_needed(&self, progress: &mut dyn Progress) -> Result<()> {
+        if let Some(size) = self.auto_resize_size() {
+            info!("Resizing RealmFS {} before update", self.realmfs.name());
+            self.apply_resize(size, progress)?;
+        }
+        Ok(())
+    }
+
+    /// Default location of the log file written by `run_update_script()`.
+    pub fn default_log_path(&self) -> PathBuf {
+        self.realmfs.path_with_extension("update.log")
+    }
+
+    fn run_in_container(&mut self, command: &str, extra_args: &[String], output: Option<File>) -> Result<ExitStatus> {
 
         let mountpoint = self.mountpoint().map_err(|e| {
             let _ = self.cleanup();
@@ -77,23 +158,32 @@ impl <'a> Update<'a> {
         let addr = alloc.allocate_address_for(&self.name())?;
         let gw = alloc.gateway();
         self.network_allocated = true;
-        Command::new("/usr/bin/systemd-nspawn")
-            .arg(format!("--setenv=IFCONFIG_IP={}", addr))
+        let mut cmd = Command::new("/usr/bin/systemd-nspawn");
+        cmd.arg(format!("--setenv=IFCONFIG_IP={}", addr))
             .arg(format!("--setenv=IFCONFIG_GW={}", gw))
             .arg("--quiet")
             .arg(format!("--machine={}", self.name()))
             .arg(format!("--directory={}", mountpoint))
             .arg("--network-zone=clear")
+            .args(&self.binds)
+            .args(extra_args)
             .arg("/bin/bash")
             .arg("-c")
-            .arg(command)
-            .status()
+            .arg(command);
+
+        if let Some(output) = output {
+            cmd.stdin(Stdio::null())
+                .stdout(output.try_clone()?)
+                .stderr(output);
+        }
+
+        let status = cmd.status()
             .map_err(|e| {
                 let _ = self.cleanup();
                 e
             })?;
         self.deactivate_update()?;
-        Ok(())
+        Ok(status)
     }
 
     fn deactivate_update(&self) -> Result<()> {
@@ -107,11 +197,16 @@ impl <'a> Update<'a> {
     pub fn apply_update(&mut self) -> Result<()> {
         match self.update_type {
             UpdateType::Sealed(ref update_image) => {
+                update_image.record_update()?;
                 update_image.seal(Some(self.realmfs.name()))?;
                 fs::rename(update_image.path(), self.realmfs.path())?;
+                Provenance::rename(update_image.path(), self.realmfs.path())?;
+                self.cleanup()
+            },
+            UpdateType::Unsealed => {
+                self.realmfs.record_update()?;
                 self.cleanup()
             },
-            UpdateType::Unsealed => self.cleanup(),
             UpdateType::NotSetup => Ok(()),
         }
     }
@@ -127,6 +222,7 @@ impl <'a> Update<'a> {
                 if update_image.path().exists() {
                     fs::remove_file(update_image.path())?;
                 }
+                Provenance::remove(update_image.path())?;
             },
             UpdateType::Unsealed => {
                 self.realmfs.deactivate()?;
@@ -134,6 +230,7 @@ impl <'a> Update<'a> {
             _ => {},
         }
         self.update_type = UpdateType::NotSetup;
+        self.lock = None;
 
         if self.network_allocated {
             BridgeAllocator::default_bridge()?
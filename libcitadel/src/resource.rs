@@ -1,9 +1,9 @@
-use std::fs::{self,File,DirEntry};
+use std::fs::{self,File,DirEntry,OpenOptions};
 use std::ffi::OsStr;
-use std::io::{self,Seek,SeekFrom};
+use std::io::{self,Seek,SeekFrom,Write,BufWriter};
 use std::path::{Path, PathBuf};
 
-use crate::{CommandLine, OsRelease, ImageHeader, MetaInfo, Result, Partition, Mounts, util, LoopDevice};
+use crate::{CommandLine, OsRelease, ImageHeader, MetaInfo, Result, Partition, Mounts, util, LoopDevice, BlockDelta, VersionFloor, Compression, Progress, NoProgress, ProgressReader};
 
 use failure::ResultExt;
 use std::sync::Arc;
@@ -130,20 +130,33 @@ impl ResourceImage {
         self.header.has_flag(ImageHeader::FLAG_HASH_TREE)
     }
 
-    pub fn decompress(&self) -> Result<()> {
+    /// Return the algorithm used to compress the data of this image.
+    pub fn compression(&self) -> Result<Compression> {
+        match self.metainfo().compression() {
+            Some(name) => Compression::from_name(name),
+            None => Ok(Compression::Xz),
+        }
+    }
+
+    pub fn decompress(&self, progress: &mut dyn Progress) -> Result<()> {
         if !self.is_compressed() {
             return Ok(())
         }
-        info!("decompressing image file {}", self.path().display());
+        let compression = self.compression()?;
+        info!("decompressing image file {} ({})", self.path().display(), compression);
         let mut reader = File::open(self.path())?;
+        let total = reader.metadata()?.len().saturating_sub(4096);
         reader.seek(SeekFrom::Start(4096))?;
 
-        let xzfile = self.path.with_extension("tmp.xz");
-        let mut out = File::create(&xzfile)?;
-        io::copy(&mut reader, &mut out)?;
-
-        util::xz_decompress(xzfile)?;
-        fs::rename(self.path.with_extension("tmp"), self.path())?;
+        let tmpfile = self.path.with_extension("tmp");
+        progress.begin("Decompressing image", total);
+        let result = compression.decompress_to(ProgressReader::new(reader, progress), &tmpfile);
+        progress.end();
+        if let Err(err) = result {
+            let _ = fs::remove_file(&tmpfile);
+            return Err(err);
+        }
+        fs::rename(&tmpfile, self.path())?;
 
         self.header.clear_flag(ImageHeader::FLAG_DATA_COMPRESSED);
         self.header.write_header_to(self.path())?;
@@ -151,30 +164,103 @@ impl ResourceImage {
         Ok(())
     }
 
-    pub fn write_to_partition(&self, partition: &Partition) -> Result<()> {
+    pub fn write_to_partition(&self, partition: &Partition, progress: &mut dyn Progress) -> Result<()> {
         if self.metainfo().image_type() != "rootfs" {
             bail!("Cannot write to partition, image type is not rootfs");
         }
 
         if !self.has_verity_hashtree() {
-            self.generate_verity_hashtree()?;
+            self.generate_verity_hashtree(progress)?;
         }
 
         info!("writing rootfs image to {}", partition.path().display());
-        cmd_with_output!("/bin/dd", "if={} of={} bs=4096 skip=1", self.path.display(), partition.path().display())?;
-
-        /*
-        let args = format!("if={} of={} bs=4096 skip=1",
-                           self.path.display(), partition.path().display());
-        util::exec_cmdline_quiet("/bin/dd", args)?;
-        */
+        self.copy_to_partition(partition, progress)?;
 
         self.header.set_status(ImageHeader::STATUS_NEW);
+        self.header.set_boot_tries(ImageHeader::DEFAULT_BOOT_TRIES);
         self.header.write_partition(partition.path())?;
 
         Ok(())
     }
 
+    // Copy everything following the header block of the image file to the start of the partition.
+    fn copy_to_partition(&self, partition: &Partition, progress: &mut dyn Progress) -> Result<()> {
+        let mut input = File::open(self.path())?;
+        let total = input.metadata()?.len().saturating_sub(4096);
+        input.seek(SeekFrom::Start(4096))?;
+        let output = OpenOptions::new().write(true).open(partition.path())?;
+        let mut output = BufWriter::with_capacity(1024 * 1024, output);
+
+        progress.begin("Writing image to partition", total);
+        io::copy(&mut ProgressReader::new(input, progress), &mut output)
+            .context(format!("error writing image to {}", partition.path().display()))?;
+        progress.end();
+
+        output.flush()?;
+        output.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Return `true` if this image is a block delta against another version of the image
+    pub fn is_delta(&self) -> bool {
+        self.metainfo().delta_base_root().is_some()
+    }
+
+    /// Apply this delta image to the data in partition `base` and write the result to `partition`.
+    ///
+    /// The data blocks of `base` are first copied to `partition` and then the blocks stored in the
+    /// delta are written over them. Finally the dm-verity hash tree is generated on `partition` and
+    /// the root hash is compared to the value in the metainfo of this image before the header is
+    /// written.
+    pub fn write_delta_to_partition(&self, base: &Partition, partition: &Partition, progress: &mut dyn Progress) -> Result<()> {
+        let metainfo = self.metainfo();
+        let base_root = match metainfo.delta_base_root() {
+            Some(root) => root,
+            None => bail!("Cannot apply image as delta, image is not a delta image"),
+        };
+        if metainfo.image_type() != "rootfs" {
+            bail!("Cannot write to partition, image type is not rootfs");
+        }
+        if !base.is_initialized() || base.metainfo().verity_root() != base_root {
+            bail!("Delta image cannot be applied to {}, base image does not match", base.path().display());
+        }
+
+        if self.is_compressed() {
+            self.decompress(progress)?;
+        }
+
+        // Make sure the partition cannot be booted if anything below fails
+        let old_header = ImageHeader::from_partition(partition.path())?;
+        if old_header.is_magic_valid() {
+            old_header.set_status(ImageHeader::STATUS_INVALID);
+            old_header.write_partition(partition.path())?;
+        }
+
+        let count = base.metainfo().nblocks().min(metainfo.nblocks());
+        info!("copying {} blocks from {} to {}", count, base.path().display(), partition.path().display());
+        cmd!("/bin/dd", "if={} of={} bs=4096 count={}", base.path().display(), partition.path().display(), count)?;
+
+        info!("applying delta to {}", partition.path().display());
+        let mut reader = File::open(self.path())?;
+        reader.seek(SeekFrom::Start(4096))?;
+        let nblocks = BlockDelta::apply(reader, partition.path())?;
+        if nblocks != metainfo.nblocks() {
+            bail!("Delta data contains {} blocks but metainfo nblocks is {}", nblocks, metainfo.nblocks());
+        }
+
+        info!("generating dm-verity hash tree on {}", partition.path().display());
+        let output = Verity::generate_partition_hashtree(partition, &metainfo, progress)?;
+        if output.root_hash() != Some(metainfo.verity_root()) {
+            bail!("Verity root hash of {} does not match metainfo after applying delta", partition.path().display());
+        }
+
+        self.header.set_flag(ImageHeader::FLAG_HASH_TREE);
+        self.header.set_status(ImageHeader::STATUS_NEW);
+        self.header.set_boot_tries(ImageHeader::DEFAULT_BOOT_TRIES);
+        self.header.write_partition(partition.path())?;
+        Ok(())
+    }
+
     fn mount_verity(&self) -> Result<()> {
         let verity_dev = self.setup_verity_device()?;
 
@@ -188,10 +274,11 @@ impl ResourceImage {
 
     pub fn setup_verity_device(&self) -> Result<PathBuf> {
         if !CommandLine::nosignatures() {
-            match self.header.public_key()? {
-                Some(pubkey) => {
-                    if !self.header.verify_signature(pubkey) {
-                        bail!("Header signature verification failed");
+            match self.header.public_keys()? {
+                Some(keys) => {
+                    let count = self.header.valid_signature_count(&keys);
+                    if count < keys.threshold() {
+                        bail!("Header signature verification failed ({} of {} required signatures valid)", count, keys.threshold());
                     }
                     info!("Image header signature is valid");
                 }
@@ -200,40 +287,38 @@ impl ResourceImage {
         }
         info!("Setting up dm-verity device for image");
         if !self.has_verity_hashtree() {
-            self.generate_verity_hashtree()?;
+            self.generate_verity_hashtree(&mut NoProgress)?;
         }
         let devname = self.verity().setup(&self.metainfo())?;
         Ok(Path::new("/dev/mapper").join(devname))
 //        verity::setup_image_device(self.path(), &self.metainfo())
     }
 
-    pub fn generate_verity_hashtree(&self) -> Result<()> {
+    pub fn generate_verity_hashtree(&self, progress: &mut dyn Progress) -> Result<()> {
         if self.has_verity_hashtree() {
             return Ok(())
         }
         if self.is_compressed() {
-            self.decompress()?;
+            self.decompress(progress)?;
         }
         info!("Generating dm-verity hash tree for image {}", self.path.display());
-//        verity::generate_image_hashtree(self.path(), self.metainfo().nblocks(), self.metainfo().verity_salt())?;
-        self.verity().generate_image_hashtree(&self.metainfo())?;
+        self.verity().generate_image_hashtree(&self.metainfo(), progress)?;
         self.header.set_flag(ImageHeader::FLAG_HASH_TREE);
         self.header.write_header_to(self.path())?;
         Ok(())
     }
 
-    pub fn verify_verity(&self) -> Result<bool> {
+    pub fn verify_verity(&self, progress: &mut dyn Progress) -> Result<bool> {
         if !self.has_verity_hashtree() {
-            self.generate_verity_hashtree()?;
+            self.generate_verity_hashtree(progress)?;
         }
         info!("Verifying dm-verity hash tree");
-        self.verity().verify(&self.metainfo())
-//        verity::verify_image(self.path(), &self.metainfo())
+        self.verity().verify(&self.metainfo(), progress)
     }
 
     pub fn generate_shasum(&self) -> Result<String> {
         if self.is_compressed() {
-            self.decompress()?;
+            self.decompress(&mut NoProgress)?;
         }
         info!("Calculating sha256 of image");
         let output = util::exec_cmdline_pipe_input("sha256sum", "-", self.path(), util::FileRange::Range{offset: 4096, len: self.metainfo().nblocks() * 4096})
@@ -250,7 +335,7 @@ impl ResourceImage {
         info!("loop mounting image to {} (noverity)", self.mount_path().display());
 
         if self.is_compressed() {
-            self.decompress()?;
+            self.decompress(&mut NoProgress)?;
         }
 
         let mount_path = self.mount_path();
@@ -264,6 +349,11 @@ impl ResourceImage {
         util::mount(&loopdev.device_str(), mount_path, Some("-oro"))
     }
 
+    /// Return `true` if this resource image is currently mounted.
+    pub fn is_mounted(&self) -> bool {
+        Mounts::is_target_mounted(self.mount_path()).unwrap_or(false)
+    }
+
     // Return the path at which to mount this resource image.
     fn mount_path(&self) -> PathBuf {
         let metainfo = self.metainfo();
@@ -351,7 +441,8 @@ impl ResourceImage {
 // Search directory for a resource image with the specified channel and image_type
 // in the image header metainfo.  If multiple matches are found, return the image
 // with the highest version number. If multiple images have the same highest version
-// number, return the image with the newest file creation time.
+// number, return the image with the newest file creation time. Images with a version
+// below the version floor are ignored.
 fn search_directory<P: AsRef<Path>>(dir: P, image_type: &str, channel: Option<&str>) -> Result<Option<ResourceImage>> {
     if !dir.as_ref().exists() {
         return Ok(None)
@@ -359,7 +450,16 @@ fn search_directory<P: AsRef<Path>>(dir: P, image_type: &str, channel: Option<&s
 
     let mut best = None;
 
+    let floor = VersionFloor::load_or_default();
     let mut matches = all_matching_images(dir.as_ref(), image_type, channel)?;
+    matches.retain(|image| {
+        let permitted = floor.permits(&image.metainfo());
+        if !permitted {
+            warn!("Ignoring image {} because version {} is below the minimum version for channel",
+                  image.path().display(), image.metainfo().version());
+        }
+        permitted
+    });
     debug!("Found {} matching images", matches.len());
 
     if channel.is_none() {
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use crate::{Result,MetaInfo,CommandLine};
+
+const FLOOR_FILE: &str = "/storage/citadel-state/version-floor";
+const BOOT_FLOOR_FILE: &str = "/sysroot/storage/citadel-state/version-floor";
+
+///
+/// The lowest version of each image type which may be booted or installed
+/// for a channel. This prevents an attacker from installing an older image
+/// which is correctly signed but has known vulnerabilities.
+///
+/// The floor is raised from the `min-version` field of the signed metainfo of
+/// an image once the image has been installed (or, for rootfs images, once the
+/// image has successfully booted) and is stored in /storage as a TOML document:
+///
+/// ```text
+/// [dev]
+/// rootfs = 12
+/// kernel = 4
+/// ```
+///
+/// The floor can be ignored by booting in recovery mode with the kernel command
+/// line variables `citadel.recovery citadel.allow-rollback`.
+///
+#[derive(Default)]
+pub struct VersionFloor {
+    floors: HashMap<String, HashMap<String, u32>>,
+}
+
+impl VersionFloor {
+
+    fn path() -> PathBuf {
+        // While booting /storage is mounted at /sysroot/storage
+        if !Path::new("/storage/citadel-state").exists() && Path::new("/sysroot/storage").exists() {
+            PathBuf::from(BOOT_FLOOR_FILE)
+        } else {
+            PathBuf::from(FLOOR_FILE)
+        }
+    }
+
+    /// Load the stored version floor, or an empty floor if none has been stored yet.
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let s = fs::read_to_string(&path)?;
+        let floors = toml::from_str(&s)
+            .map_err(|e| format_err!("failed to parse version floor file {}: {}", path.display(), e))?;
+        Ok(VersionFloor { floors })
+    }
+
+    /// Load the stored version floor, logging a warning and returning an
+    /// empty floor if it cannot be read.
+    pub fn load_or_default() -> Self {
+        match Self::load() {
+            Ok(floor) => floor,
+            Err(err) => {
+                warn!("Error loading version floor: {}", err);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, toml::to_string(&self.floors)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Return `true` if the version floor should be ignored because the system has been
+    /// booted in recovery mode with citadel.allow-rollback on the kernel command line.
+    pub fn override_enabled() -> bool {
+        CommandLine::recovery_mode() && CommandLine::allow_rollback()
+    }
+
+    pub fn min_version(&self, channel: &str, image_type: &str) -> u32 {
+        self.floors.get(channel)
+            .and_then(|m| m.get(image_type))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Raise the floor for `channel` and `image_type` to `version`. Return `true` if
+    /// the floor changed.
+    pub fn raise(&mut self, channel: &str, image_type: &str, version: u32) -> bool {
+        if version <= self.min_version(channel, image_type) {
+            return false;
+        }
+        self.floors.entry(channel.to_string())
+            .or_default()
+            .insert(image_type.to_string(), version);
+        true
+    }
+
+    /// Raise the floor to the `min-version` value in `metainfo` if present.
+    pub fn raise_from_metainfo(&mut self, metainfo: &MetaInfo) -> bool {
+        match metainfo.min_version() {
+            Some(version) => self.raise(metainfo.channel(), metainfo.image_type(), version),
+            None => false,
+        }
+    }
+
+    /// Load the stored floor, raise it from `metainfo` and save it if it changed.
+    pub fn update_from_metainfo(metainfo: &MetaInfo) -> Result<()> {
+        let mut floor = Self::load()?;
+        if floor.raise_from_metainfo(metainfo) {
+            info!("Raising minimum version of {} images for channel {} to {}",
+                  metainfo.image_type(), metainfo.channel(), floor.min_version(metainfo.channel(), metainfo.image_type()));
+            floor.save()?;
+        }
+        Ok(())
+    }
+
+    /// Return `true` if the image described by `metainfo` is not older than the floor.
+    pub fn allows(&self, metainfo: &MetaInfo) -> bool {
+        metainfo.version() >= self.min_version(metainfo.channel(), metainfo.image_type())
+    }
+
+    /// Like `allows()` but always return `true` if the override is enabled.
+    pub fn permits(&self, metainfo: &MetaInfo) -> bool {
+        if self.allows(metainfo) {
+            return true;
+        }
+        if Self::override_enabled() {
+            warn!("Allowing {} image version {} below minimum version {} because rollback override is enabled",
+                  metainfo.image_type(), metainfo.version(), self.min_version(metainfo.channel(), metainfo.image_type()));
+            return true;
+        }
+        false
+    }
+}
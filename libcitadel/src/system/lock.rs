@@ -1,5 +1,6 @@
 use std::fs::{self,File,OpenOptions};
-use std::io::{Error,ErrorKind};
+use std::io::{Error,ErrorKind,Seek,SeekFrom,Write};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path,PathBuf};
 
@@ -20,6 +21,63 @@ impl FileLock {
         Ok(flock)
     }
 
+    /// Acquire the lock without waiting. Returns `None` if the lock is currently held
+    /// by somebody else.
+    pub fn try_acquire<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let path = path.as_ref().to_path_buf();
+        for _ in 0..3 {
+            let file = Self::open_lockfile(&path)?;
+            if !Self::try_lock_file(&file)? {
+                return Ok(None);
+            }
+            // The previous holder may have removed the lockfile after we opened it
+            // and before we acquired the lock, in which case try again.
+            if Self::is_same_file(&file, &path) {
+                return Ok(Some(FileLock { file, path }));
+            }
+        }
+        Err(format_err!("unable to acquire lockfile {}", path.display() ))
+    }
+
+    /// Return `true` if the lock on the lockfile at `path` is currently held by somebody.
+    /// Unlike `try_acquire()` this never creates or removes the lockfile.
+    pub fn is_locked<P: AsRef<Path>>(path: P) -> Result<bool> {
+        let file = match Self::try_open_lockfile(path.as_ref())? {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        if !Self::try_lock_file(&file)? {
+            return Ok(true);
+        }
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(false)
+    }
+
+    /// Replace the content of the lockfile with `info`, such as a description of
+    /// the lock holder which can be read by others with `read_info()`.
+    pub fn write_info(&self, info: &str) -> Result<()> {
+        let mut file = &self.file;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(info.as_bytes())?;
+        Ok(())
+    }
+
+    /// Read the content of the lockfile at `path` written by the current holder
+    /// of the lock with `write_info()`.
+    pub fn read_info<P: AsRef<Path>>(path: P) -> Option<String> {
+        fs::read_to_string(path.as_ref()).ok()
+    }
+
+    fn is_same_file(file: &File, path: &Path) -> bool {
+        match (file.metadata(), path.metadata()) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+
     fn open_lockfile(path: &Path) -> Result<File> {
         if let Some(parent) = path.parent() {
             if !parent.exists() {
@@ -39,7 +97,7 @@ impl FileLock {
     }
 
     fn try_create_lockfile(path: &Path) -> Result<Option<File>> {
-        match OpenOptions::new().write(true).create_new(true).open(path) {
+        match OpenOptions::new().read(true).write(true).create_new(true).open(path) {
             Ok(file) => Ok(Some(file)),
             Err(ref e) if e.kind() == ErrorKind::AlreadyExists => Ok(None),
             Err(e) => Err(e.into()),
@@ -47,7 +105,7 @@ impl FileLock {
     }
 
     fn try_open_lockfile(path: &Path) -> Result<Option<File>> {
-        match File::open(path) {
+        match OpenOptions::new().read(true).write(true).open(path) {
             Ok(file) => Ok(Some(file)),
             Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
             Err(e) => Err(e.into()),
@@ -62,6 +120,17 @@ impl FileLock {
         self.flock(libc::LOCK_EX)
     }
 
+    fn try_lock_file(file: &File) -> Result<bool> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock {
+                return Ok(false);
+            }
+            return Err(err.into());
+        }
+        Ok(true)
+    }
+
     fn flock(&self, flag: libc::c_int) -> Result<()> {
         if unsafe { libc::flock(self.file.as_raw_fd(), flag) } < 0 {
             return Err(Error::last_os_error().into());
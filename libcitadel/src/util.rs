@@ -60,7 +60,7 @@ pub fn ensure_command_exists(cmd: &str) -> Result<()> {
 
 pub fn sha256<P: AsRef<Path>>(path: P) -> Result<String> {
     let path = path.as_ref();
-    let output = cmd_with_output!("/usr/bin/256sum", "{}", path.display())
+    let output = cmd_with_output!("/usr/bin/sha256sum", "{}", path.display())
         .context(format!("failed to calculate sha256 on {}", path.display()))?;
 
     let v: Vec<&str> = output.split_whitespace().collect();
@@ -209,3 +209,59 @@ pub fn chown_tree(base: &Path, chown_to: (u32,u32), include_base: bool) -> Resul
     }
     Ok(())
 }
+
+/// Format a time in seconds since the epoch as a UTC date and time string
+/// such as "2019-03-14 09:26:53".
+pub fn format_timestamp(secs: u64) -> String {
+    // Convert days since epoch to a civil date (Howard Hinnant's algorithm)
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let tod = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, tod / 3600, (tod / 60) % 60, tod % 60)
+}
+
+/// A directory under the system temporary directory which is unique to this
+/// process and is removed again when dropped, even if a test fails.
+#[cfg(test)]
+pub(crate) struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!("citadel-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_552_555_613), "2019-03-14 09:26:53");
+    }
+}
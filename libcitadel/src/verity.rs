@@ -1,11 +1,35 @@
 use std::path::{Path,PathBuf};
-use std::collections::HashMap;
-use std::fs::{self, OpenOptions,File};
-use std::io;
+use std::fs::{OpenOptions,File};
+use std::io::{Read,Write,Seek,SeekFrom,BufReader,BufWriter};
 
-use crate::{Result, MetaInfo, Partition, LoopDevice, Mountpoint};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes::randombytes;
 
+use crate::{Result, MetaInfo, Partition, LoopDevice, Mountpoint, Progress, BLOCK_SIZE};
 
+/// Size of a sha256 digest
+const DIGEST_SIZE: usize = 32;
+
+/// Number of digests which fit in a single hash block
+const HASHES_PER_BLOCK: usize = BLOCK_SIZE / DIGEST_SIZE;
+
+/// Hash trees created by `veritysetup format` begin with a superblock
+const SUPERBLOCK_MAGIC: &[u8] = b"verity\0\0";
+
+///
+/// Generates, verifies and activates dm-verity hash trees.
+///
+/// Hash trees are calculated in process using sha256 with 4096 byte data and
+/// hash blocks in the format (version 1) used by the kernel. The tree is stored
+/// without a superblock directly after the last data block, so the parameters
+/// normally read from the superblock (salt and block sizes) are passed to
+/// `veritysetup` when the device is created. Images with a hash tree generated
+/// by an older version which called `veritysetup format` still contain the
+/// superblock and are detected and handled when verifying and creating devices.
+///
+/// Only creating and removing the actual dm-verity devices requires the
+/// `veritysetup` utility.
+///
 pub struct Verity {
     image: PathBuf,
 }
@@ -18,21 +42,62 @@ impl Verity {
         Verity { image }
     }
 
-    pub fn generate_initial_hashtree(&self, output: impl AsRef<Path>) -> Result<VerityOutput> {
-        let output = output.as_ref();
-        // Don't use absolute path to veritysetup so that the build will correctly find the version from cryptsetup-native
-        let output = cmd_with_output!("veritysetup", "format {} {}", self.path_str(), output.display())?;
-        Ok(VerityOutput::parse(&output))
+    /// Generate a hash tree with a random salt for the image data file and write it to `output`.
+    pub fn generate_initial_hashtree(&self, output: impl AsRef<Path>, progress: &mut dyn Progress) -> Result<VerityOutput> {
+        let salt = hex::encode(randombytes(DIGEST_SIZE));
+        self.generate_initial_hashtree_with_salt(output, &salt, progress)
     }
 
-    pub fn generate_image_hashtree(&self, metainfo: &MetaInfo) -> Result<VerityOutput> {
-        let verity_salt = metainfo.verity_salt();
-        self.generate_image_hashtree_with_salt(metainfo, verity_salt)
+    /// Generate a hash tree for the image using `salt` instead of a random salt. Images
+    /// which will be compared block by block with `read_leaf_hashes()` must be generated
+    /// with the same salt.
+    pub fn generate_initial_hashtree_with_salt(&self, output: impl AsRef<Path>, salt: &str, progress: &mut dyn Progress) -> Result<VerityOutput> {
+        let len = self.image.metadata()?.len() as usize;
+        let nblocks = len / BLOCK_SIZE;
+        if nblocks * BLOCK_SIZE != len {
+            bail!("Image data file {} is not a multiple of the block size", self.image.display());
+        }
+        let tree = HashTree::generate(File::open(self.path())?, nblocks, &hex::decode(salt)?, progress)?;
+        let mut out = BufWriter::new(File::create(output.as_ref())?);
+        tree.write(&mut out)?;
+        out.flush()?;
+        Ok(tree.output())
+    }
+
+    /// Read the lowest level of a dm-verity hash tree which is stored at the end of the file
+    /// `path`. This is a list of the sha256 hashes of each of the `nblocks` data blocks.
+    pub fn read_leaf_hashes(path: impl AsRef<Path>, nblocks: usize) -> Result<Vec<u8>> {
+        // lowest level is last in hash tree
+        let hash_blocks = nblocks.div_ceil(HASHES_PER_BLOCK);
+        let mut file = File::open(path.as_ref())?;
+        let len = file.metadata()?.len() as usize;
+        if len < hash_blocks * BLOCK_SIZE {
+            bail!("File {} is too small to contain a hash tree for {} blocks", path.as_ref().display(), nblocks);
+        }
+        file.seek(SeekFrom::Start((len - hash_blocks * BLOCK_SIZE) as u64))?;
+        let mut hashes = vec![0u8; nblocks * DIGEST_SIZE];
+        file.read_exact(&mut hashes)?;
+        Ok(hashes)
+    }
+
+    /// Generate a hash tree for a rootfs partition which already contains the image data
+    /// described by `metainfo`. The hash tree is written directly after the data blocks.
+    pub fn generate_partition_hashtree(partition: &Partition, metainfo: &MetaInfo, progress: &mut dyn Progress) -> Result<VerityOutput> {
+        let nblocks = metainfo.nblocks();
+        let mut dev = OpenOptions::new().read(true).write(true).open(partition.path())?;
+        let tree = HashTree::generate(&mut dev, nblocks, &hex::decode(metainfo.verity_salt())?, progress)?;
+        dev.seek(SeekFrom::Start((nblocks * BLOCK_SIZE) as u64))?;
+        tree.write(&mut dev)?;
+        dev.sync_all()?;
+        Ok(tree.output())
     }
 
-    pub fn generate_image_hashtree_with_salt(&self, metainfo: &MetaInfo, salt: &str) -> Result<VerityOutput> {
+    pub fn generate_image_hashtree(&self, metainfo: &MetaInfo, progress: &mut dyn Progress) -> Result<VerityOutput> {
+        let verity_salt = metainfo.verity_salt();
+        self.generate_image_hashtree_with_salt(metainfo, verity_salt, progress)
+    }
 
-        let verityfile = self.image.with_extension("verity");
+    pub fn generate_image_hashtree_with_salt(&self, metainfo: &MetaInfo, salt: &str, progress: &mut dyn Progress) -> Result<VerityOutput> {
         let nblocks = metainfo.nblocks();
 
         // Make sure file size is correct or else verity tree will be appended in wrong place
@@ -42,24 +107,41 @@ impl Verity {
         if len != expected {
             bail!("Actual file size ({}) does not match expected size ({})", len, expected);
         }
-        let vout = LoopDevice::with_loop(self.path(), Some(4096), true, |loopdev| {
-            let output = cmd_with_output!(Self::VERITYSETUP, "--data-blocks={} --salt={} format {} {}",
-                nblocks, salt, loopdev, verityfile.display())?;
-            Ok(VerityOutput::parse(&output))
-        })?;
-        let mut input = File::open(&verityfile)?;
-        let mut output = OpenOptions::new().append(true).open(self.path())?;
-        io::copy(&mut input, &mut output)?;
-        fs::remove_file(&verityfile)?;
-        Ok(vout)
+
+        let mut input = File::open(self.path())?;
+        input.seek(SeekFrom::Start(BLOCK_SIZE as u64))?;
+        let tree = HashTree::generate(input, nblocks, &hex::decode(salt)?, progress)?;
+
+        let mut output = BufWriter::new(OpenOptions::new().append(true).open(self.path())?);
+        tree.write(&mut output)?;
+        output.flush()?;
+        Ok(tree.output())
     }
 
-    pub fn verify(&self, metainfo: &MetaInfo) -> Result<bool> {
-        LoopDevice::with_loop(self.path(), Some(4096), true, |loopdev| {
-            cmd_ok!(Self::VERITYSETUP, "--hash-offset={} verify {} {} {}",
-            metainfo.nblocks() * 4096,
-            loopdev, loopdev, metainfo.verity_root())
-        })
+    /// Recalculate the hash tree from the data blocks of the image and compare it to
+    /// the hash tree stored in the image and the root hash in `metainfo`.
+    pub fn verify(&self, metainfo: &MetaInfo, progress: &mut dyn Progress) -> Result<bool> {
+        let nblocks = metainfo.nblocks();
+        let mut file = File::open(self.path())?;
+        file.seek(SeekFrom::Start(BLOCK_SIZE as u64))?;
+        let tree = HashTree::generate(&mut file, nblocks, &hex::decode(metainfo.verity_salt())?, progress)?;
+        if tree.root_hash() != metainfo.verity_root() {
+            warn!("Calculated verity root hash does not match root hash in metainfo");
+            return Ok(false);
+        }
+
+        let mut offset = ((nblocks + 1) * BLOCK_SIZE) as u64;
+        if Self::has_superblock(&mut file, offset)? {
+            offset += BLOCK_SIZE as u64;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let mut stored = Vec::new();
+        file.take(tree.size() as u64).read_to_end(&mut stored)?;
+        if stored != tree.to_bytes() {
+            warn!("Hash tree stored in image does not match calculated hash tree");
+            return Ok(false);
+        }
+        Ok(true)
     }
 
     pub fn setup(&self, metainfo: &MetaInfo) -> Result<String> {
@@ -100,59 +182,233 @@ impl Verity {
     fn setup_device(srcdev: &str, devname: &str, metainfo: &MetaInfo) -> Result<()> {
         let nblocks = metainfo.nblocks();
         let verity_root = metainfo.verity_root();
-        cmd!(Self::VERITYSETUP, "--hash-offset={} --data-blocks={} create {} {} {} {}",
-            nblocks * 4096, nblocks, devname, srcdev, srcdev, verity_root)?;
+        let hash_offset = nblocks * BLOCK_SIZE;
+        if Self::has_superblock(&mut File::open(srcdev)?, hash_offset as u64)? {
+            cmd!(Self::VERITYSETUP, "--hash-offset={} --data-blocks={} create {} {} {} {}",
+                hash_offset, nblocks, devname, srcdev, srcdev, verity_root)?;
+        } else {
+            cmd!(Self::VERITYSETUP, "--no-superblock --format=1 --hash=sha256 --data-block-size={} --hash-block-size={} --salt={} --hash-offset={} --data-blocks={} create {} {} {} {}",
+                BLOCK_SIZE, BLOCK_SIZE, metainfo.verity_salt(), hash_offset, nblocks, devname, srcdev, srcdev, verity_root)?;
+        }
 
         Ok(())
     }
 
+    // Return `true` if a hash tree superblock written by `veritysetup format` is found at `offset`
+    fn has_superblock<F: Read + Seek>(file: &mut F, offset: u64) -> Result<bool> {
+        let mut magic = [0u8; 8];
+        file.seek(SeekFrom::Start(offset))?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(magic == SUPERBLOCK_MAGIC),
+            Err(_) => Ok(false),
+        }
+    }
+
     fn path(&self) -> &Path {
         &self.image
     }
+}
+
+///
+/// A dm-verity hash tree held in memory.
+///
+/// The lowest level of the tree contains the hash of each data block. Each
+/// higher level contains the hashes of the hash blocks in the level below,
+/// until a level fits in a single hash block. The root hash is the hash of
+/// this top level block. Every hash is calculated as sha256(salt || block)
+/// and each level is padded with zeros to a whole number of hash blocks.
+///
+/// On disk the levels are stored starting with the top level and ending with
+/// the lowest level.
+///
+pub struct HashTree {
+    salt: Vec<u8>,
+    levels: Vec<Vec<u8>>,
+    root: Vec<u8>,
+}
+
+impl HashTree {
+    /// Calculate the hash tree for `nblocks` data blocks read from `reader`.
+    pub fn generate<R: Read>(reader: R, nblocks: usize, salt: &[u8], progress: &mut dyn Progress) -> Result<HashTree> {
+        if nblocks == 0 {
+            bail!("Cannot generate verity hash tree for empty image");
+        }
+        let mut reader = BufReader::with_capacity(BLOCK_SIZE * 64, reader);
+        let mut block = vec![0u8; BLOCK_SIZE];
+        let mut leaves = Vec::with_capacity(Self::level_size(nblocks));
+        progress.begin("Generating dm-verity hash tree", nblocks as u64);
+        for idx in 0..nblocks {
+            reader.read_exact(&mut block)
+                .map_err(|e| format_err!("error reading data block {} of {}: {}", idx, nblocks, e))?;
+            leaves.extend_from_slice(&Self::hash_block(salt, &block));
+            progress.update(idx as u64 + 1);
+        }
+        progress.end();
+
+        // A single data block has no hash tree, the root is the hash of the block
+        if nblocks == 1 {
+            return Ok(HashTree { salt: salt.to_vec(), levels: Vec::new(), root: leaves });
+        }
+
+        let mut levels = Vec::new();
+        let mut level = Self::pad_level(leaves);
+        while level.len() > BLOCK_SIZE {
+            let next = level.chunks(BLOCK_SIZE)
+                .flat_map(|b| Self::hash_block(salt, b))
+                .collect();
+            levels.push(level);
+            level = Self::pad_level(next);
+        }
+        let root = Self::hash_block(salt, &level);
+        levels.push(level);
+        Ok(HashTree { salt: salt.to_vec(), levels, root })
+    }
+
+    fn hash_block(salt: &[u8], block: &[u8]) -> Vec<u8> {
+        let mut state = sha256::State::new();
+        state.update(salt);
+        state.update(block);
+        state.finalize().as_ref().to_vec()
+    }
+
+    // Size in bytes of a level of the tree containing `nhashes` hashes
+    fn level_size(nhashes: usize) -> usize {
+        nhashes.div_ceil(HASHES_PER_BLOCK) * BLOCK_SIZE
+    }
+
+    fn pad_level(mut level: Vec<u8>) -> Vec<u8> {
+        let size = Self::level_size(level.len() / DIGEST_SIZE);
+        level.resize(size, 0);
+        level
+    }
+
+    pub fn root_hash(&self) -> String {
+        hex::encode(&self.root)
+    }
+
+    pub fn salt(&self) -> String {
+        hex::encode(&self.salt)
+    }
+
+    /// The hashes of each data block, which are stored in the lowest level of the tree.
+    pub fn leaf_hashes(&self) -> &[u8] {
+        match self.levels.first() {
+            Some(level) => level,
+            None => &self.root,
+        }
+    }
+
+    /// Size in bytes of the hash tree when written to disk.
+    pub fn size(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    /// Write the levels of the tree in on-disk order.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        for level in self.levels.iter().rev() {
+            writer.write_all(level)?;
+        }
+        Ok(())
+    }
 
-    fn path_str(&self) -> &str {
-        self.image.to_str().unwrap()
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.size());
+        for level in self.levels.iter().rev() {
+            v.extend_from_slice(level);
+        }
+        v
+    }
+
+    fn output(&self) -> VerityOutput {
+        VerityOutput::new(&self.root_hash(), &self.salt())
     }
 }
 
-/// The output from the `veritysetup format` command can be parsed as key/value
-/// pairs. This class parses the output and stores it in a map for querying.
+/// The root hash and salt of a generated hash tree.
 pub struct VerityOutput {
-    output: String,
-    map: HashMap<String, String>,
+    root_hash: String,
+    salt: String,
 }
 
 impl VerityOutput {
-    /// Parse the string `output` as standard output from the dm-verity
-    /// `veritysetup format` command.
-    fn parse(output: &str) -> Self {
-        let mut vo = VerityOutput {
-            output: output.to_owned(),
-            map: HashMap::new(),
-        };
-        for line in output.lines() {
-            vo.parse_line(line);
+    fn new(root_hash: &str, salt: &str) -> Self {
+        VerityOutput {
+            root_hash: root_hash.to_owned(),
+            salt: salt.to_owned(),
         }
-        vo
     }
 
-    fn parse_line(&mut self, line: &str) {
-        let v = line.split(':').map(|s| s.trim()).collect::<Vec<_>>();
+    pub fn root_hash(&self) -> Option<&str> {
+        Some(self.root_hash.as_str())
+    }
 
-        if v.len() == 2 {
-            self.map.insert(v[0].to_owned(), v[1].to_owned());
-        }
+    pub fn salt(&self) -> Option<&str> {
+        Some(self.salt.as_str())
     }
 
-    pub fn root_hash(&self) -> Option<&str> {
-        self.map.get("Root hash").map(|s| s.as_str())
+    /// Return the hash tree parameters formatted like the output of `veritysetup format`
+    pub fn output(&self) -> String {
+        format!("Hash type:       \t1\n\
+                 Data block size: \t{}\n\
+                 Hash block size: \t{}\n\
+                 Hash algorithm:  \tsha256\n\
+                 Salt:            \t{}\n\
+                 Root hash:       \t{}\n",
+                BLOCK_SIZE, BLOCK_SIZE, self.salt, self.root_hash)
     }
+}
 
-    pub fn salt(&self) -> Option<&str> {
-        self.map.get("Salt").map(|s| s.as_str())
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+    use crate::NoProgress;
+
+    // Expected values were produced by an independent implementation of the
+    // dm-verity format 1 hash tree for data where block N is filled with the byte N.
+    const SALT: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn fixture_tree(nblocks: usize) -> HashTree {
+        let data = (0..nblocks)
+            .flat_map(|i| vec![i as u8; BLOCK_SIZE])
+            .collect::<Vec<u8>>();
+        HashTree::generate(Cursor::new(data), nblocks, &hex::decode(SALT).unwrap(), &mut NoProgress).unwrap()
+    }
+
+    fn tree_digest(tree: &HashTree) -> String {
+        hex::encode(sha256::hash(&tree.to_bytes()).as_ref())
+    }
+
+    #[test]
+    fn single_block() {
+        let tree = fixture_tree(1);
+        assert_eq!(tree.root_hash(), "4ce3ecf32c133bf6321901b6092219474b6ac91a19d0304621d629e6bb9987dc");
+        assert_eq!(tree.size(), 0);
+    }
+
+    #[test]
+    fn single_level() {
+        let tree = fixture_tree(3);
+        assert_eq!(tree.root_hash(), "9b03bbba3caf521b66ed754ee9f0d5848dd2bf99c2d51a898c133dd46ac97e51");
+        assert_eq!(tree.size(), BLOCK_SIZE);
+        assert_eq!(tree_digest(&tree), "adf7c2702465062f34b8d595ec0ac3d517318408dbae0062586378e822a149dd");
+    }
+
+    #[test]
+    fn two_levels() {
+        let tree = fixture_tree(129);
+        assert_eq!(tree.root_hash(), "6093a2333523050b628581510028976d3d3e9c62458642a83727e6df641397a4");
+        assert_eq!(tree.size(), 3 * BLOCK_SIZE);
+        assert_eq!(tree_digest(&tree), "98464b513ea866c3fced081f070047d47e884c9e8a804062cb53dae84eebc35b");
+
+        // Lowest level is stored last so that read_leaf_hashes() can find it
+        let bytes = tree.to_bytes();
+        assert_eq!(&bytes[BLOCK_SIZE..BLOCK_SIZE + 129 * DIGEST_SIZE], &tree.leaf_hashes()[..129 * DIGEST_SIZE]);
     }
 
-    pub fn output(&self) -> &str {
-        &self.output
+    #[test]
+    fn short_data() {
+        let data = vec![0u8; BLOCK_SIZE * 2];
+        assert!(HashTree::generate(Cursor::new(data), 3, &[], &mut NoProgress).is_err());
     }
 }